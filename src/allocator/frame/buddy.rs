@@ -20,13 +20,7 @@ struct BuddyEntry<A> {
 
 impl<A> Clone for BuddyEntry<A> {
     fn clone(&self) -> Self {
-        Self {
-            base: self.base,
-            size: self.size,
-            skip: self.skip,
-            used: self.used,
-            phantom: PhantomData,
-        }
+        *self
     }
 }
 impl<A> Copy for BuddyEntry<A> {}
@@ -74,7 +68,8 @@ impl<A: Arch> BuddyEntry<A> {
     unsafe fn set_usage(&self, page: usize, usage: BuddyUsage) -> Option<()> {
         unsafe {
             let addr = self.usage_addr(page)?;
-            Some(A::write(addr, usage))
+            A::write(addr, usage);
+            Some(())
         }
     }
 }
@@ -87,6 +82,11 @@ pub struct BuddyAllocator<A> {
 impl<A: Arch> BuddyAllocator<A> {
     const BUDDY_ENTRIES: usize = A::PAGE_SIZE / mem::size_of::<BuddyEntry<A>>();
 
+    /// Takes over the frames `bump_allocator` has not handed out, storing its table in the first
+    ///
+    /// # Safety
+    ///
+    /// The areas of `bump_allocator` must be unused memory reachable through `Arch::phys_to_virt`.
     pub unsafe fn new(mut bump_allocator: BumpAllocator<A>) -> Option<Self> {
         unsafe {
             // Allocate buddy table
@@ -106,7 +106,7 @@ impl<A: Arch> BuddyAllocator<A> {
             // by the bump allocator
             let mut offset = bump_allocator.offset();
             for old_area in bump_allocator.areas().iter() {
-                let mut area = old_area.clone();
+                let mut area = *old_area;
                 if offset >= area.size {
                     offset -= area.size;
                     continue;
@@ -179,6 +179,29 @@ impl<A: Arch> BuddyAllocator<A> {
     }
 }
 
+impl<A: Arch> BuddyAllocator<A> {
+    /// Finds the entry containing `size` bytes from `base`. Like `allocate` and `free`, this
+    /// scans the entries in order, reading up to `BUDDY_ENTRIES` of them on every `add_ref` and
+    /// `refcount`. Entries in use come first and there is usually one per memory area, so the
+    /// scan ends after a few reads, which is cheaper than keeping an index in the table frame.
+    unsafe fn entry_containing(&self, base: PhysicalAddress, size: usize) -> Option<BuddyEntry<A>> {
+        unsafe {
+            if self.table_virt.data() == 0 {
+                return None;
+            }
+
+            for i in 0..Self::BUDDY_ENTRIES {
+                let virt = self.table_virt.add(i * mem::size_of::<BuddyEntry<A>>());
+                let entry = A::read::<BuddyEntry<A>>(virt);
+                if base >= { entry.base } && base.add(size) <= entry.base.add(entry.size) {
+                    return Some(entry);
+                }
+            }
+            None
+        }
+    }
+}
+
 impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress> {
         unsafe {
@@ -287,6 +310,35 @@ impl<A: Arch> FrameAllocator for BuddyAllocator<A> {
         }
     }
 
+    unsafe fn add_ref(&mut self, base: PhysicalAddress, count: FrameCount) -> Option<()> {
+        unsafe {
+            let size = count.data() * A::PAGE_SIZE;
+            let entry = self.entry_containing(base, size)?;
+            let start_page = (base.data() - { entry.base }.data()) >> A::PAGE_SHIFT;
+            let pages = start_page..start_page + count.data();
+            // Check every page first, so nothing is changed on failure
+            for page in pages.clone() {
+                let usage = entry.usage(page)?;
+                if usage.0 == 0 || usage.0 == u8::MAX {
+                    return None;
+                }
+            }
+            for page in pages {
+                let usage = entry.usage(page)?;
+                entry.set_usage(page, BuddyUsage(usage.0 + 1))?;
+            }
+            Some(())
+        }
+    }
+
+    unsafe fn refcount(&self, base: PhysicalAddress) -> Option<usize> {
+        unsafe {
+            let entry = self.entry_containing(base, A::PAGE_SIZE)?;
+            let page = (base.data() - { entry.base }.data()) >> A::PAGE_SHIFT;
+            Some(entry.usage(page)?.0 as usize)
+        }
+    }
+
     unsafe fn usage(&self) -> FrameUsage {
         unsafe {
             let mut total = 0;
@@ -24,8 +24,9 @@ impl<A: Arch> BumpAllocator<A> {
             _marker: PhantomData,
         }
     }
+    /// Areas the allocator was created with, see `offset`
     pub fn areas(&self) -> &'static [MemoryArea] {
-        todo!()
+        self.orig_areas.0
     }
     /// Returns one semifree and the fully free areas. The offset is the number of bytes after
     /// which the first area is free.
@@ -38,8 +39,10 @@ impl<A: Arch> BumpAllocator<A> {
             .first()
             .map_or(PhysicalAddress::new(0), |a| a.base.add(off))
     }
+    /// Number of bytes after which `areas` are free, including the offset given to `new`
     pub fn offset(&self) -> usize {
-        (unsafe { self.usage().total().data() - self.usage().free().data() }) * A::PAGE_SIZE
+        self.orig_areas.1
+            + (unsafe { self.usage().total().data() - self.usage().free().data() }) * A::PAGE_SIZE
     }
 }
 
@@ -60,7 +63,9 @@ impl<A: Arch> FrameAllocator for BumpAllocator<A> {
         };
         // Avoid zeroing during very early bring-up on bare riscv64 to prevent faults
         #[cfg(not(target_arch = "riscv64"))]
-        A::write_bytes(A::phys_to_virt(block), 0, req_size);
+        unsafe {
+            A::write_bytes(A::phys_to_virt(block), 0, req_size);
+        }
         Some(block)
     }
 
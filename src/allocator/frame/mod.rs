@@ -43,6 +43,13 @@ impl FrameUsage {
     }
 }
 
+/// Source of physical frames for tables and pages
+///
+/// # Safety
+///
+/// Frames must be freed only once for each allocation and reference added, and must not be used
+/// after they are freed.
+#[allow(clippy::missing_safety_doc)]
 pub trait FrameAllocator {
     unsafe fn allocate(&mut self, count: FrameCount) -> Option<PhysicalAddress>;
 
@@ -59,6 +66,19 @@ pub trait FrameAllocator {
     }
 
     unsafe fn usage(&self) -> FrameUsage;
+
+    /// Adds a reference to each of `count` allocated frames starting at `address`, so each needs
+    /// one more `free` before it is actually freed. Fails if the allocator cannot share frames.
+    unsafe fn add_ref(&mut self, address: PhysicalAddress, count: FrameCount) -> Option<()> {
+        let _ = (address, count);
+        None
+    }
+
+    /// Number of references to the allocated frame at `address`, if the allocator counts them
+    unsafe fn refcount(&self, address: PhysicalAddress) -> Option<usize> {
+        let _ = address;
+        None
+    }
 }
 
 impl<T> FrameAllocator for &mut T
@@ -80,4 +100,10 @@ where
     unsafe fn usage(&self) -> FrameUsage {
         unsafe { T::usage(self) }
     }
+    unsafe fn add_ref(&mut self, address: PhysicalAddress, count: FrameCount) -> Option<()> {
+        unsafe { T::add_ref(self, address, count) }
+    }
+    unsafe fn refcount(&self, address: PhysicalAddress) -> Option<usize> {
+        unsafe { T::refcount(self, address) }
+    }
 }
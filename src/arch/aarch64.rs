@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use crate::{Arch, MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
+use crate::{Arch, MemoryArea, MemoryType, PhysicalAddress, TableKind, VirtualAddress};
 
 #[derive(Clone, Copy)]
 pub struct AArch64Arch;
@@ -9,12 +9,12 @@ impl Arch for AArch64Arch {
     const PAGE_SHIFT: usize = 12; // 4096 bytes
     const PAGE_ENTRY_SHIFT: usize = 9; // 512 entries, 8 bytes each
     const PAGE_LEVELS: usize = 4; // L0, L1, L2, L3
+    const HUGE_PAGE_LEVELS: usize = 2; // 2 MiB and 1 GiB blocks
 
-    //TODO
-    const ENTRY_ADDRESS_WIDTH: usize = 40;
+    const ENTRY_ADDRESS_WIDTH: usize = 36; // Output address bits 12 to 47
     const ENTRY_FLAG_DEFAULT_PAGE: usize = Self::ENTRY_FLAG_PRESENT
         | 1 << 1 // Page flag
-        | 1 << 10 // Access flag
+        | Self::ENTRY_FLAG_ACCESSED
         | Self::ENTRY_FLAG_NO_GLOBAL;
     const ENTRY_FLAG_DEFAULT_TABLE: usize
         = Self::ENTRY_FLAG_PRESENT
@@ -26,15 +26,24 @@ impl Arch for AArch64Arch {
     const ENTRY_FLAG_READONLY: usize = 1 << 7;
     const ENTRY_FLAG_READWRITE: usize = 0;
     const ENTRY_FLAG_PAGE_USER: usize = 1 << 6;
-    // This sets both userspace and privileged execute never
-    //TODO: Separate the two?
-    const ENTRY_FLAG_NO_EXEC: usize = 0b11 << 53;
+    const ENTRY_FLAG_NO_EXEC: usize =
+        Self::ENTRY_FLAG_NO_USER_EXEC | Self::ENTRY_FLAG_NO_KERNEL_EXEC;
     const ENTRY_FLAG_EXEC: usize = 0;
+    const ENTRY_FLAG_NO_USER_EXEC: usize = 1 << 54; // UXN
+    const ENTRY_FLAG_NO_KERNEL_EXEC: usize = 1 << 53; // PXN
     const ENTRY_FLAG_GLOBAL: usize = 0;
     const ENTRY_FLAG_NO_GLOBAL: usize = 1 << 11;
-    const ENTRY_FLAG_WRITE_COMBINING: usize = 0;
+    const ENTRY_MEMORY_TYPE_MASK: usize = 0b111 << 2; // AttrIndx, selecting an attribute of `MAIR`
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 10;
+    // Dirty state is tracked by clearing the read-only flag of entries with DBM set
+    const ENTRY_FLAG_DIRTY: usize = 0;
+    const ENTRY_FLAG_DIRTY_BIT_MODIFIER: usize = 1 << 51;
+    const ENTRY_FLAG_COPY_ON_WRITE: usize = 1 << 55; // First bit reserved for software use
+    const ENTRY_FLAG_SOFTWARE_MASK: usize = 0b11 << 56;
+    const ENTRY_FLAG_COUNTER: usize = 1 << 58; // Also ignored in table descriptors
 
     const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
+    const SPLIT_TABLES: bool = true; // TTBR0_EL1 and TTBR1_EL1
 
     unsafe fn init() -> &'static [MemoryArea] {
         unimplemented!("AArch64Arch::init unimplemented");
@@ -97,16 +106,84 @@ impl Arch for AArch64Arch {
         }
     }
 
-    fn virt_is_valid(_address: VirtualAddress) -> bool {
-        //TODO: what makes an address valid on aarch64?
-        true
+    #[inline(always)]
+    fn memory_type_flags(memory_type: MemoryType) -> usize {
+        (match memory_type {
+            MemoryType::WriteBack => 0,
+            MemoryType::WriteThrough => 1,
+            MemoryType::WriteCombining => 2,
+            MemoryType::Uncached => 3,
+            MemoryType::Device => 4,
+        }) << 2
+    }
+
+    #[inline(always)]
+    fn memory_type(flags: usize) -> MemoryType {
+        match (flags & Self::ENTRY_MEMORY_TYPE_MASK) >> 2 {
+            0 => MemoryType::WriteBack,
+            1 => MemoryType::WriteThrough,
+            2 => MemoryType::WriteCombining,
+            3 => MemoryType::Uncached,
+            _ => MemoryType::Device,
+        }
+    }
+
+    fn virt_is_valid(address: VirtualAddress) -> bool {
+        // TTBR0 and TTBR1 translate 48 bits each, and the upper bits select the table, so they
+        // must be all zeros or all ones
+        let masked = address.data() & Self::PAGE_NEGATIVE_MASK;
+
+        masked == Self::PAGE_NEGATIVE_MASK || masked == 0
+    }
+
+    #[inline(always)]
+    fn table_kind(address: VirtualAddress) -> TableKind {
+        // TTBR1 translates from 0xFFFF_0000_0000_0000 and TTBR0 up to 0x0000_FFFF_FFFF_FFFF,
+        // rather than each translating half of the 48 bits
+        if address.data() & (1 << (usize::BITS - 1)) == 0 {
+            TableKind::User
+        } else {
+            TableKind::Kernel
+        }
+    }
+
+    #[inline(always)]
+    fn entry_is_leaf(entry: usize, level: usize) -> bool {
+        // Block descriptors at L1 and L2 have the table flag cleared
+        level == 0 || entry & (1 << 1) == 0
     }
+
+    #[inline(always)]
+    fn leaf_flags(flags: usize, level: usize) -> usize {
+        // Page flag for L3 pages, cleared for blocks
+        if level == 0 {
+            flags
+        } else {
+            flags & !(1 << 1)
+        }
+    }
+
+    #[inline(always)]
+    fn leaf_page_flags(flags: usize, level: usize) -> usize {
+        if level == 0 {
+            flags
+        } else {
+            flags | 1 << 1
+        }
+    }
+}
+
+impl AArch64Arch {
+    /// Value the kernel must load into MAIR_EL1 for the memory types of page entries: normal
+    /// write-back, normal write-through, normal non-cacheable twice for write-combining and
+    /// uncached, and Device-nGnRnE
+    pub const MAIR: u64 = 0x00_44_44_BB_FF;
 }
 
 #[cfg(test)]
 mod tests {
     use super::AArch64Arch;
-    use crate::Arch;
+    use crate::{Arch, MemoryType, TableKind, VirtualAddress};
 
     #[test]
     fn constants() {
@@ -120,10 +197,50 @@ mod tests {
         assert_eq!(AArch64Arch::PAGE_ENTRY_MASK, 0x1FF);
         assert_eq!(AArch64Arch::PAGE_NEGATIVE_MASK, 0xFFFF_0000_0000_0000);
 
-        assert_eq!(AArch64Arch::ENTRY_ADDRESS_SIZE, 0x0000_0100_0000_0000);
-        assert_eq!(AArch64Arch::ENTRY_ADDRESS_MASK, 0x0000_00FF_FFFF_FFFF);
-        assert_eq!(AArch64Arch::ENTRY_FLAGS_MASK, 0xFFF0_0000_0000_0FFF);
+        assert_eq!(AArch64Arch::ENTRY_ADDRESS_SIZE, 0x0000_0010_0000_0000);
+        assert_eq!(AArch64Arch::ENTRY_ADDRESS_MASK, 0x0000_000F_FFFF_FFFF);
+        assert_eq!(AArch64Arch::ENTRY_FLAGS_MASK, 0xFFFF_0000_0000_0FFF);
 
         assert_eq!(AArch64Arch::PHYS_OFFSET, 0xFFFF_8000_0000_0000);
     }
+
+    #[test]
+    fn table_kind() {
+        // TTBR0 and TTBR1 both translate 48 bits, selected by the upper bits
+        for (address, kind) in [
+            (0, TableKind::User),
+            (0x0000_8000_0000_0000, TableKind::User),
+            (0x0000_FFFF_FFFF_FFFF, TableKind::User),
+            (0xFFFF_0000_0000_0000, TableKind::Kernel),
+            (0xFFFF_8000_0000_0000, TableKind::Kernel),
+            (0xFFFF_FFFF_FFFF_FFFF, TableKind::Kernel),
+        ] {
+            assert!(AArch64Arch::virt_is_valid(VirtualAddress::new(address)));
+            assert_eq!(AArch64Arch::table_kind(VirtualAddress::new(address)), kind);
+        }
+        for address in [
+            0x0001_0000_0000_0000,
+            0x8000_0000_0000_0000,
+            0xFFFE_FFFF_FFFF_FFFF,
+        ] {
+            assert!(!AArch64Arch::virt_is_valid(VirtualAddress::new(address)));
+        }
+    }
+
+    #[test]
+    fn memory_types() {
+        // AttrIndx selects the MAIR attribute of the memory type
+        for (memory_type, attribute) in [
+            (MemoryType::WriteBack, 0xFF),
+            (MemoryType::WriteThrough, 0xBB),
+            (MemoryType::WriteCombining, 0x44),
+            (MemoryType::Uncached, 0x44),
+            (MemoryType::Device, 0x00),
+        ] {
+            let flags = AArch64Arch::memory_type_flags(memory_type);
+            let index = flags >> 2;
+            assert_eq!((AArch64Arch::MAIR >> (index * 8)) & 0xFF, attribute);
+            assert_eq!(AArch64Arch::memory_type(flags), memory_type);
+        }
+    }
 }
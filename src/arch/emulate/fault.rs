@@ -0,0 +1,74 @@
+use core::fmt;
+
+use crate::VirtualAddress;
+
+/// Kind of memory access that caused a page fault
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Privilege level of an emulated memory access
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Privilege {
+    User,
+    Kernel,
+}
+
+/// Why an emulated memory access faulted
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PageFaultReason {
+    /// Address is not valid for the architecture, for example non-canonical
+    InvalidAddress,
+    /// No present leaf entry maps the address
+    NotPresent,
+    /// User access to a page without the user flag
+    NotUser,
+    /// Write access to a read-only page
+    NotWritable,
+    /// Instruction fetch from a no-execute page
+    NotExecutable,
+    /// Access to a page without the accessed flag, when the MMU faults instead of setting it
+    NotAccessed,
+    /// Write to a page without the dirty flag, when the MMU faults instead of setting it
+    NotDirty,
+}
+
+/// How the emulated MMU maintains the accessed and dirty flags of leaf entries
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccessedDirty {
+    /// Set the flags on access, like x86, RISC-V with Svadu and AArch64 with FEAT_HAFDBS
+    Hardware,
+    /// Fault instead of setting the flags, leaving them to software, like RISC-V with Svade and
+    /// AArch64 without FEAT_HAFDBS
+    Fault,
+}
+
+/// Page fault raised by an emulated memory access
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PageFault {
+    /// First address of the access that faulted
+    pub address: VirtualAddress,
+    pub kind: AccessKind,
+    pub privilege: Privilege,
+    pub reason: PageFaultReason,
+}
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "page fault: {:?} {:?} at 0x{:X}: {:?}",
+            self.privilege,
+            self.kind,
+            self.address.data(),
+            self.reason
+        )
+    }
+}
+
+/// Called with every page fault of the emulated machine. Returns true if the fault was resolved
+/// and the access should be retried.
+pub type FaultHandler = Box<dyn FnMut(PageFault) -> bool>;
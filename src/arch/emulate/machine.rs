@@ -0,0 +1,597 @@
+use core::{
+    any::Any,
+    cell::Cell,
+    marker::PhantomData,
+    mem::{self, MaybeUninit},
+    ptr::{self, NonNull},
+};
+use std::collections::BTreeMap;
+
+use super::{
+    fault::{AccessKind, AccessedDirty, FaultHandler, PageFault, PageFaultReason, Privilege},
+    tlb::{StaleTranslation, TlbCheck, TlbEntry},
+};
+use crate::{
+    page::PageFlags, Arch, MemoryArea, PageEntry, PhysicalAddress, TableKind, VirtualAddress,
+};
+
+thread_local! {
+    // Machine acted on by EmulateArch calls on this thread
+    static CURRENT: Cell<Option<NonNull<dyn Any>>> = const { Cell::new(None) };
+    // Whether `with` is lending out the current machine
+    static BORROWED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runs `f` on the machine entered on the current thread. Panics if there is none, if it
+/// emulates another architecture, or if an enclosing call is already using it.
+pub(super) fn with<A: Arch + 'static, R>(f: impl FnOnce(&mut Machine<A>) -> R) -> R {
+    struct Release;
+    impl Drop for Release {
+        fn drop(&mut self) {
+            BORROWED.set(false);
+        }
+    }
+
+    let mut current = CURRENT
+        .get()
+        .expect("no emulated machine entered on this thread");
+    assert!(!BORROWED.replace(true), "emulated machine already in use");
+    let _release = Release;
+    // The machine stays alive while current, and the flag makes this the only reference to it
+    let machine = unsafe { current.as_mut() }
+        .downcast_mut()
+        .expect("emulated machine is for another architecture");
+    f(machine)
+}
+
+/// An emulated machine with its own memory, page tables and TLB. Calls to
+/// [`EmulateArch`](super::EmulateArch) act on the machine entered on the current thread.
+pub struct EmulatedMachine<A> {
+    machine: Box<Machine<A>>,
+    areas: Box<[MemoryArea]>,
+}
+
+impl<A: Arch + 'static> EmulatedMachine<A> {
+    /// Creates a machine with `memory_size` bytes of memory, all usable except for the initial
+    /// page tables
+    pub fn new(memory_size: usize) -> Self {
+        let reserved = Machine::<A>::RESERVED;
+        Self::with_areas(
+            memory_size,
+            &[MemoryArea {
+                base: PhysicalAddress::new(reserved),
+                size: memory_size.saturating_sub(reserved),
+            }],
+        )
+    }
+
+    /// Creates a machine with `memory_size` bytes of memory, of which only `areas` are usable.
+    ///
+    /// The first `PAGE_LEVELS` frames hold the initial page tables, which map the first
+    /// `PAGE_ENTRIES` frames at `PHYS_OFFSET`, and must not be part of any area. With
+    /// `SPLIT_TABLES`, the frame after them is the empty initial user root table.
+    pub fn with_areas(memory_size: usize, areas: &[MemoryArea]) -> Self {
+        for area in areas.iter() {
+            assert!(
+                area.base.data() >= Machine::<A>::RESERVED
+                    && area.base.add(area.size).data() <= memory_size,
+                "memory area {:X?} outside of usable emulated memory",
+                area
+            );
+        }
+
+        Self {
+            machine: Box::new(Machine::new(memory_size)),
+            areas: areas.into(),
+        }
+    }
+
+    /// Adds `count` CPUs, which start with the same tables as the first one. CPUs are numbered in
+    /// order of creation, starting at 0.
+    pub fn with_cpus(mut self, count: usize) -> Self {
+        self.machine.add_cpus(count);
+        self
+    }
+
+    /// Usable memory areas, as returned by `init` on real architectures
+    pub fn areas(&self) -> &[MemoryArea] {
+        &self.areas
+    }
+
+    /// Usable memory areas, for allocators that keep them for `'static`
+    ///
+    /// # Safety
+    ///
+    /// The areas must not be used after the machine is dropped.
+    pub unsafe fn static_areas(&self) -> &'static [MemoryArea] {
+        // The areas stay in place when the machine moves, as they are boxed
+        unsafe { &*(&*self.areas as *const [MemoryArea]) }
+    }
+
+    /// Runs `f` with this as the current machine of the thread, restoring the previously
+    /// entered machine afterwards
+    pub fn enter<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        struct Restore(Option<NonNull<dyn Any>>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.set(self.0);
+            }
+        }
+
+        let _restore = Restore(self.make_current());
+        f()
+    }
+
+    /// Makes this the current machine of the thread, returning the previous one
+    pub(super) fn make_current(&mut self) -> Option<NonNull<dyn Any>> {
+        let machine: &mut dyn Any = &mut *self.machine;
+        CURRENT.replace(Some(NonNull::from(machine)))
+    }
+}
+
+struct Cpu<A> {
+    // Current root table of each TableKind, the same one unless the architecture splits them
+    tables: [PhysicalAddress; 2],
+    // Cached leaf entries, by their level and the base of the region they map. Filled on access
+    // and only dropped by invalidation, like a real TLB, so entries of different sizes may
+    // overlap.
+    tlb: BTreeMap<(usize, VirtualAddress), TlbEntry<A>>,
+    // Single page and full invalidations so far
+    page_invalidations: usize,
+    full_invalidations: usize,
+}
+
+impl<A> Cpu<A> {
+    fn new(tables: [PhysicalAddress; 2]) -> Self {
+        Self {
+            tables,
+            tlb: BTreeMap::new(),
+            page_invalidations: 0,
+            full_invalidations: 0,
+        }
+    }
+}
+
+pub(super) struct Machine<A> {
+    memory: Box<[u8]>,
+    cpus: Vec<Cpu<A>>,
+    // Index of the CPU acted on by EmulateArch calls
+    pub(super) cpu: usize,
+    pub(super) tlb_check: TlbCheck,
+    pub(super) stale: Vec<StaleTranslation<A>>,
+    pub(super) fault_handler: Option<FaultHandler>,
+    pub(super) accessed_dirty: AccessedDirty,
+    phantom: PhantomData<A>,
+}
+
+impl<A: Arch> Machine<A> {
+    /// Frames at the start of memory used by the initial page tables
+    pub(super) const RESERVED: usize = (A::PAGE_LEVELS + A::SPLIT_TABLES as usize) * A::PAGE_SIZE;
+
+    fn new(memory_size: usize) -> Self {
+        assert!(
+            memory_size >= Self::RESERVED,
+            "emulated memory too small for the initial page tables"
+        );
+        let mut machine = Self {
+            memory: vec![0; memory_size].into_boxed_slice(),
+            cpus: vec![Cpu::new(Self::initial_tables())],
+            cpu: 0,
+            tlb_check: TlbCheck::Off,
+            stale: Vec::new(),
+            fault_handler: None,
+            accessed_dirty: AccessedDirty::Hardware,
+            phantom: PhantomData,
+        };
+
+        // Offset map PAGE_ENTRIES pages (2 MiB on x86_64), using one table per level at the start
+        // of memory, each linking PHYS_OFFSET to the next
+        let flags = A::ENTRY_FLAG_DEFAULT_TABLE;
+        for level in (1..A::PAGE_LEVELS).rev() {
+            let table = (A::PAGE_LEVELS - 1 - level) * A::PAGE_SIZE;
+            let next = table + A::PAGE_SIZE;
+            let i = (A::PHYS_OFFSET >> Self::level_shift(level)) & A::PAGE_ENTRY_MASK;
+            machine.write_entry(
+                PhysicalAddress::new(table + i * A::PAGE_ENTRY_SIZE),
+                PageEntry::new(next, flags),
+            );
+        }
+
+        // Last table links to frames, already accessed and dirty so they are usable whatever the
+        // accessed and dirty mode
+        let pt = (A::PAGE_LEVELS - 1) * A::PAGE_SIZE;
+        let flags =
+            PageFlags::<A>::new().write(true).data() | A::ENTRY_FLAG_ACCESSED | A::ENTRY_FLAG_DIRTY;
+        for i in 0..A::PAGE_ENTRIES {
+            let page = i * A::PAGE_SIZE;
+            machine.write_entry(
+                PhysicalAddress::new(pt + i * A::PAGE_ENTRY_SIZE),
+                PageEntry::new(page, flags),
+            );
+        }
+
+        // Count the present entries of each table, as `PageTable::set_entry` would
+        for level in 0..A::PAGE_LEVELS {
+            let table = (A::PAGE_LEVELS - 1 - level) * A::PAGE_SIZE;
+            let count: usize = if level == 0 { A::PAGE_ENTRIES } else { 1 };
+            for i in 0..count.ilog2() as usize + 1 {
+                if count & 1 << i != 0 {
+                    let phys = PhysicalAddress::new(table + i * A::PAGE_ENTRY_SIZE);
+                    let data = machine.read_entry(phys).data() | A::ENTRY_FLAG_COUNTER;
+                    machine.write_entry(phys, PageEntry::from_data(data));
+                }
+            }
+        }
+
+        machine
+    }
+
+    /// Root tables the first CPU starts with, indexed by table kind. Split tables start with an
+    /// empty user root after the kernel tables, others map both halves with the kernel root.
+    fn initial_tables() -> [PhysicalAddress; 2] {
+        let kernel = PhysicalAddress::new(0);
+        let mut tables = [kernel; 2];
+        if A::SPLIT_TABLES {
+            tables[TableKind::User as usize] = PhysicalAddress::new(A::PAGE_LEVELS * A::PAGE_SIZE);
+        }
+        tables
+    }
+
+    #[inline(always)]
+    fn level_shift(level: usize) -> usize {
+        level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT
+    }
+
+    /// Base of the region mapped by a leaf at `level` covering `virt`
+    #[inline(always)]
+    fn level_base(virt: VirtualAddress, level: usize) -> VirtualAddress {
+        VirtualAddress::new(virt.data() & !((1 << Self::level_shift(level)) - 1))
+    }
+
+    pub(super) fn cpu_count(&self) -> usize {
+        self.cpus.len()
+    }
+
+    /// Adds CPUs using the same tables as the first one, with empty TLBs
+    fn add_cpus(&mut self, count: usize) {
+        let tables = self.cpus[0].tables;
+        for _ in 0..count {
+            self.cpus.push(Cpu::new(tables));
+        }
+    }
+
+    fn read_phys<T>(&self, phys: PhysicalAddress) -> T {
+        let size = mem::size_of::<T>();
+        if phys.add(size).data() <= self.memory.len() {
+            unsafe { ptr::read(self.memory.as_ptr().add(phys.data()) as *const T) }
+        } else {
+            panic!(
+                "read_phys: 0x{:X} size 0x{:X} outside of memory",
+                phys.data(),
+                size
+            );
+        }
+    }
+
+    fn write_phys<T>(&mut self, phys: PhysicalAddress, value: T) {
+        let size = mem::size_of::<T>();
+        if phys.add(size).data() <= self.memory.len() {
+            unsafe {
+                ptr::write(self.memory.as_mut_ptr().add(phys.data()) as *mut T, value);
+            }
+        } else {
+            panic!(
+                "write_phys: 0x{:X} size 0x{:X} outside of memory",
+                phys.data(),
+                size
+            );
+        }
+    }
+
+    fn write_phys_bytes(&mut self, phys: PhysicalAddress, value: u8, count: usize) {
+        if phys.add(count).data() <= self.memory.len() {
+            unsafe {
+                ptr::write_bytes(self.memory.as_mut_ptr().add(phys.data()), value, count);
+            }
+        } else {
+            panic!(
+                "write_phys_bytes: 0x{:X} count 0x{:X} outside of memory",
+                phys.data(),
+                count
+            );
+        }
+    }
+
+    fn read_entry(&self, phys: PhysicalAddress) -> PageEntry<A> {
+        if A::PAGE_ENTRY_SIZE == 4 {
+            PageEntry::from_data(self.read_phys::<u32>(phys) as usize)
+        } else {
+            PageEntry::from_data(self.read_phys::<usize>(phys))
+        }
+    }
+
+    fn write_entry(&mut self, phys: PhysicalAddress, entry: PageEntry<A>) {
+        if A::PAGE_ENTRY_SIZE == 4 {
+            self.write_phys::<u32>(phys, entry.data() as u32);
+        } else {
+            self.write_phys::<usize>(phys, entry.data());
+        }
+    }
+
+    /// Walks the page tables like the hardware would, returning the leaf entry, its level and
+    /// its physical address
+    fn walk(&self, virt: VirtualAddress) -> Option<(PageEntry<A>, usize, PhysicalAddress)> {
+        let mut table = self.cpus[self.cpu].tables[A::table_kind(virt) as usize];
+        for level in (0..A::PAGE_LEVELS).rev() {
+            let i = (virt.data() >> Self::level_shift(level)) & A::PAGE_ENTRY_MASK;
+            let pte = table.add(i * A::PAGE_ENTRY_SIZE);
+            let entry = self.read_entry(pte);
+            let next = entry.address().ok()?;
+            if A::entry_is_leaf(entry.data(), level) {
+                return Some((entry, level, pte));
+            }
+            table = next;
+        }
+        None
+    }
+
+    /// Finds the cached translation covering `virt`, returning its base. Every page size is
+    /// searched, largest first, so a huge page left cached after its table changed is found
+    /// even when smaller pages were cached around `virt` too.
+    fn tlb_lookup(&self, virt: VirtualAddress) -> Option<(VirtualAddress, TlbEntry<A>)> {
+        let tlb = &self.cpus[self.cpu].tlb;
+        (0..A::PAGE_LEVELS).rev().find_map(|level| {
+            let base = Self::level_base(virt, level);
+            tlb.get(&(level, base)).map(|&tlb_entry| (base, tlb_entry))
+        })
+    }
+
+    fn translate(
+        &mut self,
+        virt: VirtualAddress,
+    ) -> Option<(PhysicalAddress, VirtualAddress, TlbEntry<A>)> {
+        let (base, tlb_entry) = match self.tlb_lookup(virt) {
+            Some((base, tlb_entry)) => {
+                self.check(virt, tlb_entry);
+                (base, tlb_entry)
+            }
+            None => {
+                // Not-present entries are never cached
+                let (entry, level, pte) = self.walk(virt)?;
+                let base = Self::level_base(virt, level);
+                let tlb_entry = TlbEntry { entry, level, pte };
+                self.cpus[self.cpu].tlb.insert((level, base), tlb_entry);
+                (base, tlb_entry)
+            }
+        };
+        let size = 1 << Self::level_shift(tlb_entry.level);
+        let phys = tlb_entry.entry.address().ok()?.data() & !(size - 1);
+        let offset = virt.data() - base.data();
+        Some((PhysicalAddress::new(phys + offset), base, tlb_entry))
+    }
+
+    /// Compares a cached translation used by an access with the page tables
+    fn check(&mut self, virt: VirtualAddress, tlb_entry: TlbEntry<A>) {
+        if self.tlb_check == TlbCheck::Off {
+            return;
+        }
+        let current = self.walk(virt);
+        // Clearing the accessed flag without a flush only delays setting it again, but a cached
+        // dirty flag would let writes go unrecorded. The MMU ignores the entry counter.
+        if let Some((entry, level, _)) = current
+            && (entry.data() ^ tlb_entry.entry.data())
+                & !(A::ENTRY_FLAG_ACCESSED | A::ENTRY_FLAG_COUNTER)
+                == 0
+            && level == tlb_entry.level
+        {
+            return;
+        }
+        let stale = StaleTranslation {
+            cpu: self.cpu,
+            virt,
+            cached: tlb_entry.entry,
+            current: current.map(|(entry, _, _)| entry),
+        };
+        match self.tlb_check {
+            TlbCheck::Off => (),
+            TlbCheck::Record => self.stale.push(stale),
+            TlbCheck::Panic => panic!(
+                "stale TLB entry on CPU {} for 0x{:X}: cached 0x{:X}, current {:X?}",
+                self.cpu,
+                virt.data(),
+                stale.cached.data(),
+                stale.current.map(|entry| entry.data())
+            ),
+        }
+    }
+
+    /// Translates and checks the permissions of every page touched by an access, returning the
+    /// physical chunks to access in order
+    fn access(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        kind: AccessKind,
+        privilege: Privilege,
+    ) -> Result<Vec<(PhysicalAddress, usize)>, PageFault> {
+        let mut chunks = Vec::new();
+        let mut offset = 0;
+        while offset < size {
+            let addr = virt.add(offset);
+            let phys = self.translate_checked(addr, kind, privilege)?;
+            let len = (size - offset).min(A::PAGE_SIZE - (addr.data() & A::PAGE_OFFSET_MASK));
+            chunks.push((phys, len));
+            offset += len;
+        }
+        Ok(chunks)
+    }
+
+    fn translate_checked(
+        &mut self,
+        virt: VirtualAddress,
+        kind: AccessKind,
+        privilege: Privilege,
+    ) -> Result<PhysicalAddress, PageFault> {
+        let fault = |reason| PageFault {
+            address: virt,
+            kind,
+            privilege,
+            reason,
+        };
+        if !A::virt_is_valid(virt) {
+            return Err(fault(PageFaultReason::InvalidAddress));
+        }
+        let Some((phys, base, tlb_entry)) = self.translate(virt) else {
+            return Err(fault(PageFaultReason::NotPresent));
+        };
+        let data = tlb_entry.entry.data();
+        let flags = tlb_entry.entry.flags();
+        let hardware = self.accessed_dirty == AccessedDirty::Hardware;
+        let write = kind == AccessKind::Write;
+        let reason = if privilege == Privilege::User && !flags.has_user() {
+            PageFaultReason::NotUser
+        } else if write
+            && !flags.has_write()
+            && !(hardware && data & A::ENTRY_FLAG_DIRTY_BIT_MODIFIER != 0)
+        {
+            PageFaultReason::NotWritable
+        } else if kind == AccessKind::Execute
+            && !match privilege {
+                Privilege::User => flags.has_user_execute(),
+                Privilege::Kernel => flags.has_kernel_execute(),
+            }
+        {
+            PageFaultReason::NotExecutable
+        } else if !hardware && data & A::ENTRY_FLAG_ACCESSED == 0 {
+            PageFaultReason::NotAccessed
+        } else if !hardware && write && data & A::ENTRY_FLAG_DIRTY != A::ENTRY_FLAG_DIRTY {
+            PageFaultReason::NotDirty
+        } else {
+            if hardware {
+                self.update_flags(base, tlb_entry, write);
+            }
+            return Ok(phys);
+        };
+        // Like x86, a faulting access drops the translation it used, so a handler fixing the
+        // permissions does not need to flush before retrying
+        self.invalidate(virt);
+        Err(fault(reason))
+    }
+
+    /// Sets the accessed flag, and the dirty one for writes, in a leaf entry used by an access.
+    /// Like the MMU, this updates both the cached entry and the one in memory if still present.
+    fn update_flags(&mut self, base: VirtualAddress, tlb_entry: TlbEntry<A>, write: bool) {
+        let mut set = A::ENTRY_FLAG_ACCESSED;
+        let mut clear = 0;
+        if write {
+            set |= A::ENTRY_FLAG_DIRTY;
+            if tlb_entry.entry.data() & A::ENTRY_FLAG_DIRTY_BIT_MODIFIER != 0 {
+                clear |= A::ENTRY_FLAG_READONLY;
+            }
+        }
+        let cached = tlb_entry.entry.data();
+        if cached & set == set && cached & clear == 0 {
+            return;
+        }
+
+        let current = self.read_entry(tlb_entry.pte);
+        if current.present() {
+            let data = (current.data() | set) & !clear;
+            self.write_entry(tlb_entry.pte, PageEntry::from_data(data));
+        }
+        let entry = PageEntry::from_data((cached | set) & !clear);
+        self.cpus[self.cpu]
+            .tlb
+            .insert((tlb_entry.level, base), TlbEntry { entry, ..tlb_entry });
+    }
+
+    fn check_phys(&self, phys: PhysicalAddress, len: usize) {
+        if phys.add(len).data() > self.memory.len() {
+            panic!("0x{:X} size 0x{:X} outside of memory", phys.data(), len);
+        }
+    }
+
+    pub(super) fn read<T>(
+        &mut self,
+        virt: VirtualAddress,
+        kind: AccessKind,
+        privilege: Privilege,
+    ) -> Result<T, PageFault> {
+        let chunks = self.access(virt, mem::size_of::<T>(), kind, privilege)?;
+        let mut value = MaybeUninit::<T>::uninit();
+        let mut dst = value.as_mut_ptr() as *mut u8;
+        for (phys, len) in chunks {
+            self.check_phys(phys, len);
+            unsafe {
+                ptr::copy_nonoverlapping(self.memory.as_ptr().add(phys.data()), dst, len);
+                dst = dst.add(len);
+            }
+        }
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub(super) fn write<T>(
+        &mut self,
+        virt: VirtualAddress,
+        value: &T,
+        privilege: Privilege,
+    ) -> Result<(), PageFault> {
+        let chunks = self.access(virt, mem::size_of::<T>(), AccessKind::Write, privilege)?;
+        let mut src = value as *const T as *const u8;
+        for (phys, len) in chunks {
+            self.check_phys(phys, len);
+            unsafe {
+                ptr::copy_nonoverlapping(src, self.memory.as_mut_ptr().add(phys.data()), len);
+                src = src.add(len);
+            }
+        }
+        Ok(())
+    }
+
+    pub(super) fn write_bytes(
+        &mut self,
+        virt: VirtualAddress,
+        value: u8,
+        count: usize,
+        privilege: Privilege,
+    ) -> Result<(), PageFault> {
+        let chunks = self.access(virt, count, AccessKind::Write, privilege)?;
+        for (phys, len) in chunks {
+            self.write_phys_bytes(phys, value, len);
+        }
+        Ok(())
+    }
+
+    pub(super) fn invalidate(&mut self, virt: VirtualAddress) {
+        // Like invlpg, this drops the translations of every size covering the address
+        let cpu = &mut self.cpus[self.cpu];
+        cpu.page_invalidations += 1;
+        for level in 0..A::PAGE_LEVELS {
+            cpu.tlb.remove(&(level, Self::level_base(virt, level)));
+        }
+    }
+
+    pub(super) fn invalidate_all(&mut self) {
+        self.cpus[self.cpu].full_invalidations += 1;
+        self.cpus[self.cpu].tlb.clear();
+    }
+
+    pub(super) fn invalidations(&self) -> (usize, usize) {
+        let cpu = &self.cpus[self.cpu];
+        (cpu.page_invalidations, cpu.full_invalidations)
+    }
+
+    pub(super) fn get_table(&self, table_kind: TableKind) -> PhysicalAddress {
+        self.cpus[self.cpu].tables[table_kind as usize]
+    }
+
+    pub(super) fn set_table(&mut self, table_kind: TableKind, address: PhysicalAddress) {
+        let cpu = &mut self.cpus[self.cpu];
+        if A::SPLIT_TABLES {
+            cpu.tables[table_kind as usize] = address;
+        } else {
+            cpu.tables = [address; 2];
+        }
+        self.invalidate_all();
+    }
+}
@@ -0,0 +1,949 @@
+use core::{
+    marker::PhantomData,
+    mem::{self, ManuallyDrop},
+};
+
+use self::machine::Machine;
+pub use self::{
+    fault::{AccessKind, AccessedDirty, FaultHandler, PageFault, PageFaultReason, Privilege},
+    machine::EmulatedMachine,
+    tlb::{StaleTranslation, TlbCheck},
+};
+use crate::{
+    arch::x86_64::X8664Arch, Arch, MemoryArea, MemoryType, PhysicalAddress, TableKind,
+    VirtualAddress, MEGABYTE,
+};
+
+mod fault;
+mod machine;
+mod tlb;
+
+/// Software emulation of the paging format of architecture `A`, backed by host memory
+///
+/// # Safety
+///
+/// The unsafe functions act on the emulated machine entered on the current thread, and panic if
+/// there is none.
+#[derive(Clone, Copy)]
+pub struct EmulateArch<A = X8664Arch>(PhantomData<A>);
+
+impl<A: Arch + 'static> Arch for EmulateArch<A> {
+    const PAGE_SHIFT: usize = A::PAGE_SHIFT;
+    const PAGE_ENTRY_SHIFT: usize = A::PAGE_ENTRY_SHIFT;
+    const PAGE_LEVELS: usize = A::PAGE_LEVELS;
+    const HUGE_PAGE_LEVELS: usize = A::HUGE_PAGE_LEVELS;
+
+    const ENTRY_ADDRESS_SHIFT: usize = A::ENTRY_ADDRESS_SHIFT;
+    const ENTRY_FLAG_DEFAULT_PAGE: usize = A::ENTRY_FLAG_DEFAULT_PAGE;
+    const ENTRY_FLAG_DEFAULT_TABLE: usize = A::ENTRY_FLAG_DEFAULT_TABLE;
+    const ENTRY_FLAG_PRESENT: usize = A::ENTRY_FLAG_PRESENT;
+    const ENTRY_FLAG_READONLY: usize = A::ENTRY_FLAG_READONLY;
+    const ENTRY_FLAG_READWRITE: usize = A::ENTRY_FLAG_READWRITE;
+    const ENTRY_FLAG_PAGE_USER: usize = A::ENTRY_FLAG_PAGE_USER;
+    const ENTRY_FLAG_TABLE_USER: usize = A::ENTRY_FLAG_TABLE_USER;
+    const ENTRY_FLAG_NO_EXEC: usize = A::ENTRY_FLAG_NO_EXEC;
+    const ENTRY_FLAG_EXEC: usize = A::ENTRY_FLAG_EXEC;
+    const ENTRY_FLAG_NO_USER_EXEC: usize = A::ENTRY_FLAG_NO_USER_EXEC;
+    const ENTRY_FLAG_NO_KERNEL_EXEC: usize = A::ENTRY_FLAG_NO_KERNEL_EXEC;
+
+    const PHYS_OFFSET: usize = A::PHYS_OFFSET;
+    const SPLIT_TABLES: bool = A::SPLIT_TABLES;
+    const FLUSH_ALL_THRESHOLD: usize = A::FLUSH_ALL_THRESHOLD;
+
+    const ENTRY_FLAG_GLOBAL: usize = A::ENTRY_FLAG_GLOBAL;
+    const ENTRY_FLAG_NO_GLOBAL: usize = A::ENTRY_FLAG_NO_GLOBAL;
+
+    const ENTRY_ADDRESS_WIDTH: usize = A::ENTRY_ADDRESS_WIDTH;
+
+    const ENTRY_MEMORY_TYPE_MASK: usize = A::ENTRY_MEMORY_TYPE_MASK;
+    const ENTRY_FLAG_HUGE: usize = A::ENTRY_FLAG_HUGE;
+    const ENTRY_FLAG_ACCESSED: usize = A::ENTRY_FLAG_ACCESSED;
+    const ENTRY_FLAG_DIRTY: usize = A::ENTRY_FLAG_DIRTY;
+    const ENTRY_FLAG_DIRTY_BIT_MODIFIER: usize = A::ENTRY_FLAG_DIRTY_BIT_MODIFIER;
+    const ENTRY_FLAG_COPY_ON_WRITE: usize = A::ENTRY_FLAG_COPY_ON_WRITE;
+    const ENTRY_FLAG_SOFTWARE_MASK: usize = A::ENTRY_FLAG_SOFTWARE_MASK;
+    const ENTRY_FLAG_COUNTER: usize = A::ENTRY_FLAG_COUNTER;
+
+    unsafe fn init() -> &'static [MemoryArea] {
+        // The machine is leaked, staying current on this thread outside of any
+        // EmulatedMachine::enter
+        let reserved = Machine::<A>::RESERVED;
+        let machine = Box::leak(Box::new(EmulatedMachine::<A>::with_areas(
+            MEMORY_SIZE,
+            &[
+                MemoryArea {
+                    base: PhysicalAddress::new(reserved), // Initial tables wasted
+                    size: MEMORY_SIZE / 2 - reserved,
+                },
+                // Second area for debugging
+                MemoryArea {
+                    base: PhysicalAddress::new(MEMORY_SIZE / 2),
+                    size: MEMORY_SIZE / 2,
+                },
+            ],
+        )));
+        machine.make_current();
+        let machine: &'static EmulatedMachine<A> = machine;
+        machine.areas()
+    }
+
+    #[inline(always)]
+    unsafe fn read<T>(address: VirtualAddress) -> T {
+        unsafe {
+            Self::try_read(address, Privilege::Kernel).unwrap_or_else(|fault| panic!("{}", fault))
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn write<T>(address: VirtualAddress, value: T) {
+        unsafe {
+            Self::try_write(address, value, Privilege::Kernel)
+                .unwrap_or_else(|fault| panic!("{}", fault))
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn write_bytes(address: VirtualAddress, value: u8, count: usize) {
+        unsafe {
+            Self::try_write_bytes(address, value, count, Privilege::Kernel)
+                .unwrap_or_else(|fault| panic!("{}", fault))
+        }
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate(address: VirtualAddress) {
+        machine::with::<A, _>(|machine| machine.invalidate(address));
+    }
+
+    #[inline(always)]
+    unsafe fn invalidate_all() {
+        machine::with::<A, _>(|machine| machine.invalidate_all());
+    }
+
+    #[inline(always)]
+    unsafe fn table(table_kind: TableKind) -> PhysicalAddress {
+        machine::with::<A, _>(|machine| machine.get_table(table_kind))
+    }
+
+    #[inline(always)]
+    unsafe fn set_table(table_kind: TableKind, address: PhysicalAddress) {
+        machine::with::<A, _>(|machine| machine.set_table(table_kind, address));
+    }
+
+    fn virt_is_valid(address: VirtualAddress) -> bool {
+        A::virt_is_valid(address)
+    }
+
+    #[inline(always)]
+    fn table_kind(address: VirtualAddress) -> TableKind {
+        A::table_kind(address)
+    }
+
+    #[inline(always)]
+    fn entry_is_leaf(entry: usize, level: usize) -> bool {
+        A::entry_is_leaf(entry, level)
+    }
+
+    #[inline(always)]
+    fn leaf_flags(flags: usize, level: usize) -> usize {
+        A::leaf_flags(flags, level)
+    }
+
+    #[inline(always)]
+    fn leaf_page_flags(flags: usize, level: usize) -> usize {
+        A::leaf_page_flags(flags, level)
+    }
+
+    #[inline(always)]
+    fn memory_type_flags(memory_type: MemoryType) -> usize {
+        A::memory_type_flags(memory_type)
+    }
+
+    #[inline(always)]
+    fn memory_type(flags: usize) -> MemoryType {
+        A::memory_type(flags)
+    }
+}
+
+const MEMORY_SIZE: usize = 64 * MEGABYTE;
+
+#[allow(clippy::missing_safety_doc)]
+impl<A: Arch + 'static> EmulateArch<A> {
+    /// Installs a handler for page faults, replacing any previous one
+    pub unsafe fn set_fault_handler(handler: impl FnMut(PageFault) -> bool + 'static) {
+        machine::with::<A, _>(|machine| machine.fault_handler = Some(Box::new(handler)));
+    }
+
+    /// Removes the page fault handler, so faults are returned to the accessor
+    pub unsafe fn clear_fault_handler() {
+        machine::with::<A, _>(|machine| machine.fault_handler = None);
+    }
+
+    /// Runs an access, passing faults to the fault handler until it succeeds or the handler
+    /// gives up
+    unsafe fn handle_faults<T>(
+        mut f: impl FnMut(&mut Machine<A>) -> Result<T, PageFault>,
+    ) -> Result<T, PageFault> {
+        loop {
+            let fault = match machine::with(&mut f) {
+                Ok(ok) => return Ok(ok),
+                Err(fault) => fault,
+            };
+
+            // The handler is taken out while running, as it may access emulated memory
+            let Some(mut handler) = machine::with::<A, _>(|machine| machine.fault_handler.take())
+            else {
+                return Err(fault);
+            };
+            let resolved = handler(fault);
+            machine::with::<A, _>(|machine| {
+                machine.fault_handler.get_or_insert(handler);
+            });
+            if !resolved {
+                return Err(fault);
+            }
+        }
+    }
+
+    /// Reads `T` from emulated memory, which may cross page boundaries
+    pub unsafe fn try_read<T>(
+        address: VirtualAddress,
+        privilege: Privilege,
+    ) -> Result<T, PageFault> {
+        unsafe { Self::handle_faults(|machine| machine.read(address, AccessKind::Read, privilege)) }
+    }
+
+    /// Fetches `T` from emulated memory as an instruction, requiring execute permission
+    pub unsafe fn try_fetch<T>(
+        address: VirtualAddress,
+        privilege: Privilege,
+    ) -> Result<T, PageFault> {
+        unsafe {
+            Self::handle_faults(|machine| machine.read(address, AccessKind::Execute, privilege))
+        }
+    }
+
+    /// Writes `value` to emulated memory, which may cross page boundaries. Nothing is written
+    /// if any page faults.
+    pub unsafe fn try_write<T>(
+        address: VirtualAddress,
+        value: T,
+        privilege: Privilege,
+    ) -> Result<(), PageFault> {
+        unsafe {
+            // The bytes of value are moved into emulated memory only if the write succeeds
+            let value = ManuallyDrop::new(value);
+            let result = Self::handle_faults(|machine| machine.write(address, &*value, privilege));
+            if result.is_err() {
+                drop(ManuallyDrop::into_inner(value));
+            }
+            result
+        }
+    }
+
+    /// Fills `count` bytes of emulated memory with `value`. Nothing is written if any page
+    /// faults.
+    pub unsafe fn try_write_bytes(
+        address: VirtualAddress,
+        value: u8,
+        count: usize,
+        privilege: Privilege,
+    ) -> Result<(), PageFault> {
+        unsafe {
+            Self::handle_faults(|machine| machine.write_bytes(address, value, count, privilege))
+        }
+    }
+}
+
+#[allow(clippy::missing_safety_doc)]
+impl<A: Arch + 'static> EmulateArch<A> {
+    /// Number of CPUs of the emulated machine
+    pub unsafe fn cpu_count() -> usize {
+        machine::with::<A, _>(|machine| machine.cpu_count())
+    }
+
+    /// CPU that tables, accesses and invalidations currently act on
+    pub unsafe fn cpu() -> usize {
+        machine::with::<A, _>(|machine| machine.cpu)
+    }
+
+    /// Switches the CPU that tables, accesses and invalidations act on
+    pub unsafe fn set_cpu(cpu: usize) {
+        machine::with::<A, _>(|machine| {
+            assert!(
+                cpu < machine.cpu_count(),
+                "emulated CPU {} does not exist",
+                cpu
+            );
+            machine.cpu = cpu;
+        });
+    }
+
+    /// Sets how the TLB of the emulated machine checks cached translations
+    pub unsafe fn set_tlb_check(check: TlbCheck) {
+        machine::with::<A, _>(|machine| machine.tlb_check = check);
+    }
+
+    /// Sets how the MMU of the emulated machine maintains accessed and dirty flags
+    pub unsafe fn set_accessed_dirty(mode: AccessedDirty) {
+        machine::with::<A, _>(|machine| machine.accessed_dirty = mode);
+    }
+
+    /// Numbers of single page and full TLB invalidations on the current CPU so far
+    pub unsafe fn invalidations() -> (usize, usize) {
+        machine::with::<A, _>(|machine| machine.invalidations())
+    }
+
+    /// Takes the stale translations recorded since the last call
+    pub unsafe fn stale_translations() -> Vec<StaleTranslation<A>> {
+        machine::with::<A, _>(|machine| mem::take(&mut machine.stale))
+    }
+}
+
+/// Defines a test for each of the given functions, running it on a fresh machine of every
+/// architecture
+#[cfg(test)]
+macro_rules! all_archs {
+    ($($test:ident),+ $(,)?) => {
+        mod all_archs {
+            $(
+                #[test]
+                fn $test() {
+                    use $crate::arch::run_emulated;
+                    run_emulated::<$crate::X8664Arch>(super::$test::<$crate::X8664Arch>);
+                    run_emulated::<$crate::X86Arch>(super::$test::<$crate::X86Arch>);
+                    run_emulated::<$crate::AArch64Arch>(super::$test::<$crate::AArch64Arch>);
+                    run_emulated::<$crate::RiscV64Sv39Arch>(
+                        super::$test::<$crate::RiscV64Sv39Arch>,
+                    );
+                    run_emulated::<$crate::RiscV64Sv48Arch>(
+                        super::$test::<$crate::RiscV64Sv48Arch>,
+                    );
+                }
+            )+
+        }
+    };
+}
+#[cfg(test)]
+pub(crate) use all_archs;
+
+/// Runs a test on a fresh machine emulating `A`
+#[cfg(test)]
+pub(crate) fn run_emulated<A: Arch + 'static>(test: unsafe fn(&'static [MemoryArea])) {
+    // A second CPU for SMP tests
+    let mut machine = EmulatedMachine::<A>::new(16 * MEGABYTE).with_cpus(1);
+    // The test is done before the machine is dropped
+    let areas = unsafe { machine.static_areas() };
+    machine.enter(|| unsafe { test(areas) });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        AccessKind, AccessedDirty, EmulateArch, EmulatedMachine, PageFault, PageFaultReason,
+        Privilege, TlbCheck,
+    };
+    use crate::{
+        Arch, BuddyAllocator, BumpAllocator, FrameAllocator, MapError, MemoryArea, MemoryType,
+        PageEntry, PageFlags, PageMapper, PageSize, PhysicalAddress, TableKind, VirtualAddress,
+        X8664Arch, MEGABYTE,
+    };
+
+    all_archs!(
+        tlb,
+        tlb_sizes,
+        faults,
+        execute,
+        software_bits,
+        memory_types,
+        accessed_dirty,
+        smp
+    );
+
+    unsafe fn tlb<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Record);
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            mapper
+                .map(virt, PageSize::base(), PageFlags::new().write(true))
+                .expect("failed to map page")
+                .flush();
+            EmulateArch::<A>::write::<u8>(virt, 1);
+
+            let other = mapper.allocator_mut().allocate_one().unwrap();
+            EmulateArch::<A>::write::<u8>(EmulateArch::<A>::phys_to_virt(other), 2);
+            let (_, _, flush) = mapper
+                .remap_with_full(virt, PageSize::base(), |_, flags| (other, flags))
+                .expect("failed to remap page");
+
+            // Until flushed, accesses use the cached translation
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 1);
+            let stale = EmulateArch::<A>::stale_translations();
+            assert_eq!(stale.len(), 1);
+            assert_eq!(stale[0].virt, virt);
+            assert_eq!(stale[0].current.unwrap().address(), Ok(other));
+
+            flush.flush();
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 2);
+            assert!(EmulateArch::<A>::stale_translations().is_empty());
+
+            let (_, _, flush) = mapper
+                .unmap_phys(virt, PageSize::base(), false)
+                .expect("failed to unmap");
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 2);
+            let stale = EmulateArch::<A>::stale_translations();
+            assert_eq!(stale.len(), 1);
+            assert!(stale[0].current.is_none());
+
+            flush.flush();
+            assert!(mapper.translate(virt).is_none());
+        }
+    }
+
+    unsafe fn tlb_sizes<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Record);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let base = PageSize::base();
+            let huge = PageSize::<EmulateArch<A>>::from_level(1).expect("no huge pages");
+            let phys = PhysicalAddress::new(0);
+            let virt = VirtualAddress::new(4 * huge.bytes());
+            let small = virt.add(A::PAGE_SIZE);
+
+            // A page cached, then unmapped with its table without a flush
+            mapper
+                .map_phys(small, phys, base, PageFlags::new())
+                .expect("failed to map page")
+                .flush();
+            EmulateArch::<A>::read::<u8>(small);
+            let (_, _, flush) = mapper
+                .unmap_phys(small, base, true)
+                .expect("failed to unmap page");
+            flush.ignore();
+
+            // A huge page over it, cached at its base and then changed without a flush
+            mapper
+                .map_phys(virt, phys, huge, PageFlags::new().write(true))
+                .expect("failed to map huge page")
+                .flush();
+            EmulateArch::<A>::read::<u8>(virt);
+            mapper
+                .remap(virt, huge, PageFlags::new())
+                .expect("failed to remap huge page")
+                .ignore();
+
+            // Accesses past the smaller page still use the stale huge page
+            let after = small.add(A::PAGE_SIZE);
+            EmulateArch::<A>::read::<u8>(after);
+            let stale = EmulateArch::<A>::stale_translations();
+            assert_eq!(stale.len(), 1);
+            assert_eq!(stale[0].virt, after);
+
+            // Invalidating any address of the huge page drops it, and the smaller page with it
+            EmulateArch::<A>::invalidate(small);
+            EmulateArch::<A>::read::<u8>(after);
+            EmulateArch::<A>::read::<u8>(small);
+            assert!(EmulateArch::<A>::stale_translations().is_empty());
+        }
+    }
+
+    unsafe fn faults<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            let next = virt.add(A::PAGE_SIZE);
+            assert_eq!(
+                EmulateArch::<A>::try_read::<u8>(virt, Privilege::User),
+                Err(PageFault {
+                    address: virt,
+                    kind: AccessKind::Read,
+                    privilege: Privilege::User,
+                    reason: PageFaultReason::NotPresent,
+                })
+            );
+
+            // Accesses crossing into an unmapped page fault at its start, without writing
+            mapper
+                .map(
+                    virt,
+                    PageSize::base(),
+                    PageFlags::new().user(true).write(true),
+                )
+                .expect("failed to map page")
+                .flush();
+            let crossing = VirtualAddress::new(next.data() - 2);
+            let fault = EmulateArch::<A>::try_write::<u32>(crossing, 0x1234_5678, Privilege::User)
+                .unwrap_err();
+            assert_eq!(fault.address, next);
+            assert_eq!(fault.reason, PageFaultReason::NotPresent);
+            assert_eq!(EmulateArch::<A>::read::<u16>(crossing), 0);
+
+            mapper
+                .map(next, PageSize::base(), PageFlags::new().write(true))
+                .expect("failed to map page")
+                .flush();
+            EmulateArch::<A>::write::<u32>(crossing, 0x1234_5678);
+            assert_eq!(EmulateArch::<A>::read::<u32>(crossing), 0x1234_5678);
+            assert_eq!(EmulateArch::<A>::read::<u16>(next), 0x1234);
+
+            let fault = EmulateArch::<A>::try_read::<u32>(crossing, Privilege::User).unwrap_err();
+            assert_eq!(fault.address, next);
+            assert_eq!(fault.reason, PageFaultReason::NotUser);
+
+            mapper
+                .remap(next, PageSize::base(), PageFlags::new())
+                .expect("failed to remap page")
+                .flush();
+            let fault =
+                EmulateArch::<A>::try_write_bytes(crossing, 0, 4, Privilege::Kernel).unwrap_err();
+            assert_eq!(fault.address, next);
+            assert_eq!(fault.kind, AccessKind::Write);
+            assert_eq!(fault.reason, PageFaultReason::NotWritable);
+
+            if A::ENTRY_FLAG_NO_EXEC | A::ENTRY_FLAG_EXEC != 0 {
+                let fault = EmulateArch::<A>::try_fetch::<u8>(virt, Privilege::User).unwrap_err();
+                assert_eq!(fault.reason, PageFaultReason::NotExecutable);
+            }
+
+            // Demand paging through the fault handler, with frames from a separate allocator
+            let mut demand = PageMapper::<EmulateArch<A>, _>::current(
+                TableKind::User,
+                BumpAllocator::<EmulateArch<A>>::new(areas, MEGABYTE / 2),
+            );
+            EmulateArch::<A>::set_fault_handler(move |fault| {
+                if fault.reason != PageFaultReason::NotPresent {
+                    return false;
+                }
+                let page = VirtualAddress::new(fault.address.data() & !A::PAGE_OFFSET_MASK);
+                match demand.map(page, PageSize::base(), PageFlags::new().write(true)) {
+                    Ok(flush) => {
+                        flush.flush();
+                        true
+                    }
+                    Err(_) => false,
+                }
+            });
+            let lazy = VirtualAddress::new(8 * MEGABYTE);
+            assert_eq!(EmulateArch::<A>::read::<u64>(lazy), 0);
+            EmulateArch::<A>::write::<u64>(lazy.add(A::PAGE_SIZE), 42);
+            assert_eq!(EmulateArch::<A>::read::<u64>(lazy.add(A::PAGE_SIZE)), 42);
+            assert!(mapper.translate(lazy.add(A::PAGE_SIZE)).is_some());
+            assert_eq!(
+                EmulateArch::<A>::try_write::<u8>(next, 0, Privilege::Kernel)
+                    .unwrap_err()
+                    .reason,
+                PageFaultReason::NotWritable
+            );
+            EmulateArch::<A>::clear_fault_handler();
+        }
+    }
+
+    unsafe fn execute<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let fetch = |virt, privilege| {
+                EmulateArch::<A>::try_fetch::<u8>(virt, privilege).map_err(|fault| fault.reason)
+            };
+
+            // User code the kernel may not execute, as hardening
+            let code = VirtualAddress::new(4 * MEGABYTE);
+            let flags = PageFlags::new()
+                .user(true)
+                .execute(true)
+                .kernel_execute(false);
+            assert!(flags.has_user_execute());
+            assert!(!flags.has_kernel_execute());
+            mapper
+                .map(code, PageSize::base(), flags)
+                .expect("failed to map page")
+                .flush();
+            assert_eq!(fetch(code, Privilege::User), Ok(0));
+            assert_eq!(
+                fetch(code, Privilege::Kernel),
+                Err(PageFaultReason::NotExecutable)
+            );
+            let (_, mapped, _) = mapper.translate(code).expect("failed to translate page");
+            assert!(mapped.has_execute());
+            assert!(!mapped.has_kernel_execute());
+
+            // Kernel code stays executable by the kernel only
+            let kernel = code.add(A::PAGE_SIZE);
+            let flags = PageFlags::new().execute(true).user_execute(false);
+            assert!(!flags.has_user_execute());
+            assert!(flags.has_kernel_execute());
+            mapper
+                .map(kernel, PageSize::base(), flags)
+                .expect("failed to map page")
+                .flush();
+            assert_eq!(fetch(kernel, Privilege::Kernel), Ok(0));
+            assert_eq!(
+                fetch(kernel, Privilege::User),
+                Err(PageFaultReason::NotUser)
+            );
+
+            if A::ENTRY_FLAG_NO_EXEC | A::ENTRY_FLAG_EXEC != 0 {
+                let flags = PageFlags::<A>::new().user(true).execute(false);
+                assert!(!flags.has_user_execute());
+                assert!(!flags.has_kernel_execute());
+                assert!(!flags.user_execute(true).has_kernel_execute());
+            }
+
+            // The order of `user` and the execute permissions does not matter
+            let user_last = PageFlags::<A>::new().user_execute(true).user(true);
+            let user_first = PageFlags::<A>::new().user(true).user_execute(true);
+            assert_eq!(user_last.data(), user_first.data());
+            assert!(user_last.has_user_execute());
+            let kernel_last = PageFlags::<A>::new()
+                .user(true)
+                .kernel_execute(true)
+                .user(false);
+            assert_eq!(
+                kernel_last.data(),
+                PageFlags::<A>::new().kernel_execute(true).data()
+            );
+            assert!(kernel_last.has_kernel_execute() && !kernel_last.has_unsupported_execute());
+
+            // Execution the architecture cannot allow is rejected instead of dropped
+            let flags = PageFlags::new().kernel_execute(true).user(true);
+            let res = mapper.map_phys(
+                code.add(2 * A::PAGE_SIZE),
+                PhysicalAddress::new(0),
+                PageSize::base(),
+                flags,
+            );
+            let remapped = mapper.remap(code, PageSize::base(), flags);
+            if A::ENTRY_FLAG_NO_KERNEL_EXEC == 0 {
+                assert!(!flags.has_kernel_execute());
+                assert_eq!(res.err(), Some(MapError::UnsupportedExecute));
+                assert_eq!(remapped.err(), Some(MapError::UnsupportedExecute));
+                // Entry bits cannot hold the request
+                assert!(!PageFlags::<A>::from_data(flags.data()).has_unsupported_execute());
+            } else {
+                assert!(flags.has_kernel_execute());
+                res.expect("failed to map page").flush();
+                remapped.expect("failed to remap page").flush();
+            }
+        }
+    }
+
+    unsafe fn software_bits<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let bits = PageFlags::<A>::SOFTWARE_BITS;
+            let all = (1 << bits) - 1;
+            assert!(bits >= 1);
+            assert_eq!(A::ENTRY_FLAG_SOFTWARE_MASK & !A::ENTRY_FLAGS_MASK, 0);
+            assert_eq!(
+                A::ENTRY_FLAG_SOFTWARE_MASK
+                    & (A::ENTRY_FLAG_PRESENT
+                        | A::ENTRY_FLAG_COPY_ON_WRITE
+                        | A::ENTRY_FLAG_COUNTER
+                        | A::ENTRY_FLAG_HUGE
+                        | A::ENTRY_MEMORY_TYPE_MASK),
+                0
+            );
+
+            // Values are packed into the bits, dropping what does not fit
+            let flags = PageFlags::<A>::new().write(true).software(all | 1 << bits);
+            assert_eq!(flags.get_software(), all);
+            assert_eq!(
+                flags.data() & A::ENTRY_FLAG_SOFTWARE_MASK,
+                A::ENTRY_FLAG_SOFTWARE_MASK
+            );
+            assert!(flags.has_write());
+            assert_eq!(
+                flags.software(0).data(),
+                PageFlags::<A>::new().write(true).data()
+            );
+
+            // Mapped pages keep their value, which the MMU ignores
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            mapper
+                .map(
+                    virt,
+                    PageSize::base(),
+                    PageFlags::new().user(true).write(true).software(1),
+                )
+                .expect("failed to map page")
+                .flush();
+            let (_, mapped, _) = mapper.translate(virt).expect("failed to translate page");
+            assert_eq!(mapped.get_software(), 1);
+            EmulateArch::<A>::write::<u8>(virt, 1);
+            assert_eq!(
+                EmulateArch::<A>::try_read::<u8>(virt, Privilege::User),
+                Ok(1)
+            );
+
+            // Entries that are not present keep it too
+            let mut entry = PageEntry::<A>::new(0, 0);
+            entry.set_software(all);
+            assert!(!entry.present());
+            assert_eq!(entry.software(), all);
+            assert_eq!(entry.data(), A::ENTRY_FLAG_SOFTWARE_MASK);
+        }
+    }
+
+    unsafe fn memory_types<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let huge = PageSize::<EmulateArch<A>>::from_level(1).expect("no huge pages");
+            let types = [
+                MemoryType::WriteBack,
+                MemoryType::WriteThrough,
+                MemoryType::WriteCombining,
+                MemoryType::Uncached,
+                MemoryType::Device,
+            ];
+
+            // The memory type survives huge pages, whose entries have extra flags
+            for (i, memory_type) in types.into_iter().enumerate() {
+                let flags = PageFlags::new().write(true).memory_type(memory_type);
+                assert_eq!(
+                    flags.data() & A::ENTRY_MEMORY_TYPE_MASK,
+                    A::memory_type_flags(memory_type)
+                );
+                for size in [PageSize::base(), huge] {
+                    let virt = VirtualAddress::new((2 * i + 2) * huge.bytes());
+                    let virt = virt.add(if size.is_huge() { huge.bytes() } else { 0 });
+                    mapper
+                        .map_phys(virt, PhysicalAddress::new(0), size, flags)
+                        .expect("failed to map page")
+                        .flush();
+                    let (_, mapped, page_size) =
+                        mapper.translate(virt).expect("failed to translate page");
+                    assert_eq!(page_size, size);
+                    assert_eq!(mapped.get_memory_type(), flags.get_memory_type());
+                    assert!(mapped.has_write());
+                }
+            }
+
+            // Write-back is the default, and replaces any other type
+            assert_eq!(
+                PageFlags::<A>::new().get_memory_type(),
+                MemoryType::WriteBack
+            );
+            let flags = PageFlags::<A>::new().write_combining(true);
+            assert_eq!(
+                flags.write_combining(false).data(),
+                PageFlags::<A>::new().data()
+            );
+        }
+    }
+
+    unsafe fn accessed_dirty<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Record);
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let flags = |mapper: &PageMapper<EmulateArch<A>, _>, virt| {
+                let (_, flags, _): (_, PageFlags<EmulateArch<A>>, _) =
+                    mapper.translate(virt).expect("failed to translate page");
+                flags
+            };
+            // With the dirty bit modifier, clean pages are read-only until written
+            let clean = PageFlags::new().write(true).dirty(false).accessed(false);
+            assert_eq!(clean.has_write(), A::ENTRY_FLAG_DIRTY_BIT_MODIFIER == 0);
+
+            // The MMU sets the accessed flag on any access and the dirty flag on writes
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            mapper
+                .map(virt, PageSize::base(), clean)
+                .expect("failed to map page")
+                .flush();
+            assert!(!flags(&mapper, virt).is_accessed());
+            EmulateArch::<A>::read::<u8>(virt);
+            assert!(flags(&mapper, virt).is_accessed());
+            assert!(!flags(&mapper, virt).is_dirty());
+            EmulateArch::<A>::write::<u8>(virt, 1);
+            assert!(flags(&mapper, virt).is_dirty());
+            assert!(EmulateArch::<A>::stale_translations().is_empty());
+
+            // Clearing the accessed flag needs no flush, but clearing the dirty flag does
+            let used = flags(&mapper, virt);
+            mapper
+                .remap(virt, PageSize::base(), used.accessed(false))
+                .expect("failed to remap page")
+                .ignore();
+            EmulateArch::<A>::read::<u8>(virt);
+            assert!(EmulateArch::<A>::stale_translations().is_empty());
+            mapper
+                .remap(virt, PageSize::base(), clean)
+                .expect("failed to remap page")
+                .ignore();
+            EmulateArch::<A>::write::<u8>(virt, 2);
+            assert_eq!(EmulateArch::<A>::stale_translations().len(), 1);
+            EmulateArch::<A>::invalidate(virt);
+
+            // Without hardware updates, accesses fault until software sets the flags
+            EmulateArch::<A>::set_accessed_dirty(AccessedDirty::Fault);
+            let lazy = virt.add(A::PAGE_SIZE);
+            mapper
+                .map(lazy, PageSize::base(), clean)
+                .expect("failed to map page")
+                .flush();
+            let fault = EmulateArch::<A>::try_read::<u8>(lazy, Privilege::Kernel).unwrap_err();
+            assert_eq!(fault.reason, PageFaultReason::NotAccessed);
+            mapper
+                .remap(lazy, PageSize::base(), clean.accessed(true))
+                .expect("failed to remap page")
+                .flush();
+            assert_eq!(EmulateArch::<A>::read::<u8>(lazy), 0);
+            let fault = EmulateArch::<A>::try_write::<u8>(lazy, 3, Privilege::Kernel).unwrap_err();
+            if A::ENTRY_FLAG_DIRTY_BIT_MODIFIER != 0 {
+                assert_eq!(fault.reason, PageFaultReason::NotWritable);
+            } else {
+                assert_eq!(fault.reason, PageFaultReason::NotDirty);
+            }
+            assert!(!flags(&mapper, lazy).is_dirty());
+
+            // A fault handler can set the flags instead, like a kernel would
+            let mut handler_mapper = PageMapper::<EmulateArch<A>, _>::current(
+                TableKind::User,
+                BumpAllocator::<EmulateArch<A>>::new(areas, MEGABYTE / 2),
+            );
+            EmulateArch::<A>::set_fault_handler(move |fault| {
+                let (_, flags, _) = match handler_mapper.translate(fault.address) {
+                    Some(some) => some,
+                    None => return false,
+                };
+                let flags = match fault.reason {
+                    PageFaultReason::NotAccessed => flags.accessed(true),
+                    PageFaultReason::NotDirty => flags.dirty(true),
+                    PageFaultReason::NotWritable
+                        if flags.has_flag(A::ENTRY_FLAG_DIRTY_BIT_MODIFIER) =>
+                    {
+                        flags.dirty(true)
+                    }
+                    _ => return false,
+                };
+                match handler_mapper.remap(fault.address, PageSize::base(), flags) {
+                    Ok(flush) => {
+                        flush.flush();
+                        true
+                    }
+                    Err(_) => false,
+                }
+            });
+            let handled = lazy.add(A::PAGE_SIZE);
+            mapper
+                .map(handled, PageSize::base(), clean)
+                .expect("failed to map page")
+                .flush();
+            EmulateArch::<A>::write::<u8>(handled, 4);
+            assert_eq!(EmulateArch::<A>::read::<u8>(handled), 4);
+            assert!(flags(&mapper, handled).is_accessed());
+            assert!(flags(&mapper, handled).is_dirty());
+            EmulateArch::<A>::clear_fault_handler();
+            assert!(EmulateArch::<A>::stale_translations().is_empty());
+        }
+    }
+
+    unsafe fn smp<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Record);
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            assert_eq!(EmulateArch::<A>::cpu_count(), 2);
+
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            mapper
+                .map(virt, PageSize::base(), PageFlags::new().write(true))
+                .expect("failed to map page")
+                .flush();
+            EmulateArch::<A>::write::<u8>(virt, 1);
+            EmulateArch::<A>::set_cpu(1);
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 1);
+            EmulateArch::<A>::set_cpu(0);
+
+            let other = mapper.allocator_mut().allocate_one().unwrap();
+            EmulateArch::<A>::write::<u8>(EmulateArch::<A>::phys_to_virt(other), 2);
+            mapper
+                .remap_with_full(virt, PageSize::base(), |_, flags| (other, flags))
+                .expect("failed to remap page")
+                .2
+                .flush();
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 2);
+            assert!(EmulateArch::<A>::stale_translations().is_empty());
+
+            // The flush only reached the first CPU
+            EmulateArch::<A>::set_cpu(1);
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 1);
+            let stale = EmulateArch::<A>::stale_translations();
+            assert_eq!(stale.len(), 1);
+            assert_eq!(stale[0].cpu, 1);
+
+            EmulateArch::<A>::invalidate(virt);
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 2);
+            assert!(EmulateArch::<A>::stale_translations().is_empty());
+
+            // Tables are per CPU, and only split between user and kernel where the
+            // architecture does so
+            let kernel = EmulateArch::<A>::table(TableKind::Kernel);
+            let current = EmulateArch::<A>::table(TableKind::User);
+            let user = mapper.allocator_mut().allocate_one().unwrap();
+            EmulateArch::<A>::set_table(TableKind::User, user);
+            assert_eq!(EmulateArch::<A>::table(TableKind::User), user);
+            if A::SPLIT_TABLES {
+                assert_eq!(EmulateArch::<A>::table(TableKind::Kernel), kernel);
+                let user_virt = EmulateArch::<A>::phys_to_virt(user);
+                assert_eq!(EmulateArch::<A>::read::<u8>(user_virt), 0);
+                assert!(EmulateArch::<A>::try_read::<u8>(
+                    VirtualAddress::new(0),
+                    Privilege::Kernel
+                )
+                .is_err());
+            } else {
+                assert_eq!(EmulateArch::<A>::table(TableKind::Kernel), user);
+            }
+            EmulateArch::<A>::set_cpu(0);
+            assert_eq!(EmulateArch::<A>::table(TableKind::User), current);
+            assert_eq!(EmulateArch::<A>::read::<u8>(virt), 2);
+        }
+    }
+
+    #[test]
+    fn isolated_machines() {
+        unsafe {
+            let phys = PhysicalAddress::new(MEGABYTE);
+            let virt = EmulateArch::<X8664Arch>::phys_to_virt(phys);
+            let mut first = EmulatedMachine::<X8664Arch>::new(2 * MEGABYTE);
+            let mut second = EmulatedMachine::<X8664Arch>::with_areas(
+                4 * MEGABYTE,
+                &[MemoryArea {
+                    base: PhysicalAddress::new(2 * MEGABYTE),
+                    size: 2 * MEGABYTE,
+                }],
+            );
+            assert_eq!(second.areas()[0].base.data(), 2 * MEGABYTE);
+
+            first.enter(|| {
+                EmulateArch::<X8664Arch>::write::<u8>(virt, 1);
+                second.enter(|| {
+                    assert_eq!(EmulateArch::<X8664Arch>::read::<u8>(virt), 0);
+                    EmulateArch::<X8664Arch>::write::<u8>(virt, 2);
+                });
+                // Leaving the inner machine restores the outer one
+                assert_eq!(EmulateArch::<X8664Arch>::read::<u8>(virt), 1);
+            });
+            second.enter(|| assert_eq!(EmulateArch::<X8664Arch>::read::<u8>(virt), 2));
+        }
+    }
+}
@@ -0,0 +1,35 @@
+use crate::{PageEntry, PhysicalAddress, VirtualAddress};
+
+/// How the emulated TLB verifies cached translations against the page tables
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TlbCheck {
+    /// Use cached translations without checking them, like real hardware
+    Off,
+    /// Record every access through a stale translation, see
+    /// [`EmulateArch::stale_translations`](super::EmulateArch::stale_translations)
+    Record,
+    /// Panic on the first access through a stale translation
+    Panic,
+}
+
+/// An access that used a cached translation no longer matching the page tables, meaning a flush
+/// was missed after the tables were changed
+#[derive(Clone, Copy, Debug)]
+pub struct StaleTranslation<A> {
+    /// CPU whose TLB held the translation
+    pub cpu: usize,
+    /// Accessed address
+    pub virt: VirtualAddress,
+    /// Leaf entry in the TLB, which was used for the access
+    pub cached: PageEntry<A>,
+    /// Leaf entry currently in the page tables, if any
+    pub current: Option<PageEntry<A>>,
+}
+
+#[derive(Clone, Copy)]
+pub(super) struct TlbEntry<A> {
+    pub(super) entry: PageEntry<A>,
+    pub(super) level: usize,
+    // Physical address of the entry, where the MMU sets accessed and dirty flags
+    pub(super) pte: PhysicalAddress,
+}
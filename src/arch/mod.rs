@@ -1,11 +1,15 @@
 use core::ptr;
 
-use crate::{MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
+use crate::{MemoryArea, MemoryType, PhysicalAddress, TableKind, VirtualAddress};
 
 //TODO: Support having all page tables compile on all architectures
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+pub(crate) use self::emulate::{all_archs, run_emulated};
 #[cfg(all(feature = "std", target_pointer_width = "64"))]
-pub use self::emulate::EmulateArch;
-#[cfg(target_pointer_width = "32")]
+pub use self::emulate::{
+    AccessKind, AccessedDirty, EmulateArch, EmulatedMachine, FaultHandler, PageFault,
+    PageFaultReason, Privilege, StaleTranslation, TlbCheck,
+};
 pub use self::x86::X86Arch;
 #[cfg(target_pointer_width = "64")]
 pub use self::{
@@ -20,15 +24,23 @@ mod aarch64;
 mod emulate;
 #[cfg(target_pointer_width = "64")]
 mod riscv64;
-#[cfg(target_pointer_width = "32")]
 mod x86;
 #[cfg(target_pointer_width = "64")]
 mod x86_64;
 
+/// Paging format and MMU of an architecture
+///
+/// # Safety
+///
+/// The unsafe functions access memory, tables and TLBs of the running machine directly. Addresses
+/// must be mapped and valid for the access, and tables must be valid tables of the architecture
+/// that are not in use by anything the change would break.
+#[allow(clippy::missing_safety_doc)]
 pub trait Arch: Clone + Copy {
     const PAGE_SHIFT: usize;
     const PAGE_ENTRY_SHIFT: usize;
     const PAGE_LEVELS: usize;
+    const HUGE_PAGE_LEVELS: usize = 0; // Number of levels above 0 that can hold leaf entries
 
     const ENTRY_ADDRESS_WIDTH: usize; // Number of bits of physical address in PTE
     const ENTRY_ADDRESS_SHIFT: usize = Self::PAGE_SHIFT; // Offset of physical address in PTE
@@ -41,11 +53,22 @@ pub trait Arch: Clone + Copy {
     const ENTRY_FLAG_TABLE_USER: usize = Self::ENTRY_FLAG_PAGE_USER; // Directory user page table flag
     const ENTRY_FLAG_NO_EXEC: usize;
     const ENTRY_FLAG_EXEC: usize;
+    const ENTRY_FLAG_NO_USER_EXEC: usize = 0; // Prevents execution by user mode only, if the architecture separates it
+    const ENTRY_FLAG_NO_KERNEL_EXEC: usize = 0; // Prevents execution by the kernel only, if the architecture separates it
     const ENTRY_FLAG_GLOBAL: usize;
     const ENTRY_FLAG_NO_GLOBAL: usize;
-    const ENTRY_FLAG_WRITE_COMBINING: usize;
+    const ENTRY_MEMORY_TYPE_MASK: usize; // Leaf entry bits selecting the memory type
+    const ENTRY_FLAG_HUGE: usize = 0; // Marks entries above level 0 as leaves
+    const ENTRY_FLAG_ACCESSED: usize; // Set by the MMU when a leaf entry is used
+    const ENTRY_FLAG_DIRTY: usize; // Set by the MMU when a leaf entry is used for a write
+    const ENTRY_FLAG_DIRTY_BIT_MODIFIER: usize = 0; // Writes to read-only entries with this flag make them writable instead of faulting
+    const ENTRY_FLAG_COPY_ON_WRITE: usize; // Software bit marking leaves shared read-only by a fork
+    const ENTRY_FLAG_SOFTWARE_MASK: usize; // Bits ignored by the MMU, even when not present, and left to the OS besides copy-on-write
+    const ENTRY_FLAG_COUNTER: usize = 0; // Bit ignored by the MMU in every entry, storing the number of entries in use of a table across its first entries, or 0 to scan tables instead
 
     const PHYS_OFFSET: usize;
+    const SPLIT_TABLES: bool = false; // User and kernel halves use separate root tables
+    const FLUSH_ALL_THRESHOLD: usize = 32; // Pages above which invalidating them one at a time is slower than invalidating all
 
     const PAGE_SIZE: usize = 1 << Self::PAGE_SHIFT;
     const PAGE_OFFSET_MASK: usize = Self::PAGE_SIZE - 1;
@@ -101,4 +124,52 @@ pub trait Arch: Clone + Copy {
     }
 
     fn virt_is_valid(address: VirtualAddress) -> bool;
+
+    /// Half of the address space holding `address`, given by the highest bit the tables
+    /// translate. Without `SPLIT_TABLES`, one root table maps both halves, and each is managed by
+    /// mappers of its kind only. Architectures whose split tables each translate every bit
+    /// select on the upper bits instead.
+    #[inline(always)]
+    fn table_kind(address: VirtualAddress) -> TableKind {
+        if address.data() & (1 << (Self::PAGE_ADDRESS_SHIFT - 1)) == 0 {
+            TableKind::User
+        } else {
+            TableKind::Kernel
+        }
+    }
+
+    /// Whether a present entry at `level` maps memory directly, instead of pointing to the next
+    /// level table. Entries at level 0 are always leaves.
+    #[inline(always)]
+    fn entry_is_leaf(_entry: usize, level: usize) -> bool {
+        level == 0
+    }
+
+    /// Converts page flags into the flags of a leaf entry at `level`
+    #[inline(always)]
+    fn leaf_flags(flags: usize, level: usize) -> usize {
+        if level == 0 {
+            flags
+        } else {
+            flags | Self::ENTRY_FLAG_HUGE
+        }
+    }
+
+    /// Leaf entry bits in `ENTRY_MEMORY_TYPE_MASK` selecting `memory_type`, which are 0 for
+    /// `MemoryType::WriteBack`. Types the architecture lacks use the closest stricter one.
+    fn memory_type_flags(memory_type: MemoryType) -> usize;
+
+    /// Memory type selected by the leaf entry bits of `flags`. Where several types share bits,
+    /// the strictest of them is returned.
+    fn memory_type(flags: usize) -> MemoryType;
+
+    /// Converts the flags of a leaf entry at `level` back into page flags, undoing `leaf_flags`
+    #[inline(always)]
+    fn leaf_page_flags(flags: usize, level: usize) -> usize {
+        if level == 0 {
+            flags
+        } else {
+            flags & !Self::ENTRY_FLAG_HUGE
+        }
+    }
 }
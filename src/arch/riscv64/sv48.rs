@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use crate::{Arch, MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
+use crate::{Arch, MemoryArea, MemoryType, PhysicalAddress, TableKind, VirtualAddress};
 
 #[derive(Clone, Copy)]
 pub struct RiscV64Sv48Arch;
@@ -9,6 +9,7 @@ impl Arch for RiscV64Sv48Arch {
     const PAGE_SHIFT: usize = 12; // 4096 bytes
     const PAGE_ENTRY_SHIFT: usize = 9; // 512 entries, 8 bytes each
     const PAGE_LEVELS: usize = 4; // L0, L1, L2, L3
+    const HUGE_PAGE_LEVELS: usize = 3; // Megapages, gigapages and terapages
 
     const ENTRY_ADDRESS_WIDTH: usize = 44;
     const ENTRY_ADDRESS_SHIFT: usize = 10;
@@ -21,10 +22,14 @@ impl Arch for RiscV64Sv48Arch {
     const ENTRY_FLAG_PAGE_USER: usize = 1 << 4;
     const ENTRY_FLAG_TABLE_USER: usize = 0;
     const ENTRY_FLAG_NO_EXEC: usize = 0;
-    const ENTRY_FLAG_EXEC: usize = 1 << 3;
+    const ENTRY_FLAG_EXEC: usize = 1 << 3; // Supervisor mode never executes user pages
     const ENTRY_FLAG_GLOBAL: usize = 1 << 5;
     const ENTRY_FLAG_NO_GLOBAL: usize = 0;
-    const ENTRY_FLAG_WRITE_COMBINING: usize = 0;
+    const ENTRY_MEMORY_TYPE_MASK: usize = 0b11 << 61; // PBMT, needs the Svpbmt extension
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 6;
+    const ENTRY_FLAG_DIRTY: usize = 1 << 7;
+    const ENTRY_FLAG_COPY_ON_WRITE: usize = 1 << 8; // First RSW bit
+    const ENTRY_FLAG_SOFTWARE_MASK: usize = 1 << 9; // Second RSW bit, none is left to count entries
 
     const PHYS_OFFSET: usize = 0xFFFF_8000_0000_0000;
 
@@ -46,6 +51,8 @@ impl Arch for RiscV64Sv48Arch {
         }
     }
 
+    // One root table maps both halves, so both kinds name it. User tables get the kernel half by
+    // sharing it, see `PageMapper::with_kernel_template`.
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
         unsafe {
@@ -73,6 +80,25 @@ impl Arch for RiscV64Sv48Arch {
         }
     }
 
+    #[inline(always)]
+    fn memory_type_flags(memory_type: MemoryType) -> usize {
+        // Without write-through, it is non-cacheable like write-combining
+        match memory_type {
+            MemoryType::WriteBack => 0,
+            MemoryType::WriteThrough | MemoryType::WriteCombining | MemoryType::Uncached => 1 << 61,
+            MemoryType::Device => 2 << 61,
+        }
+    }
+
+    #[inline(always)]
+    fn memory_type(flags: usize) -> MemoryType {
+        match (flags & Self::ENTRY_MEMORY_TYPE_MASK) >> 61 {
+            0 => MemoryType::WriteBack,
+            1 => MemoryType::Uncached,
+            _ => MemoryType::Device,
+        }
+    }
+
     fn virt_is_valid(address: VirtualAddress) -> bool {
         // RISC-V SV48 uses 48-bit sign-extended addresses, identical to 4-level paging on x86_64.
         let mask = !((Self::PAGE_ADDRESS_SIZE as usize - 1) >> 1);
@@ -80,6 +106,12 @@ impl Arch for RiscV64Sv48Arch {
 
         masked == mask || masked == 0
     }
+
+    #[inline(always)]
+    fn entry_is_leaf(entry: usize, level: usize) -> bool {
+        // Any of R, W or X set makes a leaf, otherwise the entry points to the next level
+        level == 0 || entry & (Self::ENTRY_FLAG_READWRITE | Self::ENTRY_FLAG_EXEC) != 0
+    }
 }
 
 #[cfg(test)]
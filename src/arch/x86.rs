@@ -1,7 +1,7 @@
 //TODO: USE PAE
 use core::arch::asm;
 
-use crate::{Arch, MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
+use crate::{Arch, MemoryArea, MemoryType, PhysicalAddress, TableKind, VirtualAddress};
 
 #[derive(Clone, Copy)]
 pub struct X86Arch;
@@ -10,6 +10,7 @@ impl Arch for X86Arch {
     const PAGE_SHIFT: usize = 12; // 4096 bytes
     const PAGE_ENTRY_SHIFT: usize = 10; // 1024 entries, 4 bytes each
     const PAGE_LEVELS: usize = 2; // PD, PT
+    const HUGE_PAGE_LEVELS: usize = 1; // 4 MiB pages
 
     const ENTRY_ADDRESS_WIDTH: usize = 20;
     const ENTRY_FLAG_DEFAULT_PAGE: usize = Self::ENTRY_FLAG_PRESENT;
@@ -18,12 +19,17 @@ impl Arch for X86Arch {
     const ENTRY_FLAG_READONLY: usize = 0;
     const ENTRY_FLAG_READWRITE: usize = 1 << 1;
     const ENTRY_FLAG_PAGE_USER: usize = 1 << 2;
-    // Not used: const ENTRY_FLAG_HUGE: usize = 1 << 7;
+    const ENTRY_FLAG_HUGE: usize = 1 << 7;
     const ENTRY_FLAG_GLOBAL: usize = 1 << 8;
     const ENTRY_FLAG_NO_GLOBAL: usize = 0;
-    const ENTRY_FLAG_NO_EXEC: usize = 0; // NOT AVAILABLE UNLESS PAE IS USED!
+    const ENTRY_FLAG_NO_EXEC: usize = 0; // NOT AVAILABLE UNLESS PAE IS USED! SMEP still keeps the kernel from executing user pages
     const ENTRY_FLAG_EXEC: usize = 0;
-    const ENTRY_FLAG_WRITE_COMBINING: usize = 1 << 7;
+    const ENTRY_MEMORY_TYPE_MASK: usize = 1 << 4 | 1 << 3; // PCD and PWT, the PAT bit is unused
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 5;
+    const ENTRY_FLAG_DIRTY: usize = 1 << 6;
+    const ENTRY_FLAG_COPY_ON_WRITE: usize = 1 << 9; // Available to software
+    const ENTRY_FLAG_SOFTWARE_MASK: usize = 1 << 10;
+    const ENTRY_FLAG_COUNTER: usize = 1 << 11;
 
     const PHYS_OFFSET: usize = 0x8000_0000;
 
@@ -33,31 +39,73 @@ impl Arch for X86Arch {
 
     #[inline(always)]
     unsafe fn invalidate(address: VirtualAddress) {
-        asm!("invlpg [{0}]", in(reg) address.data());
+        unsafe {
+            asm!("invlpg [{0}]", in(reg) address.data());
+        }
     }
 
+    // One root table maps both halves, so both kinds name it. User tables get the kernel half by
+    // sharing it, see `PageMapper::with_kernel_template`.
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
-        let address: usize;
-        asm!("mov {0}, cr3", out(reg) address);
-        PhysicalAddress::new(address)
+        unsafe {
+            let address: usize;
+            asm!("mov {0}, cr3", out(reg) address);
+            PhysicalAddress::new(address)
+        }
     }
 
     #[inline(always)]
     unsafe fn set_table(_table_kind: TableKind, address: PhysicalAddress) {
-        asm!("mov cr3, {0}", in(reg) address.data());
+        unsafe {
+            asm!("mov cr3, {0}", in(reg) address.data());
+        }
+    }
+
+    #[inline(always)]
+    fn memory_type_flags(memory_type: MemoryType) -> usize {
+        // PWT and PCD select the first four entries of `PAT`
+        match memory_type {
+            MemoryType::WriteBack => 0,
+            MemoryType::WriteThrough => 1 << 3,
+            MemoryType::WriteCombining => 1 << 4,
+            MemoryType::Uncached | MemoryType::Device => 1 << 4 | 1 << 3,
+        }
+    }
+
+    #[inline(always)]
+    fn memory_type(flags: usize) -> MemoryType {
+        match flags & Self::ENTRY_MEMORY_TYPE_MASK {
+            0 => MemoryType::WriteBack,
+            0b01000 => MemoryType::WriteThrough,
+            0b10000 => MemoryType::WriteCombining,
+            _ => MemoryType::Device,
+        }
     }
 
     fn virt_is_valid(_address: VirtualAddress) -> bool {
         // On 32-bit x86, every virtual address is valid
         true
     }
+
+    #[inline(always)]
+    fn entry_is_leaf(entry: usize, level: usize) -> bool {
+        // The page size flag in a PD entry maps a 4 MiB page
+        level == 0 || entry & Self::ENTRY_FLAG_HUGE != 0
+    }
+}
+
+impl X86Arch {
+    /// Value the kernel must load into the IA32_PAT MSR for the memory types of page entries.
+    /// Only the third entry differs from the power on value, with write-combining instead of
+    /// uncached minus. The upper four entries repeat the lower four.
+    pub const PAT: u64 = 0x0001_0406_0001_0406;
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{VirtualAddress, X86Arch};
-    use crate::Arch;
+    use super::X86Arch;
+    use crate::{Arch, MemoryType};
 
     #[test]
     fn constants() {
@@ -69,12 +117,38 @@ mod tests {
         assert_eq!(X86Arch::PAGE_ENTRY_SIZE, 4);
         assert_eq!(X86Arch::PAGE_ENTRIES, 1024);
         assert_eq!(X86Arch::PAGE_ENTRY_MASK, 0x3FF);
-        assert_eq!(X86Arch::PAGE_NEGATIVE_MASK, 0x0000_0000_0000);
+        // Masks of the bits above the address extend to the width of the host usize, so only
+        // the 32 bits of entries and addresses are compared
+        assert_eq!(X86Arch::PAGE_NEGATIVE_MASK as u32, 0x0000_0000);
 
         assert_eq!(X86Arch::ENTRY_ADDRESS_SIZE, 0x0000_0000_0010_0000);
         assert_eq!(X86Arch::ENTRY_ADDRESS_MASK, 0x000F_FFFF);
-        assert_eq!(X86Arch::ENTRY_FLAGS_MASK, 0x0000_0FFF);
+        assert_eq!(X86Arch::ENTRY_FLAGS_MASK as u32, 0x0000_0FFF);
 
         assert_eq!(X86Arch::PHYS_OFFSET, 0x8000_0000);
     }
+
+    #[test]
+    fn memory_types() {
+        // PWT and PCD index the PAT entry holding the memory type encoding
+        for (memory_type, encoding) in [
+            (MemoryType::WriteBack, 0x06),
+            (MemoryType::WriteThrough, 0x04),
+            (MemoryType::WriteCombining, 0x01),
+            (MemoryType::Uncached, 0x00),
+            (MemoryType::Device, 0x00),
+        ] {
+            let flags = X86Arch::memory_type_flags(memory_type);
+            let index = flags >> 3;
+            assert_eq!((X86Arch::PAT >> (index * 8)) & 0xFF, encoding);
+        }
+        for memory_type in [
+            MemoryType::WriteBack,
+            MemoryType::WriteThrough,
+            MemoryType::WriteCombining,
+        ] {
+            let flags = X86Arch::memory_type_flags(memory_type);
+            assert_eq!(X86Arch::memory_type(flags), memory_type);
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use core::arch::asm;
 
-use crate::{Arch, MemoryArea, PhysicalAddress, TableKind, VirtualAddress};
+use crate::{Arch, MemoryArea, MemoryType, PhysicalAddress, TableKind, VirtualAddress};
 
 #[derive(Clone, Copy, Debug)]
 pub struct X8664Arch;
@@ -9,6 +9,7 @@ impl Arch for X8664Arch {
     const PAGE_SHIFT: usize = 12; // 4096 bytes
     const PAGE_ENTRY_SHIFT: usize = 9; // 512 entries, 8 bytes each
     const PAGE_LEVELS: usize = 4; // PML4, PDP, PD, PT
+    const HUGE_PAGE_LEVELS: usize = 2; // 2 MiB and 1 GiB pages
 
     const ENTRY_ADDRESS_WIDTH: usize = 40;
     const ENTRY_FLAG_DEFAULT_PAGE: usize = Self::ENTRY_FLAG_PRESENT;
@@ -17,12 +18,17 @@ impl Arch for X8664Arch {
     const ENTRY_FLAG_READONLY: usize = 0;
     const ENTRY_FLAG_READWRITE: usize = 1 << 1;
     const ENTRY_FLAG_PAGE_USER: usize = 1 << 2;
-    // Not used: const ENTRY_FLAG_HUGE: usize = 1 << 7;
+    const ENTRY_FLAG_HUGE: usize = 1 << 7;
     const ENTRY_FLAG_GLOBAL: usize = 1 << 8;
     const ENTRY_FLAG_NO_GLOBAL: usize = 0;
-    const ENTRY_FLAG_NO_EXEC: usize = 1 << 63;
+    const ENTRY_FLAG_NO_EXEC: usize = 1 << 63; // The kernel never executes user pages, with SMEP
     const ENTRY_FLAG_EXEC: usize = 0;
-    const ENTRY_FLAG_WRITE_COMBINING: usize = 1 << 7;
+    const ENTRY_MEMORY_TYPE_MASK: usize = 1 << 4 | 1 << 3; // PCD and PWT, the PAT bit is unused
+    const ENTRY_FLAG_ACCESSED: usize = 1 << 5;
+    const ENTRY_FLAG_DIRTY: usize = 1 << 6;
+    const ENTRY_FLAG_COPY_ON_WRITE: usize = 1 << 9; // Available to software
+    const ENTRY_FLAG_SOFTWARE_MASK: usize = 0b11 << 10 | 0x3F << 52;
+    const ENTRY_FLAG_COUNTER: usize = 1 << 58;
 
     const PHYS_OFFSET: usize = Self::PAGE_NEGATIVE_MASK + (Self::PAGE_ADDRESS_SIZE >> 1) as usize; // PML4 slot 256 and onwards
 
@@ -37,6 +43,8 @@ impl Arch for X8664Arch {
         }
     }
 
+    // One root table maps both halves, so both kinds name it. User tables get the kernel half by
+    // sharing it, see `PageMapper::with_kernel_template`.
     #[inline(always)]
     unsafe fn table(_table_kind: TableKind) -> PhysicalAddress {
         unsafe {
@@ -53,12 +61,46 @@ impl Arch for X8664Arch {
         }
     }
 
+    #[inline(always)]
+    fn memory_type_flags(memory_type: MemoryType) -> usize {
+        // PWT and PCD select the first four entries of `PAT`
+        match memory_type {
+            MemoryType::WriteBack => 0,
+            MemoryType::WriteThrough => 1 << 3,
+            MemoryType::WriteCombining => 1 << 4,
+            MemoryType::Uncached | MemoryType::Device => 1 << 4 | 1 << 3,
+        }
+    }
+
+    #[inline(always)]
+    fn memory_type(flags: usize) -> MemoryType {
+        match flags & Self::ENTRY_MEMORY_TYPE_MASK {
+            0 => MemoryType::WriteBack,
+            0b01000 => MemoryType::WriteThrough,
+            0b10000 => MemoryType::WriteCombining,
+            _ => MemoryType::Device,
+        }
+    }
+
     fn virt_is_valid(address: VirtualAddress) -> bool {
         // On x86_64, an address is valid if and only if it is canonical. It may still point to
         // unmapped memory, but will always be valid once translated via the page table has
         // suceeded.
         address.is_canonical()
     }
+
+    #[inline(always)]
+    fn entry_is_leaf(entry: usize, level: usize) -> bool {
+        // The page size flag in a PDP or PD entry maps a 1 GiB or 2 MiB page
+        level == 0 || (level < 3 && entry & Self::ENTRY_FLAG_HUGE != 0)
+    }
+}
+
+impl X8664Arch {
+    /// Value the kernel must load into the IA32_PAT MSR for the memory types of page entries.
+    /// Only the third entry differs from the power on value, with write-combining instead of
+    /// uncached minus. The upper four entries repeat the lower four.
+    pub const PAT: u64 = 0x0001_0406_0001_0406;
 }
 
 impl VirtualAddress {
@@ -72,7 +114,7 @@ impl VirtualAddress {
 #[cfg(test)]
 mod tests {
     use super::{VirtualAddress, X8664Arch};
-    use crate::Arch;
+    use crate::{Arch, MemoryType};
 
     #[test]
     fn constants() {
@@ -109,4 +151,19 @@ mod tests {
         no(0x1337_8000_0000_0000);
         no(0x0000_8000_0000_0000);
     }
+
+    #[test]
+    fn memory_types() {
+        // PWT and PCD index the PAT entry holding the memory type encoding
+        for (memory_type, encoding) in [
+            (MemoryType::WriteBack, 0x06),
+            (MemoryType::WriteThrough, 0x04),
+            (MemoryType::WriteCombining, 0x01),
+            (MemoryType::Uncached, 0x00),
+            (MemoryType::Device, 0x00),
+        ] {
+            let index = X8664Arch::memory_type_flags(memory_type) >> 3;
+            assert_eq!((X8664Arch::PAT >> (index * 8)) & 0xFF, encoding);
+        }
+    }
 }
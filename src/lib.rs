@@ -1,5 +1,8 @@
 #![cfg_attr(not(feature = "std"), no_std)]
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
 pub use crate::{allocator::*, arch::*, page::*};
 
 mod allocator;
@@ -12,8 +15,8 @@ pub const GIGABYTE: usize = MEGABYTE * 1024;
 #[cfg(target_pointer_width = "64")]
 pub const TERABYTE: usize = GIGABYTE * 1024;
 
-/// Specific table to be used, needed on some architectures
-//TODO: Use this throughout the code
+/// Half of the address space a table maps, see [`Arch::table_kind`]. Architectures with
+/// `SPLIT_TABLES` have a root table for each, others map both with the same root.
 #[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
 pub enum TableKind {
     /// Userspace page table
@@ -38,6 +41,8 @@ impl PhysicalAddress {
         self.0
     }
 
+    // Offsets by a number of bytes rather than adding two addresses, like `pointer::add`
+    #[allow(clippy::should_implement_trait)]
     #[inline(always)]
     pub fn add(self, offset: usize) -> Self {
         Self(self.0 + offset)
@@ -60,6 +65,8 @@ impl VirtualAddress {
         self.0
     }
 
+    // Offsets by a number of bytes rather than adding two addresses, like `pointer::add`
+    #[allow(clippy::should_implement_trait)]
     #[inline(always)]
     pub fn add(self, offset: usize) -> Self {
         Self(self.0 + offset)
@@ -2,8 +2,8 @@
 
 use rmm::{
     Arch, BuddyAllocator, BumpAllocator, EmulateArch, Flusher, FrameAllocator, FrameCount,
-    MemoryArea, PageFlags, PageFlushAll, PageMapper, PageTable, PhysicalAddress, TableKind,
-    VirtualAddress, GIGABYTE, KILOBYTE, MEGABYTE, TERABYTE,
+    MemoryArea, PageFlags, PageFlushAll, PageMapper, PageSize, PageTable, PhysicalAddress,
+    TableKind, VirtualAddress, GIGABYTE, KILOBYTE, MEGABYTE, TERABYTE,
 };
 use std::marker::PhantomData;
 
@@ -21,37 +21,31 @@ pub fn format_size(size: usize) -> String {
     }
 }
 
-#[allow(dead_code)]
-unsafe fn dump_tables<A: Arch>(table: PageTable<A>) {
+unsafe fn dump_tables<A: Arch>(table: PageTable<A>, dot: bool) {
     unsafe {
-        let level = table.level();
-        for i in 0..A::PAGE_ENTRIES {
-            if level == 0 {
-                if let Some(entry) = table.entry(i) {
-                    if entry.present() {
-                        let base = table.entry_base(i).unwrap();
-                        println!(
-                            "0x{:X}: 0x{:X}",
-                            base.data(),
-                            entry.address().unwrap().data()
-                        );
-                    }
-                }
-            } else {
-                if let Some(next) = table.next(i) {
-                    dump_tables(next);
-                }
-            }
+        let mut out = String::new();
+        if dot {
+            table.dump_dot(&mut out)
+        } else {
+            table.dump_text(&mut out)
         }
+        .expect("failed to dump tables");
+        print!("{}", out);
     }
 }
 
+/// Free list of frames of one size, linked through the first word of each frame
+///
+/// # Safety
+///
+/// Frames are read and written through `Arch::phys_to_virt`, and must be free while listed.
 pub struct SlabNode<A> {
     next: PhysicalAddress,
     count: usize,
     phantom: PhantomData<A>,
 }
 
+#[allow(clippy::missing_safety_doc)]
 impl<A: Arch> SlabNode<A> {
     pub fn new(next: PhysicalAddress, count: usize) -> Self {
         Self {
@@ -89,12 +83,19 @@ impl<A: Arch> SlabNode<A> {
     }
 }
 
+/// Allocator of frames in the sizes of pages, with a free list for each
+///
+/// # Safety
+///
+/// As with [`SlabNode`], the memory handed to it must be free and reachable through
+/// `Arch::phys_to_virt`.
 pub struct SlabAllocator<A> {
     //TODO: Allow allocations up to maximum pageable size
     nodes: [SlabNode<A>; 4],
     phantom: PhantomData<A>,
 }
 
+#[allow(clippy::missing_safety_doc)]
 impl<A: Arch> SlabAllocator<A> {
     pub unsafe fn new(areas: &'static [MemoryArea], offset: usize) -> Self {
         unsafe {
@@ -130,11 +131,11 @@ impl<A: Arch> SlabAllocator<A> {
             for level in 0..A::PAGE_LEVELS - 1 {
                 let level_shift = level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
                 let level_size = 1 << level_shift;
-                if size <= level_size {
-                    if let Some(base) = self.nodes[level].remove() {
-                        self.free(base.add(size), level_size - size);
-                        return Some(base);
-                    }
+                if size <= level_size
+                    && let Some(base) = self.nodes[level].remove()
+                {
+                    self.free(base.add(size), level_size - size);
+                    return Some(base);
                 }
             }
             None
@@ -183,18 +184,26 @@ unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
         let mut bump_allocator = BumpAllocator::<A>::new(areas, 0);
 
         {
-            // Map all physical areas at PHYS_OFFSET
-            let mut mapper = PageMapper::<A, _>::create(TableKind::Kernel, &mut bump_allocator)
-                .expect("failed to create Mapper");
+            // Map all physical areas at PHYS_OFFSET, using the largest pages that fit
+            let mut mapper =
+                PageMapper::<A, _>::create(TableKind::Kernel, None, &mut bump_allocator)
+                    .expect("failed to create Mapper");
             for area in areas.iter() {
-                for i in 0..area.size / A::PAGE_SIZE {
-                    let phys = area.base.add(i * A::PAGE_SIZE);
-                    let virt = A::phys_to_virt(phys);
-                    let flush = mapper
-                        .map_phys(virt, phys, PageFlags::<A>::new().write(true))
-                        .expect("failed to map page to frame");
-                    flush.ignore(); // Not the active table
-                }
+                let size = area.size & !A::PAGE_OFFSET_MASK;
+                let flush = mapper
+                    .map_range(
+                        A::phys_to_virt(area.base),
+                        area.base,
+                        size,
+                        PageFlags::<A>::new().write(true),
+                    )
+                    .unwrap_or_else(|partial| {
+                        panic!(
+                            "failed to map area after 0x{:X} bytes: {}",
+                            partial.done, partial.error
+                        )
+                    });
+                flush.ignore(); // Not the active table
             }
 
             // Use the new table
@@ -211,32 +220,36 @@ unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
             {
                 let phys_opt = allocator.allocate_one();
                 println!("page {}: {:X?}", i, phys_opt);
-                if i % 3 == 0 {
-                    if let Some(phys) = phys_opt {
-                        println!("free {}: {:X?}", i, phys_opt);
-                        allocator.free_one(phys);
-                    }
+                if i % 3 == 0
+                    && let Some(phys) = phys_opt
+                {
+                    println!("free {}: {:X?}", i, phys_opt);
+                    allocator.free_one(phys);
                 }
             }
 
             {
                 let phys_opt = allocator.allocate(FrameCount::new(16));
                 println!("page*16 {}: {:X?}", i, phys_opt);
-                if i % 2 == 0 {
-                    if let Some(phys) = phys_opt {
-                        println!("free*16 {}: {:X?}", i, phys_opt);
-                        allocator.free(phys, FrameCount::new(16));
-                    }
+                if i % 2 == 0
+                    && let Some(phys) = phys_opt
+                {
+                    println!("free*16 {}: {:X?}", i, phys_opt);
+                    allocator.free(phys, FrameCount::new(16));
                 }
             }
         }
 
-        let mut mapper = PageMapper::<A, _>::current(TableKind::Kernel, &mut allocator);
+        let mut mapper = PageMapper::<A, _>::current(TableKind::User, &mut allocator);
         let mut flush_all = PageFlushAll::new();
         for i in 0..16 {
             let virt = VirtualAddress::new(MEGABYTE + i * A::PAGE_SIZE);
             let flush = mapper
-                .map(virt, PageFlags::<A>::new().user(true).write(true))
+                .map(
+                    virt,
+                    PageSize::base(),
+                    PageFlags::<A>::new().user(true).write(true),
+                )
                 .expect("failed to map page");
             flush_all.consume(flush);
         }
@@ -245,7 +258,9 @@ unsafe fn new_tables<A: Arch>(areas: &'static [MemoryArea]) {
         let mut flush_all = PageFlushAll::new();
         for i in 0..16 {
             let virt = VirtualAddress::new(MEGABYTE + i * A::PAGE_SIZE);
-            let flush = mapper.unmap(virt, false).expect("failed to unmap page");
+            let flush = mapper
+                .unmap(virt, PageSize::base(), false)
+                .expect("failed to unmap page");
             flush_all.consume(flush);
         }
         flush_all.flush();
@@ -271,12 +286,21 @@ unsafe fn inner<A: Arch>() {
     unsafe {
         let areas = A::init();
 
-        // Debug table
-        //dump_tables(PageTable::<A>::top());
+        // Debug table, as text with --dump or Graphviz DOT with --dot
+        let dump = std::env::args().skip(1).find_map(|arg| match arg.as_str() {
+            "--dump" => Some(false),
+            "--dot" => Some(true),
+            _ => None,
+        });
+        if let Some(dot) = dump {
+            dump_tables(PageTable::<A>::top(TableKind::Kernel), dot);
+        }
 
         new_tables::<A>(areas);
 
-        //dump_tables(PageTable::<A>::top());
+        if let Some(dot) = dump {
+            dump_tables(PageTable::<A>::top(TableKind::Kernel), dot);
+        }
 
         for i in &[1, 2, 4, 8, 16, 32] {
             let phys = PhysicalAddress::new(i * MEGABYTE);
@@ -0,0 +1,287 @@
+use core::fmt::{self, Write};
+
+use crate::{canonical, Arch, PageEntry, PageFlags, PageSize, PageTable};
+
+/// Present entries of a table, with leaves mapping contiguous memory with the same flags merged
+/// into one run
+enum Node<A> {
+    Table {
+        index: usize,
+        entry: PageEntry<A>,
+    },
+    Leaves {
+        first: usize,
+        last: usize,
+        entry: PageEntry<A>,
+    },
+}
+
+unsafe fn nodes<A: Arch>(
+    table: &PageTable<A>,
+    mut f: impl FnMut(Node<A>) -> fmt::Result,
+) -> fmt::Result {
+    unsafe {
+        let level = table.level();
+        let entry_size = 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+        let mut run: Option<(usize, usize, PageEntry<A>)> = None;
+        for i in 0..A::PAGE_ENTRIES {
+            let Some(entry) = table.entry(i) else {
+                continue;
+            };
+            let leaf = entry.present() && A::entry_is_leaf(entry.data(), level);
+            if let Some((first, last, start)) = run {
+                let continues = leaf
+                    && last + 1 == i
+                    && entry.flags().data() == start.flags().data()
+                    && entry.address().ok()
+                        == start
+                            .address()
+                            .ok()
+                            .map(|phys| phys.add((i - first) * entry_size));
+                if continues {
+                    run = Some((first, i, start));
+                    continue;
+                }
+                f(Node::Leaves {
+                    first,
+                    last,
+                    entry: start,
+                })?;
+                run = None;
+            }
+            if leaf {
+                run = Some((i, i, entry));
+            } else if entry.present() {
+                f(Node::Table { index: i, entry })?;
+            }
+        }
+        if let Some((first, last, entry)) = run {
+            f(Node::Leaves { first, last, entry })?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the tree of `table` as text, with a line for each table entry and each run of leaves,
+/// indented by depth
+pub(crate) unsafe fn dump_text<A: Arch>(table: &PageTable<A>, out: &mut impl Write) -> fmt::Result {
+    unsafe {
+        writeln!(out, "L{} table {:#x}", table.level(), table.phys().data())?;
+        dump_text_inner(table, 1, out)
+    }
+}
+
+unsafe fn dump_text_inner<A: Arch>(
+    table: &PageTable<A>,
+    depth: usize,
+    out: &mut impl Write,
+) -> fmt::Result {
+    unsafe {
+        let level = table.level();
+        nodes(table, |node| match node {
+            Node::Table { index, entry } => {
+                let virt = canonical::<A>(table.entry_base(index).ok_or(fmt::Error)?);
+                writeln!(
+                    out,
+                    "{:indent$}L{}[{}] {:#x} table {:#x} ({:#x})",
+                    "",
+                    level,
+                    index,
+                    virt.data(),
+                    entry.address().map_err(|_| fmt::Error)?.data(),
+                    entry.flags().data(),
+                    indent = depth * 2
+                )?;
+                match table.next(index) {
+                    Some(next) => dump_text_inner(&next, depth + 1, out),
+                    None => Ok(()),
+                }
+            }
+            Node::Leaves { first, last, entry } => {
+                let virt = canonical::<A>(table.entry_base(first).ok_or(fmt::Error)?);
+                write!(
+                    out,
+                    "{:indent$}L{}[{}",
+                    "",
+                    level,
+                    first,
+                    indent = depth * 2
+                )?;
+                if last > first {
+                    write!(out, "..={}", last)?;
+                }
+                write!(
+                    out,
+                    "] {:#x} -> {:#x} {} x {} ",
+                    virt.data(),
+                    entry.address().map_err(|_| fmt::Error)?.data(),
+                    last - first + 1,
+                    SizeName(PageSize::<A>::from_level(level).map(|size| size.bytes()))
+                )?;
+                write_flags(out, leaf_page_flags(entry, level))?;
+                writeln!(out, " ({:#x})", entry.flags().data())
+            }
+        })
+    }
+}
+
+/// Writes the tree of `table` as a Graphviz DOT digraph, with a node for each table and each
+/// run of leaves
+pub(crate) unsafe fn dump_dot<A: Arch>(table: &PageTable<A>, out: &mut impl Write) -> fmt::Result {
+    unsafe {
+        writeln!(out, "digraph page_tables {{")?;
+        writeln!(out, "    node [shape=box, fontname=monospace];")?;
+        writeln!(
+            out,
+            "    t{:x} [label=\"L{} table\\n{:#x}\"];",
+            table.phys().data(),
+            table.level(),
+            table.phys().data()
+        )?;
+        dump_dot_inner(table, out)?;
+        writeln!(out, "}}")
+    }
+}
+
+unsafe fn dump_dot_inner<A: Arch>(table: &PageTable<A>, out: &mut impl Write) -> fmt::Result {
+    unsafe {
+        let level = table.level();
+        let parent = table.phys().data();
+        nodes(table, |node| match node {
+            Node::Table { index, entry } => {
+                let virt = canonical::<A>(table.entry_base(index).ok_or(fmt::Error)?);
+                let Some(next) = table.next(index) else {
+                    return Ok(());
+                };
+                writeln!(
+                    out,
+                    "    t{:x} [label=\"L{} table\\n{:#x}\"];",
+                    next.phys().data(),
+                    next.level(),
+                    next.phys().data()
+                )?;
+                writeln!(
+                    out,
+                    "    t{:x} -> t{:x} [label=\"[{}] {:#x}\\n({:#x})\"];",
+                    parent,
+                    next.phys().data(),
+                    index,
+                    virt.data(),
+                    entry.flags().data()
+                )?;
+                dump_dot_inner(&next, out)
+            }
+            Node::Leaves { first, last, entry } => {
+                let virt = canonical::<A>(table.entry_base(first).ok_or(fmt::Error)?);
+                write!(
+                    out,
+                    "    l{:x}_{} [shape=note, label=\"{:#x}\\n-> {:#x}\\n{} x {}\\n",
+                    parent,
+                    first,
+                    virt.data(),
+                    entry.address().map_err(|_| fmt::Error)?.data(),
+                    last - first + 1,
+                    SizeName(PageSize::<A>::from_level(level).map(|size| size.bytes()))
+                )?;
+                write_flags(out, leaf_page_flags(entry, level))?;
+                writeln!(out, "\"];")?;
+                write!(
+                    out,
+                    "    t{:x} -> l{:x}_{} [label=\"[{}",
+                    parent, parent, first, first
+                )?;
+                if last > first {
+                    write!(out, "..={}", last)?;
+                }
+                writeln!(out, "]\"];")
+            }
+        })
+    }
+}
+
+fn leaf_page_flags<A: Arch>(entry: PageEntry<A>, level: usize) -> PageFlags<A> {
+    unsafe { PageFlags::from_data(A::leaf_page_flags(entry.flags().data(), level)) }
+}
+
+/// Writes permissions like `rw- user global`
+fn write_flags<A: Arch>(out: &mut impl Write, flags: PageFlags<A>) -> fmt::Result {
+    write!(
+        out,
+        "r{}{} {}",
+        if flags.has_write() { 'w' } else { '-' },
+        if flags.has_execute() { 'x' } else { '-' },
+        if flags.has_user() { "user" } else { "kernel" }
+    )?;
+    if flags.is_global() {
+        write!(out, " global")?;
+    }
+    Ok(())
+}
+
+/// Size of pages like `2 MiB`, if leaves at their level are valid
+struct SizeName(Option<usize>);
+
+impl fmt::Display for SizeName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(bytes) = self.0 else {
+            return write!(f, "invalid pages");
+        };
+        for (unit, shift) in [("GiB", 30), ("MiB", 20), ("KiB", 10)] {
+            if bytes >= 1 << shift {
+                return write!(f, "{} {}", bytes >> shift, unit);
+            }
+        }
+        write!(f, "{} B", bytes)
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use crate::{
+        arch::all_archs, Arch, BumpAllocator, EmulateArch, MemoryArea, PageFlags, PageMapper,
+        PageSize, PhysicalAddress, TableKind, VirtualAddress,
+    };
+
+    all_archs!(dump);
+
+    unsafe fn dump<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::create(TableKind::User, None, &mut allocator)
+                    .expect("failed to create mapper");
+            let huge = PageSize::<EmulateArch<A>>::from_level(1).expect("no huge pages");
+            let virt = VirtualAddress::new(4 * huge.bytes());
+            mapper
+                .map_range(
+                    virt,
+                    PhysicalAddress::new(0),
+                    huge.bytes() + 3 * A::PAGE_SIZE,
+                    PageFlags::new().write(true),
+                )
+                .unwrap_or_else(|partial| panic!("failed to map range: {}", partial.error))
+                .ignore();
+
+            // A line per table, and per run of leaves
+            let mut text = String::new();
+            mapper.dump_text(&mut text).expect("failed to dump text");
+            let leaves: Vec<_> = text.lines().filter(|line| line.contains(" -> ")).collect();
+            assert_eq!(leaves.len(), 2, "{}", text);
+            assert!(leaves[0].starts_with(&format!("{:1$}L1[4] ", "", 2 * A::PAGE_LEVELS - 2)));
+            assert!(leaves[0].contains(" 1 x "));
+            assert!(leaves[1].contains("L0[0..=2] "));
+            assert!(leaves[1].contains(" 3 x 4 KiB rw"));
+            assert_eq!(text.lines().count(), 1 + A::PAGE_LEVELS - 1 + 2);
+
+            // The same tree as a graph, with an edge to every node but the root
+            let mut dot = String::new();
+            mapper.dump_dot(&mut dot).expect("failed to dump dot");
+            assert!(dot.starts_with("digraph page_tables {\n"));
+            assert!(dot.ends_with("}\n"));
+            let edges = dot.lines().filter(|line| line.contains(" -> ")).count();
+            let nodes = dot.lines().filter(|line| line.ends_with("\"];")).count() - edges;
+            assert_eq!(nodes, A::PAGE_LEVELS + 2);
+            assert_eq!(edges, nodes - 1);
+        }
+    }
+}
@@ -52,6 +52,18 @@ impl<A: Arch> PageEntry<A> {
         self.data |= flags.data();
     }
 
+    /// Value stored in the software bits, see [`PageFlags::software`]. Entries that are not
+    /// present keep it too, such as to find swapped out pages.
+    #[inline(always)]
+    pub fn software(&self) -> usize {
+        self.flags().get_software()
+    }
+
+    #[inline(always)]
+    pub fn set_software(&mut self, value: usize) {
+        self.set_flags(self.flags().software(value));
+    }
+
     #[inline(always)]
     pub fn present(&self) -> bool {
         self.data & A::ENTRY_FLAG_PRESENT != 0
@@ -0,0 +1,140 @@
+use core::fmt;
+
+/// Reason a page could not be mapped or remapped
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MapError {
+    /// The frame allocator ran out of frames for the page or its tables
+    OutOfFrames,
+    /// An address is not aligned to the page size
+    Misaligned,
+    /// The size of a range is zero
+    InvalidSize,
+    /// The virtual address is not canonical, or outside the table
+    InvalidAddress,
+    /// A page, or a table of smaller pages, already maps the address, or it is reserved
+    AlreadyMapped,
+    /// Nothing maps the address
+    NotMapped,
+    /// The address is mapped by pages smaller than requested
+    SizeMismatch,
+    /// The frame allocator cannot add another reference to a frame
+    NotShareable,
+    /// The page would be writable and executable, which the mapper's write xor execute policy
+    /// forbids
+    WriteExecute,
+    /// Execution was asked for by a privilege the architecture never lets execute the page, see
+    /// [`PageFlags::has_unsupported_execute`](crate::PageFlags::has_unsupported_execute)
+    UnsupportedExecute,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OutOfFrames => "out of frames",
+            Self::Misaligned => "address not aligned to page size",
+            Self::InvalidSize => "empty range",
+            Self::InvalidAddress => "invalid virtual address",
+            Self::AlreadyMapped => "address already mapped",
+            Self::NotMapped => "address not mapped",
+            Self::SizeMismatch => "address mapped by smaller pages",
+            Self::NotShareable => "frame cannot be shared",
+            Self::WriteExecute => "page both writable and executable",
+            Self::UnsupportedExecute => "execute permission not supported for the page",
+        })
+    }
+}
+
+/// Reason a page could not be unmapped
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum UnmapError {
+    /// The frame allocator ran out of frames to split a larger page
+    OutOfFrames,
+    /// The virtual address is not aligned to the page size
+    Misaligned,
+    /// The size of a range is zero
+    InvalidSize,
+    /// The virtual address is not canonical, or outside the table
+    InvalidAddress,
+    /// Nothing maps the address
+    NotMapped,
+    /// The address is mapped by pages smaller than requested
+    SizeMismatch,
+}
+
+impl fmt::Display for UnmapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::OutOfFrames => "out of frames",
+            Self::Misaligned => "address not aligned to page size",
+            Self::InvalidSize => "empty range",
+            Self::InvalidAddress => "invalid virtual address",
+            Self::NotMapped => "address not mapped",
+            Self::SizeMismatch => "address mapped by smaller pages",
+        })
+    }
+}
+
+// Unmapping shares the table walk and splitting of larger pages with mapping
+impl From<MapError> for UnmapError {
+    fn from(err: MapError) -> Self {
+        match err {
+            MapError::OutOfFrames | MapError::NotShareable => Self::OutOfFrames,
+            MapError::Misaligned => Self::Misaligned,
+            MapError::InvalidSize => Self::InvalidSize,
+            // Unmapping keeps the flags of the pages it splits, so never violates the policy
+            MapError::InvalidAddress | MapError::WriteExecute | MapError::UnsupportedExecute => {
+                Self::InvalidAddress
+            }
+            MapError::AlreadyMapped | MapError::SizeMismatch => Self::SizeMismatch,
+            MapError::NotMapped => Self::NotMapped,
+        }
+    }
+}
+
+/// Reason a region of virtual memory could not be found, added or changed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RegionError {
+    /// No free range is large enough
+    NoSpace,
+    /// An address or size is not aligned to the page size, or an alignment is not a power of two
+    Misaligned,
+    /// The size of a range is zero
+    InvalidSize,
+    /// The range is outside the managed range
+    OutOfBounds,
+    /// The range overlaps a region
+    Overlap,
+    /// Part of the range is not in a region, or not in the same one
+    NotFound,
+    /// The page tables could not be changed
+    Map(MapError),
+    /// The page tables could not be changed while unmapping
+    Unmap(UnmapError),
+}
+
+impl fmt::Display for RegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoSpace => f.write_str("no free range large enough"),
+            Self::Misaligned => f.write_str("address or size not aligned to page size"),
+            Self::InvalidSize => f.write_str("empty range"),
+            Self::OutOfBounds => f.write_str("range outside address space"),
+            Self::Overlap => f.write_str("range overlaps a region"),
+            Self::NotFound => f.write_str("range not in a region"),
+            Self::Map(err) => err.fmt(f),
+            Self::Unmap(err) => err.fmt(f),
+        }
+    }
+}
+
+impl From<MapError> for RegionError {
+    fn from(err: MapError) -> Self {
+        Self::Map(err)
+    }
+}
+
+impl From<UnmapError> for RegionError {
+    fn from(err: UnmapError) -> Self {
+        Self::Unmap(err)
+    }
+}
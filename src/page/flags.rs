@@ -2,13 +2,44 @@ use core::{fmt, marker::PhantomData};
 
 use crate::Arch;
 
+/// Caching and ordering of accesses to a page, mapped by each architecture to its own attributes
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MemoryType {
+    /// Normal cached memory, the default
+    WriteBack,
+    /// Cached for reads, with writes going to memory
+    WriteThrough,
+    /// Uncached, with writes buffered and combined, like for frame buffers
+    WriteCombining,
+    /// Uncached normal memory
+    Uncached,
+    /// Uncached and strongly ordered, without speculative accesses, for device registers
+    Device,
+}
+
 #[derive(Clone, Copy)]
 pub struct PageFlags<A> {
     data: usize,
+    // Execute permissions asked for, resolved into the single one of architectures without
+    // separate user and kernel permissions for the privilege owning the page. Not stored in
+    // entries, so flags read from one or made with `from_data` have none.
+    exec: ExecuteRequest,
     arch: PhantomData<A>,
 }
 
+/// Execute permissions asked for by `execute` for every privilege, and by `user_execute` and
+/// `kernel_execute`, which take precedence
+#[derive(Clone, Copy, Default)]
+struct ExecuteRequest {
+    all: Option<bool>,
+    user: Option<bool>,
+    kernel: Option<bool>,
+}
+
 impl<A: Arch> PageFlags<A> {
+    /// Number of bits of the value stored with `software`
+    pub const SOFTWARE_BITS: u32 = A::ENTRY_FLAG_SOFTWARE_MASK.count_ones();
+
     #[inline(always)]
     pub fn new() -> Self {
         unsafe {
@@ -32,10 +63,18 @@ impl<A: Arch> PageFlags<A> {
         }
     }
 
+    /// Flags made of the raw entry bits in `data`. Execute permissions asked for with
+    /// `user_execute` or `kernel_execute` are not part of the bits, so they are forgotten, and
+    /// `has_unsupported_execute` is false.
+    ///
+    /// # Safety
+    ///
+    /// `data` must only hold entry flags of the architecture, as they are written to entries.
     #[inline(always)]
     pub unsafe fn from_data(data: usize) -> Self {
         Self {
             data,
+            exec: ExecuteRequest::default(),
             arch: PhantomData,
         }
     }
@@ -56,10 +95,27 @@ impl<A: Arch> PageFlags<A> {
         self
     }
 
+    /// Shorthand for the write-combining memory type, or write-back when `value` is false
     #[must_use]
     #[inline(always)]
     pub fn write_combining(self, value: bool) -> Self {
-        self.custom_flag(A::ENTRY_FLAG_WRITE_COMBINING, value)
+        self.memory_type(if value {
+            MemoryType::WriteCombining
+        } else {
+            MemoryType::WriteBack
+        })
+    }
+
+    #[must_use]
+    #[inline(always)]
+    pub fn memory_type(self, memory_type: MemoryType) -> Self {
+        self.custom_flag(A::ENTRY_MEMORY_TYPE_MASK, false)
+            .custom_flag(A::memory_type_flags(memory_type), true)
+    }
+
+    #[inline(always)]
+    pub fn get_memory_type(&self) -> MemoryType {
+        A::memory_type(self.data)
     }
 
     #[inline(always)]
@@ -76,6 +132,7 @@ impl<A: Arch> PageFlags<A> {
     #[inline(always)]
     pub fn user(self, value: bool) -> Self {
         self.custom_flag(A::ENTRY_FLAG_PAGE_USER, value)
+            .resolve_execute()
     }
 
     #[inline(always)]
@@ -102,21 +159,139 @@ impl<A: Arch> PageFlags<A> {
         self.data & (A::ENTRY_FLAG_READONLY | A::ENTRY_FLAG_READWRITE) == A::ENTRY_FLAG_READWRITE
     }
 
+    /// Allows or prevents execution of the page. Mappers enforcing write xor execute reject
+    /// pages that are also writable, see [`PageMapper::with_write_xor_execute`].
+    ///
+    /// [`PageMapper::with_write_xor_execute`]: crate::PageMapper::with_write_xor_execute
     #[must_use]
     #[inline(always)]
     pub fn execute(self, value: bool) -> Self {
-        //TODO: write xor execute?
-        // Architecture may use no exec or exec, support either
-        self.custom_flag(A::ENTRY_FLAG_NO_EXEC, !value)
-            .custom_flag(A::ENTRY_FLAG_EXEC, value)
+        let mut flags = self.execute_bits(value);
+        if Self::single_execute() {
+            flags.exec.all = Some(value);
+        }
+        flags.resolve_execute()
     }
 
+    /// Whether user mode or the kernel may execute the page
     #[inline(always)]
     pub fn has_execute(&self) -> bool {
+        self.has_user_execute() || self.has_kernel_execute()
+    }
+
+    /// Allows or prevents execution by user mode, overriding `execute`. Architectures with a
+    /// single execute permission only let user mode execute user pages, so mappers reject kernel
+    /// pages asking for it, see [`has_unsupported_execute`](Self::has_unsupported_execute).
+    #[must_use]
+    #[inline(always)]
+    pub fn user_execute(self, value: bool) -> Self {
+        if A::ENTRY_FLAG_NO_USER_EXEC != 0 {
+            self.custom_flag(A::ENTRY_FLAG_NO_USER_EXEC, !value)
+        } else {
+            let mut flags = self;
+            flags.exec.user = Some(value);
+            flags.resolve_execute()
+        }
+    }
+
+    #[inline(always)]
+    pub fn has_user_execute(&self) -> bool {
+        if A::ENTRY_FLAG_NO_USER_EXEC != 0 {
+            !self.has_flag(A::ENTRY_FLAG_NO_USER_EXEC)
+        } else {
+            self.has_user() && self.execute_allowed()
+        }
+    }
+
+    /// Allows or prevents execution by the kernel, like `user_execute`. Architectures with a
+    /// single execute permission never let the kernel execute user pages, so mappers reject user
+    /// pages asking for it.
+    #[must_use]
+    #[inline(always)]
+    pub fn kernel_execute(self, value: bool) -> Self {
+        if A::ENTRY_FLAG_NO_KERNEL_EXEC != 0 {
+            self.custom_flag(A::ENTRY_FLAG_NO_KERNEL_EXEC, !value)
+        } else {
+            let mut flags = self;
+            flags.exec.kernel = Some(value);
+            flags.resolve_execute()
+        }
+    }
+
+    #[inline(always)]
+    pub fn has_kernel_execute(&self) -> bool {
+        if A::ENTRY_FLAG_NO_KERNEL_EXEC != 0 {
+            !self.has_flag(A::ENTRY_FLAG_NO_KERNEL_EXEC)
+        } else {
+            !self.has_user() && self.execute_allowed()
+        }
+    }
+
+    /// Whether the page is both writable and executable, which write xor execute forbids
+    #[inline(always)]
+    pub fn is_write_execute(&self) -> bool {
+        self.has_write() && self.has_execute()
+    }
+
+    /// Whether `user_execute` or `kernel_execute` asked for execution by the privilege that does
+    /// not own the page, which architectures with a single execute permission cannot allow. Only
+    /// known for flags built with these methods, not for those read from entries.
+    #[inline(always)]
+    pub fn has_unsupported_execute(&self) -> bool {
+        let other = if self.has_user() {
+            self.exec.kernel
+        } else {
+            self.exec.user
+        };
+        other == Some(true)
+    }
+
+    /// Whether the single execute permission of the architecture is set
+    #[inline(always)]
+    fn execute_allowed(&self) -> bool {
         // Architecture may use no exec or exec, support either
         self.data & (A::ENTRY_FLAG_NO_EXEC | A::ENTRY_FLAG_EXEC) == A::ENTRY_FLAG_EXEC
     }
 
+    #[inline(always)]
+    fn execute_bits(self, value: bool) -> Self {
+        // Architecture may use no exec or exec, support either
+        self.custom_flag(A::ENTRY_FLAG_NO_EXEC, !value)
+            .custom_flag(A::ENTRY_FLAG_EXEC, value)
+    }
+
+    /// Whether user and kernel execution share the single execute permission of the page owner
+    #[inline(always)]
+    fn single_execute() -> bool {
+        A::ENTRY_FLAG_NO_USER_EXEC | A::ENTRY_FLAG_NO_KERNEL_EXEC == 0
+    }
+
+    /// Sets the single execute permission to the one asked for the privilege owning the page, so
+    /// the order `user` and the execute permissions are set in does not matter
+    #[inline(always)]
+    fn resolve_execute(self) -> Self {
+        let owner = if self.has_user() {
+            self.exec.user
+        } else {
+            self.exec.kernel
+        };
+        match owner.or(self.exec.all) {
+            Some(value) => self.execute_bits(value),
+            None => self,
+        }
+    }
+
+    #[must_use]
+    #[inline(always)]
+    pub fn copy_on_write(self, value: bool) -> Self {
+        self.custom_flag(A::ENTRY_FLAG_COPY_ON_WRITE, value)
+    }
+
+    #[inline(always)]
+    pub fn is_copy_on_write(&self) -> bool {
+        self.has_flag(A::ENTRY_FLAG_COPY_ON_WRITE)
+    }
+
     #[must_use]
     #[inline(always)]
     pub fn global(self, value: bool) -> Self {
@@ -125,6 +300,82 @@ impl<A: Arch> PageFlags<A> {
             .custom_flag(A::ENTRY_FLAG_GLOBAL, value)
     }
 
+    #[must_use]
+    #[inline(always)]
+    pub fn accessed(self, value: bool) -> Self {
+        self.custom_flag(A::ENTRY_FLAG_ACCESSED, value)
+    }
+
+    /// Whether the page was accessed since the accessed flag was cleared
+    #[inline(always)]
+    pub fn is_accessed(&self) -> bool {
+        self.has_flag(A::ENTRY_FLAG_ACCESSED)
+    }
+
+    /// Marks the page as written to, or as clean so the next write is recorded. Without a dirty
+    /// flag, clean pages are read-only with the dirty bit modifier, and made writable by the MMU
+    /// or by a write fault handler calling this, while read-only pages stay clean.
+    #[must_use]
+    #[inline(always)]
+    pub fn dirty(self, value: bool) -> Self {
+        if A::ENTRY_FLAG_DIRTY != 0 {
+            self.custom_flag(A::ENTRY_FLAG_DIRTY, value)
+        } else if A::ENTRY_FLAG_DIRTY_BIT_MODIFIER == 0 {
+            self
+        } else if value && self.has_flag(A::ENTRY_FLAG_DIRTY_BIT_MODIFIER) {
+            self.write(true)
+        } else if !value && self.has_write() {
+            self.write(false)
+                .custom_flag(A::ENTRY_FLAG_DIRTY_BIT_MODIFIER, true)
+        } else {
+            self
+        }
+    }
+
+    /// Whether the page was written to since it was marked clean. Without a dirty flag, any
+    /// writable page counts as dirty.
+    #[inline(always)]
+    pub fn is_dirty(&self) -> bool {
+        if A::ENTRY_FLAG_DIRTY != 0 {
+            self.has_flag(A::ENTRY_FLAG_DIRTY)
+        } else {
+            self.has_write()
+        }
+    }
+
+    /// Stores the low `SOFTWARE_BITS` bits of `value` in `ENTRY_FLAG_SOFTWARE_MASK`, lowest bit
+    /// first, dropping the rest. The MMU ignores these bits, even in entries that are not present.
+    #[must_use]
+    #[inline(always)]
+    pub fn software(mut self, value: usize) -> Self {
+        let mut bit = 1;
+        let mut mask = A::ENTRY_FLAG_SOFTWARE_MASK;
+        while mask != 0 {
+            let flag = mask & mask.wrapping_neg();
+            self = self.custom_flag(flag, value & bit != 0);
+            mask &= !flag;
+            bit <<= 1;
+        }
+        self
+    }
+
+    /// Value stored with `software`
+    #[inline(always)]
+    pub fn get_software(&self) -> usize {
+        let mut value = 0;
+        let mut bit = 1;
+        let mut mask = A::ENTRY_FLAG_SOFTWARE_MASK;
+        while mask != 0 {
+            let flag = mask & mask.wrapping_neg();
+            if self.has_flag(flag) {
+                value |= bit;
+            }
+            mask &= !flag;
+            bit <<= 1;
+        }
+        value
+    }
+
     #[inline(always)]
     pub fn is_global(&self) -> bool {
         // Architecture may use global or non global, support either
@@ -132,6 +383,12 @@ impl<A: Arch> PageFlags<A> {
     }
 }
 
+impl<A: Arch> Default for PageFlags<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<A: Arch> fmt::Debug for PageFlags<A> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("PageFlags")
@@ -139,6 +396,10 @@ impl<A: Arch> fmt::Debug for PageFlags<A> {
             .field("write", &self.has_write())
             .field("executable", &self.has_execute())
             .field("user", &self.has_user())
+            .field("accessed", &self.is_accessed())
+            .field("dirty", &self.is_dirty())
+            .field("memory_type", &self.get_memory_type())
+            .field("software", &self.get_software())
             .field("bits", &format_args!("{:#0x}", self.data))
             .finish()
     }
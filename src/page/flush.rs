@@ -1,6 +1,6 @@
 use core::{marker::PhantomData, mem};
 
-use crate::{Arch, VirtualAddress};
+use crate::{Arch, PageSize, VirtualAddress};
 
 pub trait Flusher<A> {
     fn consume(&mut self, flush: PageFlush<A>);
@@ -9,26 +9,85 @@ pub trait Flusher<A> {
 #[must_use = "The page table must be flushed, or the changes unsafely ignored"]
 pub struct PageFlush<A> {
     virt: VirtualAddress,
+    // Bytes from virt to invalidate, one page of stride bytes at a time
+    size: usize,
+    stride: usize,
     phantom: PhantomData<A>,
 }
 
 impl<A: Arch> PageFlush<A> {
     pub fn new(virt: VirtualAddress) -> Self {
+        Self::new_range(virt, A::PAGE_SIZE, PageSize::base())
+    }
+
+    /// Flush of `size` bytes starting at `virt`, which may be cached as pages as small as
+    /// `stride`
+    pub fn new_range(virt: VirtualAddress, size: usize, stride: PageSize<A>) -> Self {
         Self {
             virt,
+            size,
+            stride: stride.bytes(),
             phantom: PhantomData,
         }
     }
 
-    pub fn flush(self) {
+    pub fn virt(&self) -> VirtualAddress {
+        self.virt
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Flush covering both `self` and `other`, at the smaller of their strides. An empty flush
+    /// leaves the other one unchanged.
+    pub fn combine(self, other: Self) -> Self {
+        if self.size == 0 {
+            unsafe { self.ignore() };
+            return other;
+        }
+        if other.size == 0 {
+            unsafe { other.ignore() };
+            return self;
+        }
+        let stride = self.stride.min(other.stride);
+        let start = self.virt.data().min(other.virt.data()) & !(stride - 1);
+        let end = (self.virt.data() + (self.size - 1)).max(other.virt.data() + (other.size - 1));
         unsafe {
-            A::invalidate(self.virt);
+            self.ignore();
+            other.ignore();
+        }
+        Self {
+            virt: VirtualAddress::new(start),
+            size: end - start + 1,
+            stride,
+            phantom: PhantomData,
         }
     }
 
-    pub unsafe fn ignore(self) {
-        mem::forget(self);
+    /// Invalidates the pages one at a time, or everything above `Arch::FLUSH_ALL_THRESHOLD`
+    /// pages
+    pub fn flush(self) {
+        unsafe {
+            if self.size.div_ceil(self.stride) > A::FLUSH_ALL_THRESHOLD {
+                A::invalidate_all();
+                return;
+            }
+            let mut offset = 0;
+            while offset < self.size {
+                A::invalidate(self.virt.add(offset));
+                offset += self.stride;
+            }
+        }
     }
+
+    /// Drops the flush without invalidating anything
+    ///
+    /// # Safety
+    ///
+    /// The pages must not be accessed through stale translations, because they were never cached
+    /// or are invalidated some other way.
+    pub unsafe fn ignore(self) {}
 }
 
 // TODO: Might remove Drop and add #[must_use] again, but ergonomically I prefer being able to pass
@@ -46,10 +105,20 @@ impl<A: Arch> PageFlushAll<A> {
 
     pub fn flush(self) {}
 
+    /// Forgets the flusher without invalidating anything
+    ///
+    /// # Safety
+    ///
+    /// Like [`PageFlush::ignore`], for every flush consumed.
     pub unsafe fn ignore(self) {
         mem::forget(self);
     }
 }
+impl<A: Arch> Default for PageFlushAll<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl<A: Arch> Drop for PageFlushAll<A> {
     fn drop(&mut self) {
         unsafe {
@@ -64,6 +133,150 @@ impl<A: Arch> Flusher<A> for PageFlushAll<A> {
         }
     }
 }
+/// Maximum number of separate ranges a `PageFlushBatch` keeps before flushing everything
+const BATCH_RANGES: usize = 16;
+
+/// Range of addresses to invalidate one page of `stride` bytes at a time
+#[derive(Clone, Copy)]
+struct FlushRange {
+    start: usize,
+    end: usize,
+    stride: usize,
+}
+
+impl FlushRange {
+    fn pages(&self) -> usize {
+        (self.end - self.start).div_ceil(self.stride)
+    }
+
+    /// Whether invalidating this range also invalidates all of `other`
+    fn covers(&self, other: &Self) -> bool {
+        self.stride <= other.stride && self.start <= other.start && other.end <= self.end
+    }
+}
+
+/// Flusher collecting the pages of many flushes, merging overlapping and adjacent ranges, then
+/// invalidating them one at a time when flushed or dropped. Above `Arch::FLUSH_ALL_THRESHOLD`
+/// pages, or too many separate ranges, everything is invalidated instead.
+pub struct PageFlushBatch<A: Arch> {
+    ranges: [FlushRange; BATCH_RANGES],
+    len: usize,
+    // Whether too many ranges were added to keep them all
+    all: bool,
+    phantom: PhantomData<fn() -> A>,
+}
+
+impl<A: Arch> PageFlushBatch<A> {
+    pub fn new() -> Self {
+        Self {
+            ranges: [FlushRange {
+                start: 0,
+                end: 0,
+                stride: 1,
+            }; BATCH_RANGES],
+            len: 0,
+            all: false,
+            phantom: PhantomData,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.all && self.len == 0
+    }
+
+    /// Number of pages that would be invalidated one at a time, or `None` if everything would be
+    /// invalidated instead
+    pub fn pages(&self) -> Option<usize> {
+        let pages = self.ranges[..self.len].iter().map(FlushRange::pages).sum();
+        (!self.all && pages <= A::FLUSH_ALL_THRESHOLD).then_some(pages)
+    }
+
+    /// Adds `size` bytes starting at `virt`, which may be cached as pages as small as `stride`
+    pub fn add_range(&mut self, virt: VirtualAddress, size: usize, stride: PageSize<A>) {
+        if self.all || size == 0 {
+            return;
+        }
+        let stride = stride.bytes();
+        let mut range = FlushRange {
+            start: virt.data() & !(stride - 1),
+            end: virt.data().saturating_add(size),
+            stride,
+        };
+        let mut i = 0;
+        while i < self.len {
+            let other = self.ranges[i];
+            if other.covers(&range) {
+                return;
+            }
+            let touches = other.start <= range.end && range.start <= other.end;
+            if range.covers(&other) || (touches && other.stride == range.stride) {
+                range.start = range.start.min(other.start);
+                range.end = range.end.max(other.end);
+                self.len -= 1;
+                self.ranges[i] = self.ranges[self.len];
+                // A larger range may now touch ranges already checked
+                i = 0;
+            } else {
+                i += 1;
+            }
+        }
+        if self.len == BATCH_RANGES {
+            self.all = true;
+        } else {
+            self.ranges[self.len] = range;
+            self.len += 1;
+        }
+    }
+
+    pub fn flush(self) {}
+
+    /// Forgets the flusher without invalidating anything
+    ///
+    /// # Safety
+    ///
+    /// Like [`PageFlush::ignore`], for every flush consumed.
+    pub unsafe fn ignore(self) {
+        mem::forget(self);
+    }
+}
+
+impl<A: Arch> Default for PageFlushBatch<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Arch> Drop for PageFlushBatch<A> {
+    fn drop(&mut self) {
+        unsafe {
+            if self.pages().is_none() {
+                A::invalidate_all();
+                return;
+            }
+            for range in &self.ranges[..self.len] {
+                let mut virt = range.start;
+                while virt < range.end {
+                    A::invalidate(VirtualAddress::new(virt));
+                    virt = match virt.checked_add(range.stride) {
+                        Some(next) => next,
+                        None => break,
+                    };
+                }
+            }
+        }
+    }
+}
+
+impl<A: Arch> Flusher<A> for PageFlushBatch<A> {
+    fn consume(&mut self, flush: PageFlush<A>) {
+        let stride = PageSize::from_bytes(flush.stride).unwrap_or(PageSize::base());
+        self.add_range(flush.virt, flush.size, stride);
+        unsafe {
+            flush.ignore();
+        }
+    }
+}
+
 impl<A: Arch, T: Flusher<A> + ?Sized> Flusher<A> for &mut T {
     fn consume(&mut self, flush: PageFlush<A>) {
         <T as Flusher<A>>::consume(self, flush)
@@ -72,3 +285,220 @@ impl<A: Arch, T: Flusher<A> + ?Sized> Flusher<A> for &mut T {
 impl<A: Arch> Flusher<A> for () {
     fn consume(&mut self, _: PageFlush<A>) {}
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PageFlush, PageFlushBatch, BATCH_RANGES};
+    #[cfg(all(feature = "std", target_pointer_width = "64"))]
+    use crate::{
+        arch::all_archs, BuddyAllocator, BumpAllocator, EmulateArch, Flusher, MemoryArea,
+        PageFlags, PageMapper, PhysicalAddress, TableKind, TlbCheck, MEGABYTE,
+    };
+    use crate::{Arch, PageSize, VirtualAddress, X86Arch};
+
+    fn flush(virt: usize, size: usize, level: usize) -> PageFlush<X86Arch> {
+        PageFlush::new_range(
+            VirtualAddress::new(virt),
+            size,
+            PageSize::from_level(level).unwrap(),
+        )
+    }
+
+    fn bounds(flush: PageFlush<X86Arch>) -> (usize, usize) {
+        let bounds = (flush.virt().data(), flush.size());
+        unsafe { flush.ignore() };
+        bounds
+    }
+
+    #[test]
+    fn combine() {
+        let page = X86Arch::PAGE_SIZE;
+        assert_eq!(
+            bounds(flush(4 * page, page, 0).combine(flush(page, 2 * page, 0))),
+            (page, 4 * page)
+        );
+        // The start is aligned down to the smaller stride
+        let huge = PageSize::<X86Arch>::from_level(1).unwrap().bytes();
+        assert_eq!(
+            bounds(flush(huge + page, page, 0).combine(flush(huge, huge, 1))),
+            (huge, huge)
+        );
+        // Empty flushes are the identity, wherever they are
+        assert_eq!(
+            bounds(flush(0, 0, 0).combine(flush(page, page, 0))),
+            (page, page)
+        );
+        assert_eq!(
+            bounds(flush(page, page, 0).combine(flush(huge, 0, 1))),
+            (page, page)
+        );
+        assert_eq!(bounds(flush(page, 0, 0).combine(flush(0, 0, 0))), (0, 0));
+    }
+
+    fn batch(ranges: &[(usize, usize, usize)]) -> PageFlushBatch<X86Arch> {
+        let mut batch = PageFlushBatch::new();
+        for &(virt, size, level) in ranges {
+            batch.add_range(
+                VirtualAddress::new(virt),
+                size,
+                PageSize::from_level(level).unwrap(),
+            );
+        }
+        batch
+    }
+
+    fn pages(batch: PageFlushBatch<X86Arch>) -> Option<usize> {
+        let pages = batch.pages();
+        unsafe { batch.ignore() };
+        pages
+    }
+
+    #[test]
+    fn batch_merge() {
+        let page = X86Arch::PAGE_SIZE;
+        let huge = PageSize::<X86Arch>::from_level(1).unwrap().bytes();
+        let empty = batch(&[(page, 0, 0)]);
+        assert!(empty.is_empty());
+        assert_eq!(pages(empty), Some(0));
+
+        // Overlapping and adjacent ranges are merged, so no page is counted twice
+        assert_eq!(
+            pages(batch(&[(page, 2 * page, 0), (2 * page, 2 * page, 0)])),
+            Some(3)
+        );
+        assert_eq!(
+            pages(batch(&[(page, page, 0), (2 * page, page, 0)])),
+            Some(2)
+        );
+        assert_eq!(
+            pages(batch(&[(4 * page, page, 0), (page, page, 0)])),
+            Some(2)
+        );
+        // Merging a range may let it swallow ranges added before
+        assert_eq!(
+            pages(batch(&[
+                (page, page, 0),
+                (5 * page, page, 0),
+                (2 * page, 3 * page, 0),
+            ])),
+            Some(5)
+        );
+        // The start is aligned down to the stride
+        assert_eq!(pages(batch(&[(page + 1, page, 0)])), Some(2));
+
+        // Ranges with a smaller stride cover those with a larger one, in either order
+        assert_eq!(
+            pages(batch(&[(huge, page, 1), (huge, 2 * page, 0)])),
+            Some(2)
+        );
+        assert_eq!(
+            pages(batch(&[(huge, 2 * page, 0), (huge, page, 1)])),
+            Some(2)
+        );
+        // but not the other way around, as base pages inside a huge page range may be cached too
+        assert_eq!(
+            pages(batch(&[(huge, huge, 1), (huge + page, page, 0)])),
+            Some(2)
+        );
+        // and adjacent ranges with different strides are kept apart
+        assert_eq!(
+            pages(batch(&[(huge, huge, 1), (2 * huge, page, 0)])),
+            Some(2)
+        );
+    }
+
+    #[test]
+    fn batch_overflow() {
+        let page = X86Arch::PAGE_SIZE;
+        let ranges: [_; BATCH_RANGES + 1] = core::array::from_fn(|i| (2 * i * page, page, 0));
+
+        // One range too many invalidates everything, and later ranges are ignored
+        assert_eq!(pages(batch(&ranges[..BATCH_RANGES])), Some(BATCH_RANGES));
+        let mut all = batch(&ranges);
+        assert!(!all.is_empty());
+        all.add_range(VirtualAddress::new(page), page, PageSize::base());
+        assert_eq!(pages(all), None);
+
+        // So do too many pages
+        let threshold = X86Arch::FLUSH_ALL_THRESHOLD;
+        assert_eq!(pages(batch(&[(0, threshold * page, 0)])), Some(threshold));
+        assert_eq!(pages(batch(&[(0, (threshold + 1) * page, 0)])), None);
+    }
+
+    #[cfg(all(feature = "std", target_pointer_width = "64"))]
+    all_archs!(flush_batch);
+
+    #[cfg(all(feature = "std", target_pointer_width = "64"))]
+    unsafe fn flush_batch<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let page = A::PAGE_SIZE;
+            let virt = VirtualAddress::new(28 * MEGABYTE);
+            let pages = A::FLUSH_ALL_THRESHOLD + 1;
+            let flags = PageFlags::new().write(true);
+            mapper
+                .map_range(virt, PhysicalAddress::new(0), pages * page, flags)
+                .unwrap_or_else(|partial| panic!("failed to map range: {}", partial.error))
+                .flush();
+            let touch = |count: usize| {
+                for i in 0..count {
+                    EmulateArch::<A>::read::<u8>(virt.add(i * page));
+                }
+            };
+            let invalidated = |before: (usize, usize)| {
+                let (pages, all) = EmulateArch::<A>::invalidations();
+                (pages - before.0, all - before.1)
+            };
+
+            // Overlapping and adjacent flushes are merged, with each page invalidated once
+            touch(4);
+            let before = EmulateArch::<A>::invalidations();
+            let mut batch = PageFlushBatch::new();
+            for i in [1, 0, 1, 3, 2] {
+                let (_, _, flush) = mapper
+                    .remap_with(virt.add(i * page), PageSize::base(), |flags| {
+                        flags.write(false)
+                    })
+                    .expect("failed to remap page");
+                batch.consume(flush);
+            }
+            assert_eq!(batch.pages(), Some(4));
+            batch.flush();
+            assert_eq!(invalidated(before), (4, 0));
+            touch(4);
+
+            // Above the threshold everything is invalidated at once
+            touch(pages);
+            let before = EmulateArch::<A>::invalidations();
+            let mut batch = PageFlushBatch::new();
+            batch.consume(
+                mapper
+                    .protect_range(virt, pages * page, flags)
+                    .unwrap_or_else(|partial| panic!("failed to protect range: {}", partial.error)),
+            );
+            assert_eq!(batch.pages(), None);
+            drop(batch);
+            assert_eq!(invalidated(before), (0, 1));
+            EmulateArch::<A>::write::<u8>(virt.add(page), 1);
+
+            // So it is with too many separate ranges, even when they are few pages
+            let before = EmulateArch::<A>::invalidations();
+            let mut batch = PageFlushBatch::<EmulateArch<A>>::new();
+            for i in (0..pages).step_by(2) {
+                batch.add_range(virt.add(i * page), page, PageSize::base());
+            }
+            assert_eq!(batch.pages(), None);
+            batch.flush();
+            assert_eq!(invalidated(before), (0, 1));
+            let batch = PageFlushBatch::<EmulateArch<A>>::new();
+            assert!(batch.is_empty());
+            let before = EmulateArch::<A>::invalidations();
+            batch.flush();
+            assert_eq!(invalidated(before), (0, 0));
+        }
+    }
+}
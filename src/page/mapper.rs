@@ -1,33 +1,102 @@
-use core::marker::PhantomData;
+use core::{iter, marker::PhantomData};
 
 use crate::{
-    Arch, FrameAllocator, PageEntry, PageFlags, PageFlush, PageTable, PhysicalAddress, TableKind,
-    VirtualAddress,
+    canonical, Arch, Flusher, FrameAllocator, MapError, Mapping, Mappings, PageEntry, PageFlags,
+    PageFlush, PageSize, PageTable, PhysicalAddress, RegionError, TableKind, TableVisitor,
+    UnmapError, VirtualAddress,
 };
 
+/// Changes the mappings of the page tables starting at a root table, taking new tables and frames
+/// from an allocator
+///
+/// # Safety
+///
+/// The unsafe methods read and write the tables in place. The root table must be a valid table of
+/// the architecture, nothing else may change the tables at the same time, and memory must not be
+/// unmapped or remapped while it is still in use.
 pub struct PageMapper<A, F> {
     table_kind: TableKind,
     table_addr: PhysicalAddress,
+    // Root table whose kernel half this table shares
+    kernel_template: Option<PhysicalAddress>,
+    // Whether writable and executable pages are rejected
+    write_xor_execute: bool,
     allocator: F,
     _phantom: PhantomData<fn() -> A>,
 }
 
+#[allow(clippy::missing_safety_doc)]
 impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
     pub unsafe fn new(table_kind: TableKind, table_addr: PhysicalAddress, allocator: F) -> Self {
         Self {
             table_kind,
             table_addr,
+            kernel_template: None,
+            write_xor_execute: false,
             allocator,
             _phantom: PhantomData,
         }
     }
 
-    pub unsafe fn create(table_kind: TableKind, mut allocator: F) -> Option<Self> {
+    /// Creates an empty root table. With a `kernel_template` root table, the new table starts
+    /// with its kernel half and keeps sharing it, see [`Self::with_kernel_template`].
+    pub unsafe fn create(
+        table_kind: TableKind,
+        kernel_template: Option<PhysicalAddress>,
+        mut allocator: F,
+    ) -> Result<Self, MapError> {
         unsafe {
-            let table_addr = allocator.allocate_one()?;
+            let table_addr = allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
             // Ensure a clean root table: zero out to avoid random present bits
             A::write_bytes(A::phys_to_virt(table_addr), 0, A::PAGE_SIZE);
-            Some(Self::new(table_kind, table_addr, allocator))
+            let mapper = Self::new(table_kind, table_addr, allocator);
+            Ok(match kernel_template {
+                Some(template) => mapper.with_kernel_template(template),
+                None => mapper,
+            })
+        }
+    }
+
+    /// Shares the kernel half of the `template` root table, copying its entries now and whenever
+    /// `sync_kernel` is called. Tables added to the kernel half through this mapper are added to
+    /// the template too. Architectures with split tables have no kernel half in user tables, so
+    /// nothing is shared.
+    pub unsafe fn with_kernel_template(mut self, template: PhysicalAddress) -> Self {
+        unsafe {
+            self.kernel_template = Some(template);
+            self.sync_kernel();
+            self
+        }
+    }
+
+    pub fn kernel_template(&self) -> Option<PhysicalAddress> {
+        self.kernel_template
+    }
+
+    /// Copies the root entries of the kernel half missing from either this table or its kernel
+    /// template from the other one. Tables added to the template by another mapper are only seen
+    /// here after this is called, which `make_current` does, so a kernel accessing them with this
+    /// table current must call it when it faults on them.
+    pub unsafe fn sync_kernel(&self) {
+        unsafe {
+            let Some(template) = self.kernel_template else {
+                return;
+            };
+            if A::SPLIT_TABLES || template == self.table_addr {
+                return;
+            }
+            let mut table = self.table();
+            let mut template = PageTable::<A>::new(table.base(), template, table.level());
+            for i in A::PAGE_ENTRIES / 2..A::PAGE_ENTRIES {
+                let (Some(own), Some(shared)) = (table.entry(i), template.entry(i)) else {
+                    continue;
+                };
+                if own.present() && !shared.present() {
+                    template.set_entry(i, own);
+                } else if shared.present() && !own.present() {
+                    table.set_entry(i, shared);
+                }
+            }
         }
     }
 
@@ -44,6 +113,7 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
 
     pub unsafe fn make_current(&self) {
         unsafe {
+            self.sync_kernel();
             A::set_table(self.table_kind, self.table_addr);
         }
     }
@@ -51,7 +121,13 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
     pub fn table(&self) -> PageTable<A> {
         // SAFETY: The only way to initialize a PageMapper is via new(), and we assume it upholds
         // all necessary invariants for this to be safe.
-        unsafe { PageTable::new(VirtualAddress::new(0), self.table_addr, A::PAGE_LEVELS - 1) }
+        unsafe {
+            PageTable::new(
+                PageTable::<A>::root_base(self.table_kind),
+                self.table_addr,
+                A::PAGE_LEVELS - 1,
+            )
+        }
     }
 
     pub fn allocator(&self) -> &F {
@@ -62,32 +138,105 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
         &mut self.allocator
     }
 
+    pub fn table_kind(&self) -> TableKind {
+        self.table_kind
+    }
+
+    /// Enforces write xor execute when `enabled`, rejecting pages mapped or remapped as both
+    /// writable and executable with `MapError::WriteExecute`. Pages mapped before are kept, and can
+    /// be found with `write_execute_mappings`.
+    pub fn with_write_xor_execute(mut self, enabled: bool) -> Self {
+        self.write_xor_execute = enabled;
+        self
+    }
+
+    pub fn write_xor_execute(&self) -> bool {
+        self.write_xor_execute
+    }
+
+    /// Runs `f` with write xor execute suspended, to map regions that must be writable and
+    /// executable at once, like JIT code buffers
+    pub fn allow_write_execute<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        let enabled = self.write_xor_execute;
+        self.write_xor_execute = false;
+        let res = f(self);
+        self.write_xor_execute = enabled;
+        res
+    }
+
+    /// Checks that `flags` are allowed by the write xor execute policy
+    fn check_flags(&self, flags: PageFlags<A>) -> Result<(), MapError> {
+        if self.write_xor_execute && flags.is_write_execute() {
+            Err(MapError::WriteExecute)
+        } else if flags.has_unsupported_execute() {
+            Err(MapError::UnsupportedExecute)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Checks that the `size` bytes from `virt` are in the half of the address space this mapper
+    /// manages
+    fn check_half(&self, virt: VirtualAddress, size: usize) -> Result<(), MapError> {
+        let last = virt
+            .data()
+            .checked_add(size.saturating_sub(1))
+            .ok_or(MapError::InvalidAddress)?;
+        if A::table_kind(virt) == self.table_kind
+            && A::table_kind(VirtualAddress::new(last)) == self.table_kind
+        {
+            Ok(())
+        } else {
+            Err(MapError::InvalidAddress)
+        }
+    }
+
     pub unsafe fn remap_with_full(
         &mut self,
         virt: VirtualAddress,
+        size: PageSize<A>,
         f: impl FnOnce(PhysicalAddress, PageFlags<A>) -> (PhysicalAddress, PageFlags<A>),
-    ) -> Option<(PageFlags<A>, PhysicalAddress, PageFlush<A>)> {
+    ) -> Result<(PageFlags<A>, PhysicalAddress, PageFlush<A>), MapError> {
         unsafe {
-            self.visit(virt, |p1, i| {
-                let old_entry = p1.entry(i)?;
-                let old_phys = old_entry.address().ok()?;
-                let old_flags = old_entry.flags();
+            if virt.data() & size.offset_mask() != 0 {
+                return Err(MapError::Misaligned);
+            }
+            let flush = self.split(virt, size)?;
+            let res = self.visit(virt, |table, i| {
+                if table.level() != size.level() {
+                    return Err(MapError::SizeMismatch);
+                }
+                let old_entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+                let old_phys = old_entry.address().map_err(|_| MapError::NotMapped)?;
+                let old_flags = leaf_page_flags(old_entry, size);
                 let (new_phys, new_flags) = f(old_phys, old_flags);
+                self.check_flags(new_flags)?;
                 // TODO: Higher-level PageEntry::new interface?
-                let new_entry = PageEntry::new(new_phys.data(), new_flags.data());
-                p1.set_entry(i, new_entry);
-                Some((old_flags, old_phys, PageFlush::new(virt)))
-            })
-            .flatten()
+                let new_entry = PageEntry::new(
+                    new_phys.data(),
+                    A::leaf_flags(new_flags.data(), size.level()),
+                );
+                table.set_entry(i, new_entry);
+                Ok((old_flags, old_phys))
+            });
+            match res.and_then(|res| res) {
+                Ok((old_flags, old_phys)) => Ok((old_flags, old_phys, flush)),
+                Err(err) => {
+                    // The split leaf may still be cached whole
+                    flush.flush();
+                    Err(err)
+                }
+            }
         }
     }
     pub unsafe fn remap_with(
         &mut self,
         virt: VirtualAddress,
+        size: PageSize<A>,
         map_flags: impl FnOnce(PageFlags<A>) -> PageFlags<A>,
-    ) -> Option<(PageFlags<A>, PhysicalAddress, PageFlush<A>)> {
+    ) -> Result<(PageFlags<A>, PhysicalAddress, PageFlush<A>), MapError> {
         unsafe {
-            self.remap_with_full(virt, |same_phys, old_flags| {
+            self.remap_with_full(virt, size, |same_phys, old_flags| {
                 (same_phys, map_flags(old_flags))
             })
         }
@@ -95,19 +244,35 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
     pub unsafe fn remap(
         &mut self,
         virt: VirtualAddress,
+        size: PageSize<A>,
         flags: PageFlags<A>,
-    ) -> Option<PageFlush<A>> {
-        unsafe { self.remap_with(virt, |_| flags).map(|(_, _, flush)| flush) }
+    ) -> Result<PageFlush<A>, MapError> {
+        unsafe {
+            self.remap_with(virt, size, |_| flags)
+                .map(|(_, _, flush)| flush)
+        }
     }
 
     pub unsafe fn map(
         &mut self,
         virt: VirtualAddress,
+        size: PageSize<A>,
         flags: PageFlags<A>,
-    ) -> Option<PageFlush<A>> {
+    ) -> Result<PageFlush<A>, MapError> {
         unsafe {
-            let phys = self.allocator.allocate_one()?;
-            self.map_phys(virt, phys, flags)
+            self.check_half(virt, size.bytes())?;
+            self.check_flags(flags)?;
+            let phys = self
+                .allocator
+                .allocate(size.frames())
+                .ok_or(MapError::OutOfFrames)?;
+            match self.map_phys(virt, phys, size, flags) {
+                Ok(flush) => Ok(flush),
+                Err(err) => {
+                    self.allocator.free(phys, size.frames());
+                    Err(err)
+                }
+            }
         }
     }
 
@@ -115,147 +280,2575 @@ impl<A: Arch, F: FrameAllocator> PageMapper<A, F> {
         &mut self,
         virt: VirtualAddress,
         phys: PhysicalAddress,
+        size: PageSize<A>,
         flags: PageFlags<A>,
-    ) -> Option<PageFlush<A>> {
+    ) -> Result<PageFlush<A>, MapError> {
         unsafe {
-            //TODO: verify virt and phys are aligned
+            if (virt.data() | phys.data()) & size.offset_mask() != 0 {
+                return Err(MapError::Misaligned);
+            }
+            self.check_half(virt, size.bytes())?;
+            self.check_flags(flags)?;
             //TODO: verify flags have correct bits
-            let entry = PageEntry::new(phys.data(), flags.data());
+            let entry = PageEntry::new(phys.data(), A::leaf_flags(flags.data(), size.level()));
+            self.set_leaf(virt, size, entry, false)?;
+            Ok(PageFlush::new(virt))
+        }
+    }
+
+    /// Sets the entry for `virt` at the level of `size` to `entry`, allocating any missing table
+    /// on the way and freeing them again on failure. Present entries are never replaced, and
+    /// entries holding software state, such as reservations, only if `replace` is set.
+    unsafe fn set_leaf(
+        &mut self,
+        virt: VirtualAddress,
+        size: PageSize<A>,
+        entry: PageEntry<A>,
+        replace: bool,
+    ) -> Result<(), MapError> {
+        unsafe {
+            // Replacing a page would leak its frames, and replacing a table would leak it and the
+            // mappings below
+            let occupied = |old: PageEntry<A>| old.present() || !replace && old.data() != 0;
+            // Table and index of the first entry pointing to an added table
+            let mut added: Option<(PageTable<A>, usize)> = None;
             let mut table = self.table();
-            loop {
-                let i = table.index_of(virt)?;
-                if table.level() == 0 {
-                    //TODO: check for overwriting entry
+            let res = loop {
+                let i = match table.index_of(virt) {
+                    Ok(i) => i,
+                    Err(err) => break Err(err),
+                };
+                let Some(old) = table.entry(i) else {
+                    break Err(MapError::InvalidAddress);
+                };
+                if table.level() == size.level() {
+                    if occupied(old) {
+                        break Err(MapError::AlreadyMapped);
+                    }
                     table.set_entry(i, entry);
-                    return Some(PageFlush::new(virt));
-                } else {
-                    let next_opt = table.next(i);
-                    let next = match next_opt {
-                        Some(some) => some,
-                        None => {
-                            let next_phys = self.allocator.allocate_one()?;
-                            // Zero the newly allocated subtable to avoid garbage entries
-                            A::write_bytes(A::phys_to_virt(next_phys), 0, A::PAGE_SIZE);
-                            //TODO: correct flags?
-                            let flags = A::ENTRY_FLAG_DEFAULT_TABLE
-                                | if virt.kind() == TableKind::User {
-                                    A::ENTRY_FLAG_TABLE_USER
-                                } else {
-                                    0
-                                };
-                            table.set_entry(i, PageEntry::new(next_phys.data(), flags));
-                            table.next(i)?
-                        }
-                    };
+                    break Ok(());
+                }
+                if let Some(next) = table.next(i) {
                     table = next;
+                    continue;
+                }
+                // A larger page already maps this address
+                if occupied(old) {
+                    break Err(MapError::AlreadyMapped);
                 }
+                let Some(next_phys) = self.allocator.allocate_one() else {
+                    break Err(MapError::OutOfFrames);
+                };
+                // Zero the newly allocated subtable to avoid garbage entries
+                A::write_bytes(A::phys_to_virt(next_phys), 0, A::PAGE_SIZE);
+                table.set_entry(
+                    i,
+                    PageEntry::new(next_phys.data(), table_flags::<A>(self.table_kind)),
+                );
+                if is_shared_root(&table, i) {
+                    // Shared kernel tables stay, as other address spaces may point to them
+                    self.sync_kernel();
+                } else if added.is_none() {
+                    added = Some((PageTable::new(table.base(), table.phys(), table.level()), i));
+                }
+                let Some(next) = table.next(i) else {
+                    break Err(MapError::InvalidAddress);
+                };
+                table = next;
+            };
+            if let (Err(_), Some((mut table, i))) = (res, added) {
+                if let Some(mut next) = table.next(i) {
+                    free_tree(&mut next, &mut self.allocator);
+                }
+                table.set_entry(i, PageEntry::new(0, 0));
             }
+            res
         }
     }
+
     pub unsafe fn map_linearly(
         &mut self,
         phys: PhysicalAddress,
+        size: PageSize<A>,
         flags: PageFlags<A>,
-    ) -> Option<(VirtualAddress, PageFlush<A>)> {
+    ) -> Result<(VirtualAddress, PageFlush<A>), MapError> {
         unsafe {
             let virt = A::phys_to_virt(phys);
-            self.map_phys(virt, phys, flags).map(|flush| (virt, flush))
+            self.map_phys(virt, phys, size, flags)
+                .map(|flush| (virt, flush))
         }
     }
+    /// Calls `f` with the table and index of the leaf entry mapping `virt`, which is at level 0
+    /// unless a larger page maps it
     fn visit<T>(
         &self,
         virt: VirtualAddress,
         f: impl FnOnce(&mut PageTable<A>, usize) -> T,
-    ) -> Option<T> {
+    ) -> Result<T, MapError> {
+        self.check_half(virt, 1)?;
         let mut table = self.table();
         unsafe {
             loop {
                 let i = table.index_of(virt)?;
-                if table.level() == 0 {
-                    return Some(f(&mut table, i));
+                let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+                if table.level() == 0
+                    || (entry.present() && A::entry_is_leaf(entry.data(), table.level()))
+                {
+                    return Ok(f(&mut table, i));
                 } else {
-                    table = table.next(i)?;
+                    table = table.next(i).ok_or(MapError::NotMapped)?;
+                }
+            }
+        }
+    }
+    /// Translates `virt`, which may be inside a larger page, returning the physical address it
+    /// maps to along with the flags and size of its page
+    pub fn translate(
+        &self,
+        virt: VirtualAddress,
+    ) -> Option<(PhysicalAddress, PageFlags<A>, PageSize<A>)> {
+        let (entry, size) = self
+            .visit(virt, |table, i| unsafe {
+                Some((table.entry(i)?, PageSize::from_level(table.level())?))
+            })
+            .ok()??;
+        let offset = virt.data() & size.offset_mask();
+        Some((
+            entry.address().ok()?.add(offset),
+            leaf_page_flags(entry, size),
+            size,
+        ))
+    }
+
+    /// Iterates over the mappings of this table in address order, coalescing adjacent pages
+    pub fn mappings(&self) -> Mappings<A> {
+        unsafe { self.table().mappings() }
+    }
+
+    /// Calls `visitor` with every present entry of this table, including those of the tables
+    /// below it, in address order
+    pub fn walk(&self, visitor: &mut impl TableVisitor<A>) {
+        unsafe { self.table().walk(visitor) }
+    }
+
+    /// Clones this user address space into a new root table, returning its address.
+    /// Frames are shared instead of copied, with writable pages made read-only and copy-on-write
+    /// in both tables until `resolve_copy_on_write` is called on a write fault. Without
+    /// `SPLIT_TABLES`, the kernel half shares the tables of this one. On failure, pages no longer shared are writable again.
+    pub unsafe fn fork(
+        &mut self,
+        flusher: &mut impl Flusher<A>,
+    ) -> Result<PhysicalAddress, MapError> {
+        unsafe {
+            if self.table_kind != TableKind::User {
+                return Err(MapError::InvalidAddress);
+            }
+            let mut table = self.table();
+            let child_phys = self.allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+            A::write_bytes(A::phys_to_virt(child_phys), 0, A::PAGE_SIZE);
+            let mut child = PageTable::<A>::new(table.base(), child_phys, table.level());
+            for i in 0..A::PAGE_ENTRIES {
+                let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+                if is_shared_root(&table, i) {
+                    child.set_entry(i, entry);
+                    continue;
+                }
+                if let Err(err) =
+                    fork_entry(&mut table, &mut child, i, &mut self.allocator, flusher)
+                {
+                    for j in 0..=i {
+                        unfork_entry(&mut table, &mut child, j, &mut self.allocator);
+                    }
+                    self.allocator.free_one(child_phys);
+                    return Err(err);
+                }
+            }
+            Ok(child_phys)
+        }
+    }
+
+    /// Resolves a write fault at `virt` on a copy-on-write page, by copying its frame, or making it
+    /// writable in place if no other table shares it anymore. Returns `None` if the page is not
+    /// copy-on-write, meaning the fault is genuine.
+    pub unsafe fn resolve_copy_on_write(
+        &mut self,
+        virt: VirtualAddress,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe {
+            let base = PageSize::base();
+            let page = VirtualAddress::new(virt.data() & !A::PAGE_OFFSET_MASK);
+            let (_, flags, _) = self.translate(page).ok_or(MapError::NotMapped)?;
+            if !flags.is_copy_on_write() {
+                return Ok(None);
+            }
+            // Only the page written to is copied, not a whole huge page
+            let flush = self.split(page, base)?;
+            if let Err(err) = self.unshare(page) {
+                // The split leaf may still be cached whole
+                flush.flush();
+                return Err(err);
+            }
+            Ok(Some(flush.combine(PageFlush::new(page))))
+        }
+    }
+
+    /// Makes the copy-on-write base page at `page` writable, copying its frame if shared
+    unsafe fn unshare(&mut self, page: VirtualAddress) -> Result<(), MapError> {
+        unsafe {
+            let entry = self
+                .visit(page, |table, i| table.entry(i))?
+                .ok_or(MapError::InvalidAddress)?;
+            let old_phys = entry.address().map_err(|_| MapError::NotMapped)?;
+            let phys = if self.allocator.refcount(old_phys) == Some(1) {
+                old_phys
+            } else {
+                let new_phys = self.allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+                copy_frame::<A>(old_phys, new_phys);
+                self.allocator.free_one(old_phys);
+                new_phys
+            };
+            let flags = leaf_page_flags(entry, PageSize::base())
+                .copy_on_write(false)
+                .write(true);
+            self.visit(page, |table, i| {
+                table.set_entry(i, PageEntry::new(phys.data(), flags.data()))
+            })?;
+            Ok(())
+        }
+    }
+
+    /// Reserves `size` bytes of base pages starting at `virt`, which must be neither mapped nor
+    /// reserved, to be mapped with `flags` when `resolve_reserved` is called on their first
+    /// access. Only the tables are allocated now, and nothing is reserved if this fails.
+    pub unsafe fn reserve_range(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        flags: PageFlags<A>,
+    ) -> Result<(), MapError> {
+        unsafe {
+            if (virt.data() | size) & A::PAGE_OFFSET_MASK != 0 {
+                return Err(MapError::Misaligned);
+            }
+            if size == 0 {
+                return Err(MapError::InvalidSize);
+            }
+            self.check_half(virt, size)?;
+            self.check_flags(flags)?;
+            let entry = reserved_entry(flags);
+            let mut done = 0;
+            let mut res = Ok(());
+            while done < size {
+                res = self.set_leaf(virt.add(done), PageSize::base(), entry, false);
+                if res.is_err() {
+                    break;
                 }
+                done += A::PAGE_SIZE;
+            }
+            if res.is_err() && done > 0 {
+                // Only the entries before the failure were empty and written here. Reservations
+                // are never cached, so nothing needs to be flushed.
+                match self.unmap_phys_range(virt, done, true) {
+                    Ok(flush) => flush.ignore(),
+                    Err(partial) => partial.flush.ignore(),
+                }
+            }
+            res
+        }
+    }
+
+    /// Flags the page holding `virt` is reserved with, if it is reserved and not mapped yet
+    pub fn reservation(&self, virt: VirtualAddress) -> Option<PageFlags<A>> {
+        self.visit(virt, |table, i| unsafe { reservation(table.entry(i)?) })
+            .ok()?
+    }
+
+    /// Changes the flags the reserved page holding `virt` is to be mapped with
+    pub unsafe fn protect_reserved(
+        &mut self,
+        virt: VirtualAddress,
+        flags: PageFlags<A>,
+    ) -> Result<(), MapError> {
+        unsafe {
+            self.check_flags(flags)?;
+            self.visit(virt, |table, i| {
+                table
+                    .entry(i)
+                    .and_then(reservation)
+                    .ok_or(MapError::NotMapped)?;
+                table.set_entry(i, reserved_entry(flags));
+                Ok(())
+            })?
+        }
+    }
+
+    /// Maps a zeroed frame to the reserved page holding `virt`, on a fault at its first access.
+    /// Returns `None` if the page is not reserved, meaning the fault is genuine.
+    pub unsafe fn resolve_reserved(
+        &mut self,
+        virt: VirtualAddress,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe {
+            let page = VirtualAddress::new(virt.data() & !A::PAGE_OFFSET_MASK);
+            let Some(flags) = self.reservation(page) else {
+                return Ok(None);
+            };
+            let phys = self.allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+            A::write_bytes(A::phys_to_virt(phys), 0, A::PAGE_SIZE);
+            let entry = PageEntry::new(phys.data(), flags.data());
+            if let Err(err) = self.set_leaf(page, PageSize::base(), entry, true) {
+                self.allocator.free_one(phys);
+                return Err(err);
             }
+            Ok(Some(PageFlush::new(page)))
+        }
+    }
+
+    /// Iterates over the mappings of this table that are both writable and executable, which
+    /// write xor execute forbids
+    pub fn write_execute_mappings(&self) -> impl Iterator<Item = Mapping<A>> {
+        unsafe { self.table().write_execute_mappings() }
+    }
+
+    /// Clears the accessed flag of the page mapping `virt`, returning the flush after which the
+    /// MMU sets it again on the next access, or `None` if it was already clear
+    pub unsafe fn test_and_clear_accessed(
+        &mut self,
+        virt: VirtualAddress,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe {
+            self.test_and_clear(virt, |flags| {
+                flags.is_accessed().then(|| flags.accessed(false))
+            })
+        }
+    }
+
+    /// Marks the page mapping `virt` as clean, returning the flush after which the next write is
+    /// recorded, or `None` if it was already clean
+    pub unsafe fn test_and_clear_dirty(
+        &mut self,
+        virt: VirtualAddress,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe { self.test_and_clear(virt, |flags| flags.is_dirty().then(|| flags.dirty(false))) }
+    }
+
+    /// Like `test_and_clear_accessed`, for every page in `size` bytes starting at `virt`, calling
+    /// `f` with each page that was accessed. Larger pages crossing the ends of the range are
+    /// split, and unmapped holes are skipped.
+    pub unsafe fn test_and_clear_accessed_range(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        f: impl FnMut(VirtualAddress, PageSize<A>),
+    ) -> Result<PageFlush<A>, PartialRange<A>> {
+        unsafe {
+            self.test_and_clear_range(
+                virt,
+                size,
+                |flags| flags.is_accessed().then(|| flags.accessed(false)),
+                f,
+            )
+        }
+    }
+
+    /// Like `test_and_clear_dirty`, for every page in `size` bytes starting at `virt`, calling `f`
+    /// with each page that was dirty, like `test_and_clear_accessed_range`
+    pub unsafe fn test_and_clear_dirty_range(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        f: impl FnMut(VirtualAddress, PageSize<A>),
+    ) -> Result<PageFlush<A>, PartialRange<A>> {
+        unsafe {
+            self.test_and_clear_range(
+                virt,
+                size,
+                |flags| flags.is_dirty().then(|| flags.dirty(false)),
+                f,
+            )
+        }
+    }
+
+    /// Replaces the flags of the leaf mapping `virt` with those returned by `clear`, if any
+    unsafe fn test_and_clear(
+        &mut self,
+        virt: VirtualAddress,
+        clear: impl FnOnce(PageFlags<A>) -> Option<PageFlags<A>>,
+    ) -> Result<Option<PageFlush<A>>, MapError> {
+        unsafe {
+            self.visit(virt, |table, i| {
+                let mut entry = table
+                    .entry(i)
+                    .filter(|entry| entry.present())
+                    .ok_or(MapError::NotMapped)?;
+                let size =
+                    PageSize::<A>::from_level(table.level()).ok_or(MapError::SizeMismatch)?;
+                let Some(flags) = clear(entry.flags()) else {
+                    return Ok(None);
+                };
+                entry.set_flags(flags);
+                table.set_entry(i, entry);
+                let page = VirtualAddress::new(virt.data() & !size.offset_mask());
+                Ok(Some(PageFlush::new_range(page, size.bytes(), size)))
+            })?
+        }
+    }
+
+    unsafe fn test_and_clear_range(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        clear: impl Fn(PageFlags<A>) -> Option<PageFlags<A>>,
+        mut f: impl FnMut(VirtualAddress, PageSize<A>),
+    ) -> Result<PageFlush<A>, PartialRange<A>> {
+        unsafe {
+            self.modify_range(virt, size, Holes::Skip, false, |table, i, mut entry, _| {
+                let Some(flags) = clear(entry.flags()) else {
+                    return Ok(());
+                };
+                let page = canonical::<A>(table.entry_base(i).ok_or(MapError::InvalidAddress)?);
+                let size = PageSize::from_level(table.level()).ok_or(MapError::SizeMismatch)?;
+                entry.set_flags(flags);
+                table.set_entry(i, entry);
+                f(page, size);
+                Ok(())
+            })
         }
     }
-    pub fn translate(&self, virt: VirtualAddress) -> Option<(PhysicalAddress, PageFlags<A>)> {
-        let entry = self.visit(virt, |p1, i| unsafe { p1.entry(i) })??;
-        Some((entry.address().ok()?, entry.flags()))
+
+    /// Writes the tables to `out` as indented text, see [`PageTable::dump_text`]
+    pub fn dump_text(&self, out: &mut impl core::fmt::Write) -> core::fmt::Result {
+        unsafe { self.table().dump_text(out) }
+    }
+
+    /// Writes the tables to `out` as a Graphviz DOT digraph, see [`PageTable::dump_dot`]
+    pub fn dump_dot(&self, out: &mut impl core::fmt::Write) -> core::fmt::Result {
+        unsafe { self.table().dump_dot(out) }
     }
 
     pub unsafe fn unmap(
         &mut self,
         virt: VirtualAddress,
+        size: PageSize<A>,
         unmap_parents: bool,
-    ) -> Option<PageFlush<A>> {
+    ) -> Result<PageFlush<A>, UnmapError> {
         unsafe {
-            let (old, _, flush) = self.unmap_phys(virt, unmap_parents)?;
-            self.allocator.free_one(old);
-            Some(flush)
+            let (old, _, flush) = self.unmap_phys(virt, size, unmap_parents)?;
+            self.allocator.free(old, size.frames());
+            Ok(flush)
         }
     }
 
     pub unsafe fn unmap_phys(
         &mut self,
         virt: VirtualAddress,
+        size: PageSize<A>,
         unmap_parents: bool,
-    ) -> Option<(PhysicalAddress, PageFlags<A>, PageFlush<A>)> {
+    ) -> Result<(PhysicalAddress, PageFlags<A>, PageFlush<A>), UnmapError> {
         unsafe {
-            //TODO: verify virt is aligned
+            if virt.data() & size.offset_mask() != 0 {
+                return Err(UnmapError::Misaligned);
+            }
+            let flush = self.split(virt, size)?;
             let mut table = self.table();
-            let level = table.level();
-            unmap_phys_inner(virt, &mut table, level, unmap_parents, &mut self.allocator)
-                .map(|(pa, pf)| (pa, pf, PageFlush::new(virt)))
+            match unmap_phys_inner(virt, size, &mut table, unmap_parents, &mut self.allocator) {
+                Ok((pa, pf)) => Ok((pa, pf, flush)),
+                Err(err) => {
+                    // The split leaf may still be cached whole
+                    flush.flush();
+                    Err(err)
+                }
+            }
         }
     }
-}
-unsafe fn unmap_phys_inner<A: Arch>(
-    virt: VirtualAddress,
-    table: &mut PageTable<A>,
-    initial_level: usize,
-    unmap_parents: bool,
-    allocator: &mut impl FrameAllocator,
-) -> Option<(PhysicalAddress, PageFlags<A>)> {
-    unsafe {
-        let i = table.index_of(virt)?;
 
-        if table.level() == 0 {
-            let entry_opt = table.entry(i);
-            table.set_entry(i, PageEntry::new(0, 0));
-            let entry = entry_opt?;
+    /// Maps `size` bytes of physical memory starting at `phys` to `virt`, walking the tables once
+    /// and using the largest pages that fit. All three must be page aligned, and `size` nonzero.
+    /// Like `map_phys`, this fails on pages already mapped or reserved.
+    pub unsafe fn map_range(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        size: usize,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, PartialRange<A>> {
+        unsafe {
+            let mut progress = RangeProgress::new(virt);
+            if (virt.data() | phys.data() | size) & A::PAGE_OFFSET_MASK != 0 {
+                return Err(progress.partial(MapError::Misaligned));
+            }
+            if size == 0 {
+                return Err(progress.partial(MapError::InvalidSize));
+            }
+            if let Err(err) = self
+                .check_half(virt, size)
+                .and_then(|()| self.check_flags(flags))
+            {
+                return Err(progress.partial(err));
+            }
+            let mut table = self.table();
+            let mut range = RangeMap {
+                allocator: &mut self.allocator,
+                progress: &mut progress,
+                flags,
+                table_kind: self.table_kind,
+            };
+            let res = range.map(&mut table, virt, phys, size);
+            // Any kernel tables added are shared even if mapping failed later
+            self.sync_kernel();
+            match res {
+                Ok(()) => Ok(progress.flush(size)),
+                Err(err) => Err(progress.partial(err)),
+            }
+        }
+    }
 
-            Some((entry.address().ok()?, entry.flags()))
-        } else {
-            let mut subtable = table.next(i)?;
-
-            let res =
-                unmap_phys_inner(virt, &mut subtable, initial_level, unmap_parents, allocator)?;
-
-            //TODO: This is a bad idea for architectures where the kernel mappings are done in the process tables,
-            // as these mappings may become out of sync
-            if unmap_parents {
-                // TODO: Use a counter? This would reduce the remaining number of available bits, but could be
-                // faster (benchmark is needed).
-                let is_still_populated = (0..A::PAGE_ENTRIES)
-                    .map(|j| subtable.entry(j).expect("must be within bounds"))
-                    .any(|e| e.present());
-
-                if !is_still_populated {
-                    allocator.free_one(subtable.phys());
+    /// Unmaps every page in `size` bytes starting at `virt`, freeing their frames and splitting
+    /// larger pages crossing the ends of the range. Unmapped holes are skipped, dropping any
+    /// reservation.
+    pub unsafe fn unmap_range(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        unmap_parents: bool,
+    ) -> Result<PageFlush<A>, PartialRange<A, UnmapError>> {
+        unsafe {
+            self.modify_range(
+                virt,
+                size,
+                Holes::Clear,
+                unmap_parents,
+                |table, i, entry, allocator| {
+                    let frames = PageSize::<A>::from_level(table.level())
+                        .ok_or(UnmapError::SizeMismatch)?
+                        .frames();
+                    let phys = entry.address().map_err(|_| UnmapError::NotMapped)?;
                     table.set_entry(i, PageEntry::new(0, 0));
+                    allocator.free(phys, frames);
+                    Ok(())
+                },
+            )
+        }
+    }
+
+    /// Like `unmap_range`, without freeing the frames that were mapped
+    pub unsafe fn unmap_phys_range(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        unmap_parents: bool,
+    ) -> Result<PageFlush<A>, PartialRange<A, UnmapError>> {
+        unsafe {
+            self.modify_range(virt, size, Holes::Clear, unmap_parents, |table, i, _, _| {
+                table.set_entry(i, PageEntry::new(0, 0));
+                Ok(())
+            })
+        }
+    }
+
+    /// Changes the permissions of every page in `size` bytes starting at `virt` to those of
+    /// `flags`, splitting larger pages crossing the ends of the range. The whole range must be
+    /// mapped. The copy-on-write, software, accessed, dirty and memory type state of each page is
+    /// kept, and copy-on-write pages stay read-only until `resolve_copy_on_write`.
+    pub unsafe fn protect_range(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, PartialRange<A>> {
+        unsafe {
+            if let Err(err) = self.check_flags(flags) {
+                return Err(RangeProgress::new(virt).partial(err));
+            }
+            self.modify_range(virt, size, Holes::Fail, false, |table, i, entry, _| {
+                let size =
+                    PageSize::<A>::from_level(table.level()).ok_or(MapError::SizeMismatch)?;
+                let phys = entry.address().map_err(|_| MapError::NotMapped)?;
+                let old = leaf_page_flags(entry, size);
+                let state = page_state_mask::<A>();
+                let mut new = PageFlags::<A>::from_data(flags.data() & !state | old.data() & state);
+                if old.is_copy_on_write() {
+                    // Writes to the shared frame must fault until it is copied
+                    new = new
+                        .write(false)
+                        .custom_flag(A::ENTRY_FLAG_DIRTY_BIT_MODIFIER, false);
+                }
+                let flags = A::leaf_flags(new.data(), size.level());
+                table.set_entry(i, PageEntry::new(phys.data(), flags));
+                Ok(())
+            })
+        }
+    }
+
+    /// Maps a kernel stack of `pages` zeroed pages at the lowest free place in the `window_size`
+    /// bytes starting at `window`, with guard pages below and above it so overflows fault. The
+    /// guard pages hold markers that are not present, so nothing else can be mapped there. If
+    /// undoing a failure fails too, that error is returned instead, as pages are left mapped.
+    pub unsafe fn map_stack(
+        &mut self,
+        window: VirtualAddress,
+        window_size: usize,
+        pages: usize,
+    ) -> Result<(Stack<A>, PageFlush<A>), RegionError> {
+        unsafe {
+            if (window.data() | window_size) & A::PAGE_OFFSET_MASK != 0 {
+                return Err(RegionError::Misaligned);
+            }
+            if pages == 0 {
+                return Err(RegionError::InvalidSize);
+            }
+            self.check_half(window, window_size)?;
+            // The first free page found is the lower guard
+            let mut free = 0;
+            let guard = free_run(&self.table(), window, window_size, pages + 2, &mut free)?
+                .ok_or(RegionError::NoSpace)?;
+            let bottom = guard.add(A::PAGE_SIZE);
+            let top = bottom.add(pages * A::PAGE_SIZE);
+
+            let base = PageSize::base();
+            let mut res = self
+                .set_leaf(guard, base, guard_entry(), false)
+                .and_then(|()| self.set_leaf(top, base, guard_entry(), false));
+            let flags = PageFlags::new().write(true);
+            let mut flush = PageFlush::new_range(bottom, 0, base);
+            for i in 0..pages {
+                if res.is_err() {
+                    break;
+                }
+                let page = bottom.add(i * A::PAGE_SIZE);
+                let mapped = match self.allocator.allocate_one() {
+                    Some(phys) => {
+                        A::write_bytes(A::phys_to_virt(phys), 0, A::PAGE_SIZE);
+                        self.map_phys(page, phys, base, flags)
+                            .inspect_err(|_| self.allocator.free_one(phys))
+                    }
+                    None => Err(MapError::OutOfFrames),
+                };
+                match mapped {
+                    Ok(page_flush) => flush = flush.combine(page_flush),
+                    Err(err) => res = Err(err),
                 }
             }
+            if let Err(err) = res {
+                flush.flush();
+                // Clears the guards too
+                return match self.unmap_range(guard, (pages + 2) * A::PAGE_SIZE, true) {
+                    Ok(flush) => {
+                        flush.flush();
+                        Err(err.into())
+                    }
+                    Err(partial) => {
+                        partial.flush.flush();
+                        Err(partial.error.into())
+                    }
+                };
+            }
+            let stack = Stack {
+                bottom,
+                size: pages * A::PAGE_SIZE,
+                _phantom: PhantomData,
+            };
+            Ok((stack, flush))
+        }
+    }
 
-            Some(res)
+    /// Unmaps a stack mapped by `map_stack` and clears its guard pages, returning its frames to
+    /// the allocator
+    pub unsafe fn unmap_stack(
+        &mut self,
+        stack: Stack<A>,
+    ) -> Result<PageFlush<A>, PartialRange<A, UnmapError>> {
+        unsafe {
+            let guard = VirtualAddress::new(stack.bottom.data() - A::PAGE_SIZE);
+            self.unmap_range(guard, stack.size + 2 * A::PAGE_SIZE, true)
         }
     }
-}
-impl<A, F: core::fmt::Debug> core::fmt::Debug for PageMapper<A, F> {
-    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        f.debug_struct("PageMapper")
-            .field("frame", &self.table_addr)
-            .field("allocator", &self.allocator)
-            .finish()
+
+    unsafe fn modify_range<E: From<MapError>>(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        holes: Holes,
+        unmap_parents: bool,
+        mut f: impl FnMut(&mut PageTable<A>, usize, PageEntry<A>, &mut F) -> Result<(), E>,
+    ) -> Result<PageFlush<A>, PartialRange<A, E>> {
+        unsafe {
+            let mut progress = RangeProgress::new(virt);
+            if (virt.data() | size) & A::PAGE_OFFSET_MASK != 0 {
+                return Err(progress.partial(MapError::Misaligned.into()));
+            }
+            if size == 0 {
+                return Err(progress.partial(MapError::InvalidSize.into()));
+            }
+            if let Err(err) = self.check_half(virt, size) {
+                return Err(progress.partial(err.into()));
+            }
+            let mut table = self.table();
+            let mut walk = RangeWalk {
+                allocator: &mut self.allocator,
+                progress: &mut progress,
+                holes,
+                unmap_parents,
+                table_kind: self.table_kind,
+                f: &mut f,
+                _phantom: PhantomData,
+            };
+            match walk.walk(&mut table, virt, size) {
+                Ok(()) => Ok(progress.flush(size)),
+                Err(err) => Err(progress.partial(err)),
+            }
+        }
+    }
+
+    /// Splits any leaf larger than `size` that maps `virt` into a subtable of leaves with the
+    /// same flags, until `virt` is mapped by a leaf of `size`. Returns the flush for the split
+    /// leaf, or for `virt` alone if none was split.
+    unsafe fn split(
+        &mut self,
+        virt: VirtualAddress,
+        size: PageSize<A>,
+    ) -> Result<PageFlush<A>, MapError> {
+        unsafe {
+            let mut flush = None;
+            match self.split_inner(virt, size, &mut flush) {
+                Ok(()) => Ok(flush.unwrap_or_else(|| PageFlush::new(virt))),
+                Err(err) => {
+                    // Leaves split before the failure may still be cached whole
+                    if let Some(flush) = flush {
+                        flush.flush();
+                    }
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    unsafe fn split_inner(
+        &mut self,
+        virt: VirtualAddress,
+        size: PageSize<A>,
+        flush: &mut Option<PageFlush<A>>,
+    ) -> Result<(), MapError> {
+        unsafe {
+            self.check_half(virt, 1)?;
+            let mut table = self.table();
+            while table.level() > size.level() {
+                let i = table.index_of(virt)?;
+                let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+                if !entry.present() {
+                    break;
+                }
+                if A::entry_is_leaf(entry.data(), table.level()) {
+                    let leaf = PageSize::from_level(table.level()).ok_or(MapError::SizeMismatch)?;
+                    let base = VirtualAddress::new(virt.data() & !leaf.offset_mask());
+                    flush.get_or_insert(PageFlush::new_range(base, leaf.bytes(), leaf));
+                    split_leaf(&mut table, i, self.table_kind, &mut self.allocator)?;
+                    if is_shared_root(&table, i) {
+                        self.sync_kernel();
+                    }
+                }
+                table = table.next(i).ok_or(MapError::InvalidAddress)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Collapses the table of leaves of `size` containing `virt`, and then its parents, into
+    /// larger leaves for as long as they are fully populated, physically contiguous and have the
+    /// same flags, freeing the tables to the allocator. Mapping and remapping never do this on
+    /// their own. Returns the flush for the merged range, or `None` if nothing was merged.
+    pub unsafe fn merge(
+        &mut self,
+        virt: VirtualAddress,
+        size: PageSize<A>,
+    ) -> Option<PageFlush<A>> {
+        unsafe {
+            let mut merged = None;
+            'levels: for level in size.level() + 1..=A::HUGE_PAGE_LEVELS {
+                let mut parent = self.table();
+                while parent.level() > level {
+                    match parent.index_of(virt).ok().and_then(|i| parent.next(i)) {
+                        Some(next) => parent = next,
+                        None => break 'levels,
+                    }
+                }
+                let Ok(i) = parent.index_of(virt) else {
+                    break;
+                };
+                // Other address spaces may still point to a shared table
+                if is_shared_root(&parent, i) {
+                    break;
+                }
+                let Some(child) = parent.next(i) else {
+                    break;
+                };
+                let Some(leaf) = merged_leaf(&child) else {
+                    break;
+                };
+                parent.set_entry(i, leaf);
+                self.allocator.free_one(child.phys());
+                merged = PageSize::from_level(level);
+            }
+
+            // Leaves of any merged level may still be cached, down to the smallest
+            let merged: PageSize<A> = merged?;
+            let base = VirtualAddress::new(virt.data() & !merged.offset_mask());
+            Some(PageFlush::new_range(base, merged.bytes(), size))
+        }
+    }
+}
+/// A range operation that stopped early with `error`, after `done` bytes from the start of the
+/// range were processed. The processed part must still be flushed.
+#[must_use = "The page table must be flushed, or the changes unsafely ignored"]
+pub struct PartialRange<A, E = MapError> {
+    pub done: usize,
+    pub flush: PageFlush<A>,
+    pub error: E,
+}
+
+/// Kernel stack mapped by [`PageMapper::map_stack`], between two guard pages. Its frames are
+/// only freed by [`PageMapper::unmap_stack`].
+#[must_use = "The stack must be unmapped with PageMapper::unmap_stack, or its frames are leaked"]
+pub struct Stack<A> {
+    bottom: VirtualAddress,
+    size: usize,
+    _phantom: PhantomData<fn() -> A>,
+}
+
+impl<A: Arch> Stack<A> {
+    /// Lowest address of the stack
+    pub fn bottom(&self) -> VirtualAddress {
+        self.bottom
+    }
+
+    /// Address right after the stack, where it starts growing down from
+    pub fn top(&self) -> VirtualAddress {
+        self.bottom.add(self.size)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether `virt` is in one of the guard pages, meaning a fault there is an overflow or
+    /// underflow of the stack
+    pub fn is_guard(&self, virt: VirtualAddress) -> bool {
+        let guard_below = self.bottom.data().wrapping_sub(A::PAGE_SIZE);
+        virt.data().wrapping_sub(guard_below) < A::PAGE_SIZE
+            || virt.data().wrapping_sub(self.top().data()) < A::PAGE_SIZE
+    }
+}
+
+impl<A: Arch> core::fmt::Debug for Stack<A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("Stack")
+            .field("bottom", &self.bottom)
+            .field("size", &format_args!("{:#x}", self.size))
+            .finish()
+    }
+}
+
+/// Tracks how far a range operation got and the smallest page it changed
+struct RangeProgress {
+    start: VirtualAddress,
+    done: usize,
+    level: usize,
+}
+
+impl RangeProgress {
+    fn new(start: VirtualAddress) -> Self {
+        Self {
+            start,
+            done: 0,
+            level: usize::MAX,
+        }
+    }
+
+    /// Records a leaf at `level` ending at `end` as processed
+    fn leaf(&mut self, end: VirtualAddress, level: usize) {
+        self.done = end.data() - self.start.data();
+        self.level = self.level.min(level);
+    }
+
+    /// Records an unmapped part ending at `end` as processed, without anything to flush
+    fn hole(&mut self, end: VirtualAddress) {
+        self.done = end.data() - self.start.data();
+    }
+
+    fn flush<A: Arch>(&self, size: usize) -> PageFlush<A> {
+        let stride = PageSize::from_level(self.level).unwrap_or_else(PageSize::base);
+        PageFlush::new_range(self.start, size, stride)
+    }
+
+    fn partial<A: Arch, E>(&self, error: E) -> PartialRange<A, E> {
+        PartialRange {
+            done: self.done,
+            flush: self.flush(self.done),
+            error,
+        }
+    }
+}
+
+/// Size of the part of `len` bytes from `virt` covered by the entry of `table` containing `virt`
+fn entry_chunk<A: Arch>(table: &PageTable<A>, virt: VirtualAddress, len: usize) -> usize {
+    let entry_size = 1 << (table.level() * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+    len.min(entry_size - (virt.data() & (entry_size - 1)))
+}
+
+/// Start of the first `count` consecutive base pages in `len` bytes from `virt` that are neither
+/// mapped nor reserved, continuing a run of `free` such pages right before `virt`. Tables are only
+/// walked into when they are present, so empty parts of the range are skipped whole.
+unsafe fn free_run<A: Arch>(
+    table: &PageTable<A>,
+    virt: VirtualAddress,
+    len: usize,
+    count: usize,
+    free: &mut usize,
+) -> Result<Option<VirtualAddress>, MapError> {
+    unsafe {
+        let mut offset = 0;
+        while offset < len {
+            let virt = virt.add(offset);
+            let chunk = entry_chunk(table, virt, len - offset);
+            let i = table.index_of(virt)?;
+            let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+            if entry.data() == 0 {
+                *free += chunk >> A::PAGE_SHIFT;
+            } else if let Some(next) = table.next(i) {
+                if let Some(found) = free_run(&next, virt, chunk, count, free)? {
+                    return Ok(Some(found));
+                }
+            } else {
+                // Larger pages and reservations, or other software state
+                *free = 0;
+            }
+            offset += chunk;
+            if *free >= count {
+                let start = virt.add(chunk).data() - *free * A::PAGE_SIZE;
+                return Ok(Some(VirtualAddress::new(start)));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Walk mapping a range with the largest leaves that fit
+struct RangeMap<'a, A, F> {
+    allocator: &'a mut F,
+    progress: &'a mut RangeProgress,
+    flags: PageFlags<A>,
+    table_kind: TableKind,
+}
+
+impl<A: Arch, F: FrameAllocator> RangeMap<'_, A, F> {
+    unsafe fn map(
+        &mut self,
+        table: &mut PageTable<A>,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        len: usize,
+    ) -> Result<(), MapError> {
+        unsafe {
+            let level = table.level();
+            let entry_size = 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+            let mut offset = 0;
+            while offset < len {
+                let virt = virt.add(offset);
+                let phys = phys.add(offset);
+                let chunk = entry_chunk(table, virt, len - offset);
+                let i = table.index_of(virt)?;
+                let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+                // Tables already in place are kept, instead of being leaked by a larger page
+                let fits = level == 0
+                    || (level <= A::HUGE_PAGE_LEVELS
+                        && chunk == entry_size
+                        && phys.data() & (entry_size - 1) == 0
+                        && (!entry.present() || A::entry_is_leaf(entry.data(), level)));
+                if fits {
+                    // Reservations and other software state occupy the entry, like pages
+                    if entry.data() != 0 {
+                        return Err(MapError::AlreadyMapped);
+                    }
+                    table.set_entry(
+                        i,
+                        PageEntry::new(phys.data(), A::leaf_flags(self.flags.data(), level)),
+                    );
+                    self.progress.leaf(virt.add(chunk), level);
+                } else {
+                    let mut next = match table.next(i) {
+                        Some(some) => some,
+                        None => {
+                            // A larger page already maps this address, or it is reserved
+                            if entry.data() != 0 {
+                                return Err(MapError::AlreadyMapped);
+                            }
+                            let next_phys =
+                                self.allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+                            // Zero the newly allocated subtable to avoid garbage entries
+                            A::write_bytes(A::phys_to_virt(next_phys), 0, A::PAGE_SIZE);
+                            table.set_entry(
+                                i,
+                                PageEntry::new(next_phys.data(), table_flags::<A>(self.table_kind)),
+                            );
+                            table.next(i).ok_or(MapError::InvalidAddress)?
+                        }
+                    };
+                    self.map(&mut next, virt, phys, chunk)?;
+                }
+                offset += chunk;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// What a range walk does with entries that are not present
+#[derive(Clone, Copy)]
+enum Holes {
+    /// Stops the walk with `MapError::NotMapped`
+    Fail,
+    /// Skips them
+    Skip,
+    /// Skips them, clearing reservations and other software state they hold
+    Clear,
+}
+
+/// Walk applying `f` to every leaf inside a range, splitting leaves crossing its ends
+struct RangeWalk<'a, A, F, T> {
+    allocator: &'a mut F,
+    progress: &'a mut RangeProgress,
+    holes: Holes,
+    unmap_parents: bool,
+    table_kind: TableKind,
+    f: &'a mut T,
+    _phantom: PhantomData<fn() -> A>,
+}
+
+impl<A: Arch, F: FrameAllocator, T> RangeWalk<'_, A, F, T> {
+    unsafe fn walk<E: From<MapError>>(
+        &mut self,
+        table: &mut PageTable<A>,
+        virt: VirtualAddress,
+        len: usize,
+    ) -> Result<(), E>
+    where
+        T: FnMut(&mut PageTable<A>, usize, PageEntry<A>, &mut F) -> Result<(), E>,
+    {
+        unsafe {
+            let level = table.level();
+            let entry_size = 1 << (level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+            let mut offset = 0;
+            while offset < len {
+                let virt = virt.add(offset);
+                let chunk = entry_chunk(table, virt, len - offset);
+                let i = table.index_of(virt)?;
+                let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+                if !entry.present() {
+                    match self.holes {
+                        Holes::Fail => return Err(MapError::NotMapped.into()),
+                        Holes::Clear if entry.data() != 0 && chunk == entry_size => {
+                            table.set_entry(i, PageEntry::new(0, 0));
+                        }
+                        _ => (),
+                    }
+                    self.progress.hole(virt.add(chunk));
+                } else if A::entry_is_leaf(entry.data(), level) && chunk == entry_size {
+                    (self.f)(table, i, entry, self.allocator)?;
+                    self.progress.leaf(virt.add(chunk), level);
+                } else {
+                    if A::entry_is_leaf(entry.data(), level) {
+                        split_leaf(table, i, self.table_kind, self.allocator)?;
+                    }
+                    let mut next = table.next(i).ok_or(MapError::InvalidAddress)?;
+                    self.walk(&mut next, virt, chunk)?;
+                    if self.unmap_parents && !is_shared_root(table, i) && next.is_empty() {
+                        self.allocator.free_one(next.phys());
+                        table.set_entry(i, PageEntry::new(0, 0));
+                    }
+                }
+                offset += chunk;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Whether entry `i` of `table` is a root entry of the kernel half, which may point to a table
+/// shared by every address space, see [`PageMapper::with_kernel_template`]
+fn is_shared_root<A: Arch>(table: &PageTable<A>, i: usize) -> bool {
+    !A::SPLIT_TABLES && table.level() == A::PAGE_LEVELS - 1 && i >= A::PAGE_ENTRIES / 2
+}
+
+/// Replaces the leaf at index `i` of `table`, in a table of `table_kind`, with a subtable of
+/// smaller leaves mapping the same memory with the same flags
+unsafe fn split_leaf<A: Arch>(
+    table: &mut PageTable<A>,
+    i: usize,
+    table_kind: TableKind,
+    allocator: &mut impl FrameAllocator,
+) -> Result<(), MapError> {
+    unsafe {
+        let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+        let phys = entry.address().map_err(|_| MapError::NotMapped)?;
+        let flags = A::leaf_page_flags(entry.flags().data(), table.level());
+        let child_size =
+            PageSize::<A>::from_level(table.level() - 1).ok_or(MapError::SizeMismatch)?;
+        let child_phys = allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+        // Every entry is replaced, but the entry count starts from a zeroed table
+        A::write_bytes(A::phys_to_virt(child_phys), 0, A::PAGE_SIZE);
+        let child_base = table.entry_base(i).ok_or(MapError::InvalidAddress)?;
+        let mut child = PageTable::<A>::new(child_base, child_phys, child_size.level());
+        for j in 0..A::PAGE_ENTRIES {
+            let child_entry = PageEntry::new(
+                phys.data() + j * child_size.bytes(),
+                A::leaf_flags(flags, child_size.level()),
+            );
+            child.set_entry(j, child_entry);
+        }
+        table.set_entry(
+            i,
+            PageEntry::new(child_phys.data(), table_flags::<A>(table_kind)),
+        );
+        Ok(())
+    }
+}
+/// Copies entry `i` of `table` into `child`, sharing leaves copy-on-write and duplicating tables
+unsafe fn fork_entry<A: Arch>(
+    table: &mut PageTable<A>,
+    child: &mut PageTable<A>,
+    i: usize,
+    allocator: &mut impl FrameAllocator,
+    flusher: &mut impl Flusher<A>,
+) -> Result<(), MapError> {
+    unsafe {
+        let entry = table.entry(i).ok_or(MapError::InvalidAddress)?;
+        if !entry.present() {
+            // Reservations and other software state are kept, without anything to share
+            if entry.data() != 0 {
+                child.set_entry(i, entry);
+            }
+            return Ok(());
+        }
+        let level = table.level();
+        if let Some(mut next) = table.next(i) {
+            let next_phys = allocator.allocate_one().ok_or(MapError::OutOfFrames)?;
+            A::write_bytes(A::phys_to_virt(next_phys), 0, A::PAGE_SIZE);
+            let mut child_next = PageTable::<A>::new(next.base(), next_phys, next.level());
+            child.set_entry(i, PageEntry::new(next_phys.data(), entry.flags().data()));
+            for j in 0..A::PAGE_ENTRIES {
+                fork_entry(&mut next, &mut child_next, j, allocator, flusher)?;
+            }
+            return Ok(());
+        }
+
+        let size = PageSize::<A>::from_level(level).ok_or(MapError::SizeMismatch)?;
+        let phys = entry.address().map_err(|_| MapError::NotMapped)?;
+        allocator
+            .add_ref(phys, size.frames())
+            .ok_or(MapError::NotShareable)?;
+        let flags = leaf_page_flags(entry, size);
+        let shared = if writable(flags) {
+            // Hardware dirty state management would make the page writable again
+            let flags = flags
+                .write(false)
+                .copy_on_write(true)
+                .custom_flag(A::ENTRY_FLAG_DIRTY_BIT_MODIFIER, false);
+            let shared = PageEntry::new(phys.data(), A::leaf_flags(flags.data(), level));
+            table.set_entry(i, shared);
+            let base = table.entry_base(i).ok_or(MapError::InvalidAddress)?;
+            flusher.consume(PageFlush::new_range(
+                VirtualAddress::new(base.data()),
+                size.bytes(),
+                size,
+            ));
+            shared
+        } else {
+            entry
+        };
+        child.set_entry(i, shared);
+        Ok(())
+    }
+}
+
+/// Undoes `fork_entry` for entry `i` of `child`, freeing its tables and the references it holds,
+/// and making the copy-on-write pages of `table` that are no longer shared writable again
+unsafe fn unfork_entry<A: Arch>(
+    table: &mut PageTable<A>,
+    child: &mut PageTable<A>,
+    i: usize,
+    allocator: &mut impl FrameAllocator,
+) {
+    unsafe {
+        let Some(entry) = child.entry(i).filter(|entry| entry.present()) else {
+            return;
+        };
+        if let Some(mut child_next) = child.next(i) {
+            if let Some(mut next) = table.next(i) {
+                for j in 0..A::PAGE_ENTRIES {
+                    unfork_entry(&mut next, &mut child_next, j, allocator);
+                }
+            }
+            allocator.free_one(child_next.phys());
+            return;
+        }
+        let (Some(size), Ok(phys)) = (PageSize::<A>::from_level(child.level()), entry.address())
+        else {
+            return;
+        };
+        allocator.free(phys, size.frames());
+        let Some(parent) = table.entry(i) else {
+            return;
+        };
+        let flags = leaf_page_flags(parent, size);
+        if parent.address() == Ok(phys)
+            && flags.is_copy_on_write()
+            && allocator.refcount(phys) == Some(1)
+        {
+            let flags = flags.copy_on_write(false).write(true);
+            table.set_entry(
+                i,
+                PageEntry::new(phys.data(), A::leaf_flags(flags.data(), size.level())),
+            );
+        }
+    }
+}
+
+/// Frees `table`, the tables below it and a reference to every frame they map
+unsafe fn free_tree<A: Arch>(table: &mut PageTable<A>, allocator: &mut impl FrameAllocator) {
+    unsafe {
+        for i in 0..A::PAGE_ENTRIES {
+            let Some(entry) = table.entry(i).filter(|entry| entry.present()) else {
+                continue;
+            };
+            if let Some(mut next) = table.next(i) {
+                free_tree(&mut next, allocator);
+            } else if let (Some(size), Ok(phys)) =
+                (PageSize::<A>::from_level(table.level()), entry.address())
+            {
+                allocator.free(phys, size.frames());
+            }
+        }
+        allocator.free_one(table.phys());
+    }
+}
+
+/// Copies the contents of the frame at `src` to the frame at `dst`
+unsafe fn copy_frame<A: Arch>(src: PhysicalAddress, dst: PhysicalAddress) {
+    unsafe {
+        let src = A::phys_to_virt(src);
+        let dst = A::phys_to_virt(dst);
+        for offset in (0..A::PAGE_SIZE).step_by(core::mem::size_of::<usize>()) {
+            A::write::<usize>(dst.add(offset), A::read::<usize>(src.add(offset)));
+        }
+    }
+}
+
+/// Flags of new tables in a table of `table_kind`
+fn table_flags<A: Arch>(table_kind: TableKind) -> usize {
+    //TODO: correct flags?
+    A::ENTRY_FLAG_DEFAULT_TABLE
+        | if table_kind == TableKind::User {
+            A::ENTRY_FLAG_TABLE_USER
+        } else {
+            0
+        }
+}
+/// Large leaf replacing `table`, if it is fully populated with physically contiguous leaves of
+/// the same flags, ignoring accessed and dirty flags which are combined
+unsafe fn merged_leaf<A: Arch>(table: &PageTable<A>) -> Option<PageEntry<A>> {
+    unsafe {
+        let size = PageSize::<A>::from_level(table.level())?;
+        let merged = PageSize::<A>::from_level(table.level() + 1)?;
+        let accessed_dirty = A::ENTRY_FLAG_ACCESSED | A::ENTRY_FLAG_DIRTY;
+        let first = table.entry(0)?;
+        let phys = first.address().ok()?;
+        if phys.data() & merged.offset_mask() != 0 {
+            return None;
+        }
+        let flags = first.flags().data() & !accessed_dirty;
+        let mut used = 0;
+        // The last entry is checked first, to bail out early on tables being filled in order
+        let last = A::PAGE_ENTRIES - 1;
+        for i in iter::once(last).chain(0..last) {
+            let entry = table.entry(i)?;
+            if !entry.present()
+                || !A::entry_is_leaf(entry.data(), table.level())
+                || entry.address().ok()? != phys.add(i * size.bytes())
+                || entry.flags().data() & !accessed_dirty != flags
+            {
+                return None;
+            }
+            used |= entry.flags().data() & accessed_dirty;
+        }
+        let flags = A::leaf_page_flags(flags | used, table.level());
+        Some(PageEntry::new(
+            phys.data(),
+            A::leaf_flags(flags, merged.level()),
+        ))
+    }
+}
+/// Entry reserving a base page to be mapped with `flags`, which is not present, with the lowest
+/// address bit set to tell it apart from empty entries
+fn reserved_entry<A: Arch>(flags: PageFlags<A>) -> PageEntry<A> {
+    PageEntry::from_data(flags.data() & !A::ENTRY_FLAG_PRESENT | 1 << A::ENTRY_ADDRESS_SHIFT)
+}
+
+/// Entry marking a stack guard page, which is not present, with the second lowest address bit
+/// set to tell it apart from empty entries and reservations
+fn guard_entry<A: Arch>() -> PageEntry<A> {
+    PageEntry::from_data(2 << A::ENTRY_ADDRESS_SHIFT)
+}
+
+/// Flags a base page is reserved with by `entry`, see `reserved_entry`
+fn reservation<A: Arch>(entry: PageEntry<A>) -> Option<PageFlags<A>> {
+    let reserved = !entry.present() && entry.data() & 1 << A::ENTRY_ADDRESS_SHIFT != 0;
+    reserved.then(|| unsafe { PageFlags::from_data(entry.flags().data() | A::ENTRY_FLAG_PRESENT) })
+}
+
+fn leaf_page_flags<A: Arch>(entry: PageEntry<A>, size: PageSize<A>) -> PageFlags<A> {
+    unsafe { PageFlags::from_data(A::leaf_page_flags(entry.flags().data(), size.level())) }
+}
+
+/// Flags of a leaf holding the state of its page rather than its permissions
+fn page_state_mask<A: Arch>() -> usize {
+    A::ENTRY_FLAG_COPY_ON_WRITE
+        | A::ENTRY_FLAG_SOFTWARE_MASK
+        | A::ENTRY_FLAG_ACCESSED
+        | A::ENTRY_FLAG_DIRTY
+        | A::ENTRY_MEMORY_TYPE_MASK
+}
+
+/// Whether writes to a page with `flags` succeed, directly or by the MMU marking it dirty
+fn writable<A: Arch>(flags: PageFlags<A>) -> bool {
+    flags.has_write()
+        || A::ENTRY_FLAG_DIRTY_BIT_MODIFIER != 0 && flags.has_flag(A::ENTRY_FLAG_DIRTY_BIT_MODIFIER)
+}
+unsafe fn unmap_phys_inner<A: Arch>(
+    virt: VirtualAddress,
+    size: PageSize<A>,
+    table: &mut PageTable<A>,
+    unmap_parents: bool,
+    allocator: &mut impl FrameAllocator,
+) -> Result<(PhysicalAddress, PageFlags<A>), UnmapError> {
+    unsafe {
+        let i = table.index_of(virt)?;
+
+        if table.level() == size.level() {
+            let entry = table.entry(i).ok_or(UnmapError::InvalidAddress)?;
+            // Only leaves of the requested size are unmapped, never tables
+            if entry.present() && !A::entry_is_leaf(entry.data(), table.level()) {
+                return Err(UnmapError::SizeMismatch);
+            }
+            let phys = entry.address().map_err(|_| UnmapError::NotMapped)?;
+            table.set_entry(i, PageEntry::new(0, 0));
+
+            Ok((phys, leaf_page_flags(entry, size)))
+        } else {
+            let mut subtable = table.next(i).ok_or(UnmapError::NotMapped)?;
+
+            let res = unmap_phys_inner(virt, size, &mut subtable, unmap_parents, allocator)?;
+
+            // Shared kernel tables stay, as other address spaces still point to them
+            if unmap_parents && !is_shared_root(table, i) && subtable.is_empty() {
+                allocator.free_one(subtable.phys());
+                table.set_entry(i, PageEntry::new(0, 0));
+            }
+
+            Ok(res)
+        }
+    }
+}
+impl<A, F: core::fmt::Debug> core::fmt::Debug for PageMapper<A, F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_struct("PageMapper")
+            .field("frame", &self.table_addr)
+            .field("allocator", &self.allocator)
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use crate::{
+        arch::all_archs, canonical, AccessedDirty, Arch, BuddyAllocator, BumpAllocator,
+        EmulateArch, FrameAllocator, MapError, MemoryArea, PageFaultReason, PageFlags, PageFlush,
+        PageFlushAll, PageMapper, PageSize, PhysicalAddress, Privilege, RegionError, TableKind,
+        TlbCheck, UnmapError, VirtualAddress, MEGABYTE,
+    };
+
+    all_archs!(
+        mapper,
+        write_xor_execute,
+        huge_pages,
+        split_merge,
+        entry_count,
+        reserve,
+        stacks,
+        ranges,
+        fork,
+        fork_clean,
+        shared_kernel,
+        halves,
+        test_and_clear
+    );
+
+    unsafe fn mapper<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let virt = VirtualAddress::new(4 * MEGABYTE + 3 * A::PAGE_SIZE);
+
+            // Initial offset mapping is visible through both the mapper and the emulator
+            let kernel =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::Kernel, &mut allocator);
+            let phys_virt = EmulateArch::<A>::phys_to_virt(areas[0].base);
+            let (phys, _, _) = kernel.translate(phys_virt).expect("offset map missing");
+            assert_eq!(phys, areas[0].base);
+
+            // Each mapper only manages its half of the address space
+            assert!(kernel.translate(virt).is_none());
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            assert!(mapper.translate(phys_virt).is_none());
+            assert!(matches!(
+                mapper.map_phys(phys_virt, phys, PageSize::base(), PageFlags::new()),
+                Err(MapError::InvalidAddress)
+            ));
+
+            mapper
+                .map(
+                    virt,
+                    PageSize::base(),
+                    PageFlags::new().user(true).write(true),
+                )
+                .expect("failed to map page")
+                .flush();
+            let (phys, flags, _) = mapper.translate(virt).expect("failed to translate page");
+            assert!(flags.has_write());
+            assert!(flags.has_user());
+
+            EmulateArch::<A>::write::<u32>(virt.add(8), 0xCAFE_F00D);
+            assert_eq!(EmulateArch::<A>::read::<u32>(virt.add(8)), 0xCAFE_F00D);
+            let alias = EmulateArch::<A>::phys_to_virt(phys.add(8));
+            assert_eq!(EmulateArch::<A>::read::<u32>(alias), 0xCAFE_F00D);
+
+            mapper
+                .remap(
+                    virt,
+                    PageSize::base(),
+                    PageFlags::new().user(true).write(false),
+                )
+                .expect("failed to remap page")
+                .flush();
+            let (same_phys, flags, _) = mapper.translate(virt).expect("failed to translate page");
+            assert_eq!(same_phys, phys);
+            assert!(!flags.has_write());
+            assert_eq!(EmulateArch::<A>::read::<u32>(virt.add(8)), 0xCAFE_F00D);
+
+            // The bump allocator cannot free, so keep the parent tables
+            let (old_phys, _, flush) = mapper
+                .unmap_phys(virt, PageSize::base(), false)
+                .expect("failed to unmap");
+            flush.flush();
+            assert_eq!(old_phys, phys);
+            assert!(mapper.translate(virt).is_none());
+        }
+    }
+
+    unsafe fn write_xor_execute<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator)
+                    .with_write_xor_execute(true);
+            let base = PageSize::base();
+            let code = VirtualAddress::new(4 * MEGABYTE);
+            let jit = code.add(A::PAGE_SIZE);
+            let flags = PageFlags::new().user(true).execute(true);
+            let wx = flags.write(true);
+            assert!(wx.is_write_execute());
+            assert!(!flags.is_write_execute());
+
+            assert_eq!(
+                mapper.map(code, base, wx).err(),
+                Some(MapError::WriteExecute)
+            );
+            assert_eq!(
+                mapper
+                    .map_range(code, PhysicalAddress::new(0), 2 * A::PAGE_SIZE, wx)
+                    .err()
+                    .map(|partial| (partial.done, partial.error)),
+                Some((0, MapError::WriteExecute))
+            );
+            assert!(mapper.translate(code).is_none());
+
+            // Executable pages cannot be made writable
+            mapper
+                .map(code, base, flags)
+                .expect("failed to map page")
+                .flush();
+            assert_eq!(
+                mapper.remap(code, base, wx).err(),
+                Some(MapError::WriteExecute)
+            );
+            assert_eq!(
+                mapper
+                    .protect_range(code, A::PAGE_SIZE, wx)
+                    .err()
+                    .map(|partial| partial.error),
+                Some(MapError::WriteExecute)
+            );
+            let (_, mapped, _) = mapper.translate(code).expect("failed to translate page");
+            assert!(!mapped.has_write());
+
+            // Writable pages are allowed where the architecture can prevent execution
+            if A::ENTRY_FLAG_NO_EXEC | A::ENTRY_FLAG_EXEC != 0 {
+                let data = jit.add(A::PAGE_SIZE);
+                mapper
+                    .map(data, base, PageFlags::new().user(true).write(true))
+                    .expect("failed to map page")
+                    .flush();
+            }
+
+            // The escape hatch maps a JIT page, which the scanner reports
+            mapper
+                .allow_write_execute(|mapper| mapper.map(jit, base, wx))
+                .expect("failed to map JIT page")
+                .flush();
+            assert!(mapper.write_xor_execute());
+            let found = mapper
+                .write_execute_mappings()
+                .filter(|mapping| A::table_kind(mapping.virt) == TableKind::User)
+                .map(|mapping| (mapping.virt, mapping.len))
+                .collect::<Vec<_>>();
+            assert_eq!(found, [(jit, A::PAGE_SIZE)]);
+            EmulateArch::<A>::write::<u8>(jit, 0xC3);
+            assert_eq!(
+                EmulateArch::<A>::try_fetch::<u8>(jit, Privilege::User),
+                Ok(0xC3)
+            );
+        }
+    }
+
+    unsafe fn huge_pages<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let base = PageSize::base();
+
+            for size in PageSize::<EmulateArch<A>>::all().filter(|size| size.is_huge()) {
+                assert_eq!(PageSize::from_bytes(size.bytes()), Some(size));
+
+                // Map the start of memory, accessing it inside the offset mapped frames
+                let virt = VirtualAddress::new(2 * size.bytes());
+                let phys = PhysicalAddress::new(0);
+                let offset = A::PAGE_SIZE * A::PAGE_ENTRIES / 2 + 8;
+                assert_eq!(
+                    mapper
+                        .map_phys(virt.add(A::PAGE_SIZE), phys, size, PageFlags::new())
+                        .err(),
+                    Some(MapError::Misaligned)
+                );
+                mapper
+                    .map_phys(virt, phys, size, PageFlags::new().write(true))
+                    .expect("failed to map huge page")
+                    .flush();
+                let (inside, flags, mapped) = mapper
+                    .translate(virt.add(offset))
+                    .expect("failed to translate huge page");
+                assert_eq!(inside, phys.add(offset));
+                assert_eq!(mapped, size);
+                assert!(flags.has_write());
+                assert_eq!(flags.data() & A::ENTRY_FLAG_HUGE, 0);
+
+                EmulateArch::<A>::write::<u64>(virt.add(offset), 0xDEAD_BEEF);
+                let alias = EmulateArch::<A>::phys_to_virt(phys.add(offset));
+                assert_eq!(EmulateArch::<A>::read::<u64>(alias), 0xDEAD_BEEF);
+
+                // Smaller pages cannot be mapped over a huge page
+                assert_eq!(
+                    mapper
+                        .map_phys(virt.add(A::PAGE_SIZE), phys, base, PageFlags::new())
+                        .err(),
+                    Some(MapError::AlreadyMapped)
+                );
+
+                mapper
+                    .remap(virt, size, PageFlags::new())
+                    .expect("failed to remap huge page")
+                    .flush();
+                let (_, flags, _) = mapper.translate(virt).expect("failed to translate");
+                assert!(!flags.has_write());
+                assert_eq!(
+                    EmulateArch::<A>::try_write::<u8>(virt.add(offset), 0, Privilege::Kernel)
+                        .unwrap_err()
+                        .reason,
+                    PageFaultReason::NotWritable
+                );
+
+                let (old_phys, _, flush) = mapper
+                    .unmap_phys(virt, size, false)
+                    .expect("failed to unmap huge page");
+                flush.flush();
+                assert_eq!(old_phys, phys);
+                assert!(mapper.translate(virt.add(offset)).is_none());
+
+                // Base pages can be mapped again once the huge page is gone
+                mapper
+                    .map(virt.add(A::PAGE_SIZE), base, PageFlags::new())
+                    .expect("failed to map page")
+                    .flush();
+                assert_eq!(
+                    mapper
+                        .translate(virt.add(A::PAGE_SIZE))
+                        .map(|(_, _, size)| size),
+                    Some(base)
+                );
+
+                // Huge pages cannot replace the table just created
+                assert_eq!(
+                    mapper.map_phys(virt, phys, size, PageFlags::new()).err(),
+                    Some(MapError::AlreadyMapped)
+                );
+            }
+        }
+    }
+
+    unsafe fn split_merge<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let base = PageSize::base();
+
+            // Mapping a full table of contiguous pages keeps them apart, without freeing anything
+            let huge = PageSize::<EmulateArch<A>>::from_level(1).expect("no huge pages");
+            let mut bump = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper = PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut bump);
+            let virt = VirtualAddress::new(6 * huge.bytes());
+            for i in 0..A::PAGE_ENTRIES {
+                mapper
+                    .map_phys(
+                        virt.add(i * A::PAGE_SIZE),
+                        PhysicalAddress::new(i * A::PAGE_SIZE),
+                        base,
+                        PageFlags::new(),
+                    )
+                    .expect("failed to map page")
+                    .flush();
+            }
+            let (_, _, size) = mapper.translate(virt).expect("failed to translate page");
+            assert_eq!(size, base);
+            mapper
+                .unmap_phys_range(virt, huge.bytes(), false)
+                .unwrap_or_else(|partial| panic!("failed to unmap range: {}", partial.error))
+                .flush();
+
+            // Merging frees tables, which the bump allocator cannot do
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(bump)
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+
+            for size in PageSize::<EmulateArch<A>>::all().filter(|size| size.is_huge()) {
+                let virt = VirtualAddress::new(2 * size.bytes());
+                let phys = PhysicalAddress::new(0);
+                // Pages accessed are in the offset mapped frames, away from the initial tables
+                let page = virt.add(A::PAGE_SIZE * A::PAGE_ENTRIES / 2);
+                let offset = A::PAGE_SIZE * A::PAGE_ENTRIES / 2 + A::PAGE_SIZE + 8;
+                mapper
+                    .map_phys(virt, phys, size, PageFlags::new().write(true))
+                    .expect("failed to map huge page")
+                    .flush();
+                EmulateArch::<A>::write::<u64>(virt.add(offset), 0xDEAD_BEEF);
+
+                // Remapping a page inside a huge page splits it, keeping the rest mapped
+                let flush = mapper
+                    .remap(page, base, PageFlags::new())
+                    .expect("failed to remap page");
+                assert_eq!(flush.virt(), virt);
+                assert_eq!(flush.size(), size.bytes());
+                flush.flush();
+                let (page_phys, flags, page_size) =
+                    mapper.translate(page).expect("failed to translate page");
+                assert_eq!(page_phys, phys.add(page.data() - virt.data()));
+                assert!(!flags.has_write());
+                assert_eq!(page_size, base);
+                let (inside, flags, _) = mapper
+                    .translate(virt.add(offset))
+                    .expect("failed to translate split page");
+                assert_eq!(inside, phys.add(offset));
+                assert!(flags.has_write());
+                assert_eq!(EmulateArch::<A>::read::<u64>(virt.add(offset)), 0xDEAD_BEEF);
+                assert_eq!(
+                    EmulateArch::<A>::try_write::<u8>(page, 0, Privilege::Kernel)
+                        .unwrap_err()
+                        .reason,
+                    PageFaultReason::NotWritable
+                );
+
+                // Restoring the flags lets all the split tables merge back
+                mapper
+                    .remap(page, base, PageFlags::new().write(true))
+                    .expect("failed to remap page")
+                    .flush();
+                let (_, _, split) = mapper.translate(page).expect("failed to translate page");
+                assert_eq!(split, base);
+                let flush = mapper.merge(page, base).expect("failed to merge tables");
+                assert_eq!(flush.size(), size.bytes());
+                flush.flush();
+                let (_, _, merged) = mapper.translate(page).expect("failed to translate page");
+                assert_eq!(merged, size);
+                assert!(mapper.merge(page, base).is_none());
+                EmulateArch::<A>::write::<u8>(page, 0);
+
+                // A remap refused after splitting still flushes the split page
+                mapper = mapper.with_write_xor_execute(true);
+                assert_eq!(
+                    mapper
+                        .remap(page, base, PageFlags::new().write(true).execute(true))
+                        .err(),
+                    Some(MapError::WriteExecute)
+                );
+                mapper = mapper.with_write_xor_execute(false);
+                let (_, _, split) = mapper.translate(page).expect("failed to translate page");
+                assert_eq!(split, base);
+                assert_eq!(EmulateArch::<A>::read::<u8>(page), 0);
+
+                // Unmapping a page splits too, and mapping it back allows merging
+                let (old_phys, _, flush) = mapper
+                    .unmap_phys(page, base, false)
+                    .expect("failed to unmap page");
+                flush.flush();
+                assert_eq!(old_phys, page_phys);
+                assert!(mapper.translate(page).is_none());
+                assert_eq!(EmulateArch::<A>::read::<u64>(virt.add(offset)), 0xDEAD_BEEF);
+                mapper
+                    .map_phys(page, old_phys, base, PageFlags::new().write(true))
+                    .expect("failed to map page")
+                    .flush();
+                mapper
+                    .merge(page, base)
+                    .expect("failed to merge tables")
+                    .flush();
+                let (_, _, merged) = mapper.translate(page).expect("failed to translate page");
+                assert_eq!(merged, size);
+
+                mapper
+                    .unmap_phys(virt, size, false)
+                    .expect("failed to unmap huge page")
+                    .2
+                    .flush();
+            }
+        }
+    }
+
+    unsafe fn entry_count<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let huge = PageSize::<EmulateArch<A>>::from_level(1).expect("no huge pages");
+            let base = PageSize::base();
+            // Tables from the root to `level` on the way to `virt`, checking their count
+            let tables = |mapper: &PageMapper<EmulateArch<A>, _>, virt, level| {
+                let mut tables = vec![mapper.table()];
+                while let Some(table) = tables.last().filter(|table| table.level() > level) {
+                    let i = table.index_of(virt).expect("invalid address");
+                    tables.push(table.next(i).expect("missing table"));
+                }
+                for table in &tables {
+                    let scanned = (0..A::PAGE_ENTRIES)
+                        .filter(|&i| table.entry(i).is_some_and(|entry| entry.data() != 0))
+                        .count();
+                    assert_eq!(table.entry_count(), scanned);
+                    assert_eq!(table.is_empty(), scanned == 0);
+                }
+                tables
+            };
+
+            // Keeps the tables above the ones emptied below
+            mapper
+                .map(
+                    VirtualAddress::new(5 * huge.bytes()),
+                    base,
+                    PageFlags::new(),
+                )
+                .expect("failed to map page")
+                .flush();
+            let virt = VirtualAddress::new(4 * huge.bytes());
+            for page in [0, 1, 10] {
+                mapper
+                    .map(virt.add(page * A::PAGE_SIZE), base, PageFlags::new())
+                    .expect("failed to map page")
+                    .flush();
+            }
+            let path = tables(&mapper, virt, 0);
+            assert_eq!(path.last().map(|table| table.entry_count()), Some(3));
+            let parent = path[path.len() - 2].entry_count();
+
+            // Unmapping the last page frees its table
+            for page in [1, 10, 0] {
+                let flush = mapper
+                    .unmap(virt.add(page * A::PAGE_SIZE), base, true)
+                    .expect("failed to unmap page");
+                flush.flush();
+            }
+            assert!(mapper.translate(virt).is_none());
+            let path = tables(&mapper, virt, 1);
+            assert_eq!(
+                path.last().map(|table| table.entry_count()),
+                Some(parent - 1)
+            );
+
+            // Split tables are full, and emptied by range teardown
+            let virt = VirtualAddress::new(6 * huge.bytes());
+            mapper
+                .map_phys(virt, PhysicalAddress::new(0), huge, PageFlags::new())
+                .expect("failed to map huge page")
+                .flush();
+            let parent = tables(&mapper, virt, 1)
+                .last()
+                .map(|table| table.entry_count());
+            mapper
+                .remap(virt.add(A::PAGE_SIZE), base, PageFlags::new().write(true))
+                .expect("failed to remap page")
+                .flush();
+            let path = tables(&mapper, virt, 0);
+            assert_eq!(
+                path.last().map(|table| table.entry_count()),
+                Some(A::PAGE_ENTRIES)
+            );
+            mapper
+                .unmap_phys_range(virt, huge.bytes() / 2, true)
+                .unwrap_or_else(|partial| panic!("failed to unmap range: {}", partial.error))
+                .flush();
+            let path = tables(&mapper, virt.add(huge.bytes() / 2), 0);
+            assert_eq!(
+                path.last().map(|table| table.entry_count()),
+                Some(A::PAGE_ENTRIES / 2)
+            );
+            mapper
+                .unmap_phys_range(virt, huge.bytes(), true)
+                .unwrap_or_else(|partial| panic!("failed to unmap range: {}", partial.error))
+                .flush();
+            assert_eq!(
+                tables(&mapper, virt, 1)
+                    .last()
+                    .map(|table| table.entry_count()),
+                parent.map(|count| count - 1)
+            );
+        }
+    }
+
+    unsafe fn reserve<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            // Pages below stay in the same table, which the bump allocator could not free
+            let virt = VirtualAddress::new(12 * MEGABYTE + 4 * A::PAGE_SIZE);
+            let size = 4 * A::PAGE_SIZE;
+            let flags = PageFlags::new().user(true).write(true);
+            assert_eq!(
+                mapper.reserve_range(virt.add(1), size, flags),
+                Err(MapError::Misaligned)
+            );
+            mapper
+                .reserve_range(virt, size, flags)
+                .expect("failed to reserve range");
+            let reserved = mapper.reservation(virt.add(1)).expect("page not reserved");
+            assert_eq!(reserved.data(), flags.data());
+            assert!(mapper.reservation(virt.add(size)).is_none());
+            assert!(mapper.translate(virt).is_none());
+            // Reservations are only replaced when resolved
+            assert_eq!(
+                mapper
+                    .map_phys(virt, PhysicalAddress::new(0), PageSize::base(), flags)
+                    .err(),
+                Some(MapError::AlreadyMapped)
+            );
+            assert_eq!(
+                mapper
+                    .map_range(virt, PhysicalAddress::new(0), size, flags)
+                    .err()
+                    .map(|partial| partial.error),
+                Some(MapError::AlreadyMapped)
+            );
+            assert_eq!(
+                mapper.reserve_range(virt, size, flags),
+                Err(MapError::AlreadyMapped)
+            );
+            assert!(mapper.reservation(virt.add(size - 1)).is_some());
+            // Failing undoes only the pages reserved by that call
+            let below = VirtualAddress::new(virt.data() - 2 * A::PAGE_SIZE);
+            assert_eq!(
+                mapper.reserve_range(below, 3 * A::PAGE_SIZE, flags),
+                Err(MapError::AlreadyMapped)
+            );
+            assert!(mapper.reservation(below).is_none());
+            assert!(mapper.reservation(virt).is_some());
+            mapper
+                .resolve_reserved(virt)
+                .expect("failed to resolve reserved page")
+                .expect("page not reserved")
+                .flush();
+            assert!(mapper.reservation(virt).is_none());
+
+            // Frames come from a separate allocator on first touch
+            let mut demand = PageMapper::<EmulateArch<A>, _>::current(
+                TableKind::User,
+                BumpAllocator::<EmulateArch<A>>::new(areas, MEGABYTE / 2),
+            );
+            EmulateArch::<A>::set_fault_handler(move |fault| {
+                match demand.resolve_reserved(fault.address) {
+                    Ok(Some(flush)) => {
+                        flush.flush();
+                        true
+                    }
+                    Ok(None) | Err(_) => false,
+                }
+            });
+            let second = virt.add(A::PAGE_SIZE);
+            assert_eq!(
+                EmulateArch::<A>::try_read::<u64>(second, Privilege::User),
+                Ok(0)
+            );
+            EmulateArch::<A>::try_write::<u64>(second.add(8), 42, Privilege::User)
+                .expect("failed to write reserved page");
+            assert_eq!(EmulateArch::<A>::read::<u64>(second.add(8)), 42);
+            let (_, resolved, _) = mapper.translate(second).expect("page not mapped");
+            assert!(resolved.has_user() && resolved.has_write());
+            assert!(mapper.reservation(second).is_none());
+            let fault =
+                EmulateArch::<A>::try_read::<u8>(virt.add(size), Privilege::User).unwrap_err();
+            assert_eq!(fault.reason, PageFaultReason::NotPresent);
+
+            // Unmapping drops the reservations left
+            mapper
+                .unmap_phys_range(second, 3 * A::PAGE_SIZE, false)
+                .unwrap_or_else(|partial| panic!("failed to unmap range: {}", partial.error))
+                .flush();
+            assert!(mapper.reservation(virt.add(2 * A::PAGE_SIZE)).is_none());
+            let fault =
+                EmulateArch::<A>::try_read::<u8>(virt.add(2 * A::PAGE_SIZE), Privilege::User)
+                    .unwrap_err();
+            assert_eq!(fault.reason, PageFaultReason::NotPresent);
+            EmulateArch::<A>::clear_fault_handler();
+        }
+    }
+
+    unsafe fn stacks<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let page = A::PAGE_SIZE;
+            let window = VirtualAddress::new(24 * MEGABYTE);
+            let window_size = 18 * page;
+            let map_stack = |mapper: &mut PageMapper<EmulateArch<A>, _>| {
+                mapper
+                    .map_stack(window, window_size, 4)
+                    .map(|(stack, flush)| {
+                        flush.flush();
+                        stack
+                    })
+            };
+
+            let first = map_stack(&mut mapper).expect("failed to map stack");
+            assert_eq!(first.bottom(), window.add(page));
+            assert_eq!(first.top(), window.add(5 * page));
+            // Each stack has its own guard pages, which nothing else can be mapped to
+            let second = map_stack(&mut mapper).expect("failed to map stack");
+            assert_eq!(second.bottom(), first.top().add(2 * page));
+            assert_eq!(
+                mapper
+                    .map(first.top(), PageSize::base(), PageFlags::new())
+                    .err(),
+                Some(MapError::AlreadyMapped)
+            );
+            assert_eq!(
+                mapper
+                    .map_range(first.top(), PhysicalAddress::new(0), page, PageFlags::new())
+                    .err()
+                    .map(|partial| partial.error),
+                Some(MapError::AlreadyMapped)
+            );
+            assert!(mapper.reservation(first.top()).is_none());
+            let third = map_stack(&mut mapper).expect("failed to map stack");
+            assert_eq!(third.top(), window.add(window_size - page));
+            assert_eq!(map_stack(&mut mapper).err(), Some(RegionError::NoSpace));
+
+            // Stacks start zeroed, with faults on either side
+            let top = VirtualAddress::new(second.top().data() - 8);
+            assert_eq!(EmulateArch::<A>::read::<u64>(top), 0);
+            EmulateArch::<A>::write::<u64>(top, 1);
+            EmulateArch::<A>::write::<u64>(second.bottom(), 2);
+            for guard in [
+                VirtualAddress::new(second.bottom().data() - 8),
+                second.top(),
+            ] {
+                assert!(second.is_guard(guard));
+                let fault =
+                    EmulateArch::<A>::try_write::<u64>(guard, 0, Privilege::Kernel).unwrap_err();
+                assert_eq!(fault.reason, PageFaultReason::NotPresent);
+            }
+            assert!(!second.is_guard(top));
+            assert!(!second.is_guard(first.bottom()));
+
+            // Frames go back to the allocator, and the space to later stacks
+            let used = mapper.allocator().usage().used().data();
+            mapper
+                .unmap_stack(second)
+                .unwrap_or_else(|partial| panic!("failed to unmap stack: {}", partial.error))
+                .flush();
+            assert_eq!(mapper.allocator().usage().used().data(), used - 4);
+            let again = map_stack(&mut mapper).expect("failed to map stack");
+            assert_eq!(again.bottom(), first.top().add(2 * page));
+            assert_eq!(EmulateArch::<A>::read::<u64>(top), 0);
+            assert_eq!(
+                mapper.map_stack(window.add(1), window_size, 4).err(),
+                Some(RegionError::Misaligned)
+            );
+            assert_eq!(
+                mapper.map_stack(window, window_size, 0).err(),
+                Some(RegionError::InvalidSize)
+            );
+
+            // Runs of free pages continue across tables, some of them missing
+            let boundary = VirtualAddress::new(32 * MEGABYTE);
+            let below = VirtualAddress::new(boundary.data() - 4 * page);
+            mapper
+                .map(below.add(page), PageSize::base(), PageFlags::new())
+                .expect("failed to map page")
+                .flush();
+            let (stack, flush) = mapper
+                .map_stack(below, 256 * MEGABYTE, 4)
+                .expect("failed to map stack");
+            flush.flush();
+            assert_eq!(stack.bottom(), below.add(3 * page));
+            assert_eq!(stack.top(), boundary.add(3 * page));
+        }
+    }
+
+    unsafe fn ranges<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let base = PageSize::base();
+            let huge = PageSize::<EmulateArch<A>>::from_level(1).expect("no huge pages");
+            let virt = VirtualAddress::new(4 * huge.bytes());
+            let phys = PhysicalAddress::new(0);
+
+            // A range ending past a huge page uses it, and base pages for the rest
+            let len = huge.bytes() + 3 * A::PAGE_SIZE;
+            let flush = mapper
+                .map_range(virt, phys, len, PageFlags::new().write(true))
+                .unwrap_or_else(|_| panic!("failed to map range"));
+            assert_eq!(flush.virt(), virt);
+            assert_eq!(flush.size(), len);
+            // Too many pages to invalidate one at a time
+            let (pages, all) = EmulateArch::<A>::invalidations();
+            flush.flush();
+            assert_eq!(EmulateArch::<A>::invalidations(), (pages, all + 1));
+            let (_, _, size) = mapper.translate(virt).expect("failed to translate");
+            assert_eq!(size, huge);
+            let (tail, _, size) = mapper
+                .translate(virt.add(huge.bytes() + 2 * A::PAGE_SIZE))
+                .expect("failed to translate");
+            assert_eq!(tail, phys.add(huge.bytes() + 2 * A::PAGE_SIZE));
+            assert_eq!(size, base);
+            assert!(mapper.translate(virt.add(len)).is_none());
+
+            // Protecting a range crossing the huge page splits it
+            let page = virt.add(A::PAGE_SIZE);
+            mapper
+                .protect_range(page, huge.bytes(), PageFlags::new())
+                .unwrap_or_else(|_| panic!("failed to protect range"))
+                .flush();
+            let (_, flags, size) = mapper.translate(virt).expect("failed to translate");
+            assert!(flags.has_write());
+            assert_eq!(size, base);
+            for addr in [page, virt.add(huge.bytes())] {
+                let (_, flags, _) = mapper.translate(addr).expect("failed to translate");
+                assert!(!flags.has_write());
+                assert_eq!(
+                    EmulateArch::<A>::try_write::<u8>(addr, 0, Privilege::Kernel)
+                        .unwrap_err()
+                        .reason,
+                    PageFaultReason::NotWritable
+                );
+            }
+            let (_, flags, _) = mapper
+                .translate(virt.add(huge.bytes() + A::PAGE_SIZE))
+                .expect("failed to translate");
+            assert!(flags.has_write());
+
+            // Protecting unmapped memory stops at the hole
+            let hole = virt.add(len - A::PAGE_SIZE);
+            let Err(partial) = mapper.protect_range(hole, 2 * A::PAGE_SIZE, PageFlags::new())
+            else {
+                panic!("protected a hole");
+            };
+            assert_eq!(partial.done, A::PAGE_SIZE);
+            assert_eq!(partial.error, MapError::NotMapped);
+            partial.flush.flush();
+
+            // Unmapping the whole range removes every page, and the tables with them
+            mapper
+                .unmap_phys_range(virt, len, true)
+                .unwrap_or_else(|_| panic!("failed to unmap range"))
+                .flush();
+            assert!(mapper.translate(virt).is_none());
+            assert!(mapper.translate(virt.add(huge.bytes())).is_none());
+            assert_eq!(
+                mapper.unmap_phys(virt, base, false).err(),
+                Some(UnmapError::NotMapped)
+            );
+
+            // A huge page in the way of unaligned memory stops the mapping before it
+            mapper
+                .map_phys(virt.add(huge.bytes()), phys, huge, PageFlags::new())
+                .expect("failed to map huge page")
+                .flush();
+            let Err(partial) = mapper.map_range(
+                virt,
+                phys.add(A::PAGE_SIZE),
+                2 * huge.bytes(),
+                PageFlags::new(),
+            ) else {
+                panic!("mapped over a huge page");
+            };
+            assert_eq!(partial.done, huge.bytes());
+            assert_eq!(partial.error, MapError::AlreadyMapped);
+            assert_eq!(partial.flush.size(), huge.bytes());
+            partial.flush.flush();
+
+            // Nothing done leaves an empty flush, which combines with others
+            let Err(partial) =
+                mapper.map_range(virt.add(huge.bytes()), phys, huge.bytes(), PageFlags::new())
+            else {
+                panic!("mapped over a huge page");
+            };
+            assert_eq!(partial.done, 0);
+            let flush = partial.flush.combine(PageFlush::new(virt));
+            assert_eq!((flush.virt(), flush.size()), (virt, A::PAGE_SIZE));
+            flush.flush();
+            assert_eq!(
+                mapper
+                    .map_range(virt, phys, 0, PageFlags::new())
+                    .err()
+                    .map(|p| p.error),
+                Some(MapError::InvalidSize)
+            );
+            assert_eq!(
+                mapper.unmap_range(virt, 0, false).err().map(|p| p.error),
+                Some(UnmapError::InvalidSize)
+            );
+            assert_eq!(
+                mapper
+                    .protect_range(virt, 0, PageFlags::new())
+                    .err()
+                    .map(|p| p.error),
+                Some(MapError::InvalidSize)
+            );
+            let (_, _, size) = mapper
+                .translate(virt.add(huge.bytes()))
+                .expect("failed to translate");
+            assert_eq!(size, huge);
+        }
+    }
+
+    unsafe fn fork<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            // Frames are reference counted by the buddy allocator
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let base = PageSize::base();
+            let parent = mapper.table().phys();
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            let read_only = virt.add(A::PAGE_SIZE);
+            mapper
+                .map(virt, base, PageFlags::new().write(true))
+                .expect("failed to map page")
+                .flush();
+            mapper
+                .map(read_only, base, PageFlags::new())
+                .expect("failed to map page")
+                .flush();
+            EmulateArch::<A>::write::<u64>(virt, 1);
+
+            // A frame the allocator cannot share fails the fork, leaving the parent as it was
+            let unshared = virt.add(2 * A::PAGE_SIZE);
+            mapper
+                .map_phys(unshared, areas[0].base, base, PageFlags::new())
+                .expect("failed to map page")
+                .flush();
+            let used = mapper.allocator().usage().used().data();
+            let mut flusher = PageFlushAll::new();
+            assert_eq!(mapper.fork(&mut flusher), Err(MapError::NotShareable));
+            flusher.flush();
+            assert_eq!(mapper.allocator().usage().used().data(), used);
+            let (phys, flags, _) = mapper.translate(virt).expect("failed to translate page");
+            assert!(flags.has_write() && !flags.is_copy_on_write());
+            assert_eq!(mapper.allocator().refcount(phys), Some(1));
+            let (_, _, flush) = mapper
+                .unmap_phys(unshared, base, false)
+                .expect("failed to unmap page");
+            flush.flush();
+
+            // Both tables share the frames, with writable pages made read-only
+            let child = mapper
+                .fork(&mut PageFlushAll::new())
+                .expect("failed to fork");
+            let (phys, flags, _) = mapper.translate(virt).expect("failed to translate page");
+            assert!(!flags.has_write());
+            assert!(flags.is_copy_on_write());
+            assert_eq!(mapper.allocator().refcount(phys), Some(2));
+            let (read_only_phys, flags, _) = mapper
+                .translate(read_only)
+                .expect("failed to translate page");
+            assert!(!flags.is_copy_on_write());
+            assert_eq!(mapper.allocator().refcount(read_only_phys), Some(2));
+            assert_eq!(
+                mapper
+                    .resolve_copy_on_write(read_only)
+                    .map(|flush| flush.is_some()),
+                Ok(false)
+            );
+            assert_eq!(
+                EmulateArch::<A>::try_write::<u64>(virt, 2, Privilege::Kernel)
+                    .unwrap_err()
+                    .reason,
+                PageFaultReason::NotWritable
+            );
+
+            // Protecting a shared page keeps it copy-on-write, with its other state
+            mapper
+                .protect_range(
+                    virt,
+                    A::PAGE_SIZE,
+                    PageFlags::new().write(true).user(true).software(1),
+                )
+                .unwrap_or_else(|_| panic!("failed to protect page"))
+                .flush();
+            let (_, flags, _) = mapper.translate(virt).expect("failed to translate page");
+            assert!(!flags.has_write() && flags.has_user());
+            assert!(flags.is_copy_on_write());
+            assert!(flags.is_accessed());
+            assert_eq!(flags.get_software(), 0);
+            assert_eq!(
+                EmulateArch::<A>::try_write::<u64>(virt, 2, Privilege::Kernel)
+                    .unwrap_err()
+                    .reason,
+                PageFaultReason::NotWritable
+            );
+            mapper
+                .protect_range(virt, A::PAGE_SIZE, PageFlags::new().write(true))
+                .unwrap_or_else(|_| panic!("failed to protect page"))
+                .flush();
+
+            // The first write copies the frame, leaving the other table its only user
+            mapper
+                .resolve_copy_on_write(virt)
+                .expect("failed to resolve copy on write")
+                .expect("page is not copy on write")
+                .flush();
+            let (copy, flags, _) = mapper.translate(virt).expect("failed to translate page");
+            assert_ne!(copy, phys);
+            assert!(flags.has_write());
+            assert!(!flags.is_copy_on_write());
+            assert_eq!(mapper.allocator().refcount(phys), Some(1));
+            assert_eq!(EmulateArch::<A>::read::<u64>(virt), 1);
+            EmulateArch::<A>::write::<u64>(virt, 2);
+
+            // The last write makes the page writable in place
+            EmulateArch::<A>::set_table(TableKind::User, child);
+            let mut child_mapper = PageMapper::<EmulateArch<A>, _>::new(
+                TableKind::User,
+                child,
+                mapper.allocator_mut(),
+            );
+            assert_eq!(EmulateArch::<A>::read::<u64>(virt), 1);
+            child_mapper
+                .resolve_copy_on_write(virt)
+                .expect("failed to resolve copy on write")
+                .expect("page is not copy on write")
+                .flush();
+            let (child_phys, flags, _) = child_mapper
+                .translate(virt)
+                .expect("failed to translate page");
+            assert_eq!(child_phys, phys);
+            assert!(flags.has_write());
+            EmulateArch::<A>::write::<u64>(virt, 3);
+            EmulateArch::<A>::set_table(TableKind::User, parent);
+            assert_eq!(EmulateArch::<A>::read::<u64>(virt), 2);
+        }
+    }
+
+    unsafe fn fork_clean<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let parent = mapper.table().phys();
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            // A clean page is read-only until written with a dirty bit modifier, like on AArch64
+            mapper
+                .map(
+                    virt,
+                    PageSize::base(),
+                    PageFlags::new().write(true).dirty(false),
+                )
+                .expect("failed to map page")
+                .flush();
+
+            // Forking must not leave the modifier, which would let either side write the frame
+            let child = mapper
+                .fork(&mut PageFlushAll::new())
+                .expect("failed to fork");
+            for table in [parent, child] {
+                EmulateArch::<A>::set_table(TableKind::User, table);
+                let mapper = PageMapper::<EmulateArch<A>, _>::new(
+                    TableKind::User,
+                    table,
+                    mapper.allocator_mut(),
+                );
+                let (_, flags, _) = mapper.translate(virt).expect("failed to translate page");
+                assert!(flags.is_copy_on_write());
+                assert!(
+                    A::ENTRY_FLAG_DIRTY_BIT_MODIFIER == 0
+                        || !flags.has_flag(A::ENTRY_FLAG_DIRTY_BIT_MODIFIER)
+                );
+                assert_eq!(
+                    EmulateArch::<A>::try_write::<u64>(virt, 1, Privilege::Kernel)
+                        .unwrap_err()
+                        .reason,
+                    PageFaultReason::NotWritable
+                );
+            }
+            EmulateArch::<A>::set_table(TableKind::User, parent);
+        }
+    }
+
+    unsafe fn shared_kernel<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let base = PageSize::base();
+            let kernel = EmulateArch::<A>::table(TableKind::Kernel);
+            let offset_mapped = EmulateArch::<A>::phys_to_virt(PhysicalAddress::new(0));
+            let mut user_mapper = PageMapper::<EmulateArch<A>, _>::create(
+                TableKind::User,
+                Some(kernel),
+                &mut allocator,
+            )
+            .expect("failed to create table");
+
+            // User tables have no kernel half with split tables
+            let root = user_mapper.table();
+            let i = root.index_of(offset_mapped).expect("failed to index root");
+            let kernel_half = root.entry(i).expect("failed to read entry").present();
+            assert_eq!(kernel_half, !A::SPLIT_TABLES);
+            if A::SPLIT_TABLES {
+                return;
+            }
+            user_mapper.make_current();
+            EmulateArch::<A>::read::<u8>(offset_mapped);
+
+            // Tables added to the template are seen once synchronized
+            let root_span = 1 << ((A::PAGE_LEVELS - 1) * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT);
+            let top =
+                canonical::<EmulateArch<A>>(VirtualAddress::new((A::PAGE_ENTRIES - 1) * root_span));
+            let mut kernel_mapper = PageMapper::<EmulateArch<A>, _>::new(
+                TableKind::Kernel,
+                kernel,
+                user_mapper.allocator_mut(),
+            );
+            kernel_mapper
+                .map(top, base, PageFlags::new().write(true))
+                .expect("failed to map page")
+                .flush();
+            assert!(EmulateArch::<A>::try_read::<u64>(top, Privilege::Kernel).is_err());
+            user_mapper.sync_kernel();
+            EmulateArch::<A>::write::<u64>(top, 0xDEAD_BEEF);
+
+            // Unmapping never frees shared tables
+            let mut kernel_mapper = PageMapper::<EmulateArch<A>, _>::new(
+                TableKind::Kernel,
+                kernel,
+                user_mapper.allocator_mut(),
+            );
+            kernel_mapper
+                .unmap(top, base, true)
+                .expect("failed to unmap page")
+                .flush();
+            let i = root.index_of(top).expect("failed to index root");
+            for root in [root, kernel_mapper.table()] {
+                assert!(root.entry(i).expect("failed to read entry").present());
+            }
+            EmulateArch::<A>::set_table(TableKind::Kernel, kernel);
+        }
+    }
+
+    unsafe fn halves<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let base = PageSize::base();
+            // Split tables translate the whole address width each, others half of it. The start of
+            // the kernel half is the offset mapping without split tables, so its end is used.
+            let size = A::PAGE_ADDRESS_SIZE as usize;
+            let (user, kernel) = if A::SPLIT_TABLES {
+                (size - A::PAGE_SIZE, A::PAGE_NEGATIVE_MASK)
+            } else {
+                (size / 2 - A::PAGE_SIZE, size - A::PAGE_SIZE)
+            };
+            let user = VirtualAddress::new(user);
+            let kernel = canonical::<EmulateArch<A>>(VirtualAddress::new(kernel));
+            assert_eq!(EmulateArch::<A>::table_kind(user), TableKind::User);
+            assert_eq!(EmulateArch::<A>::table_kind(kernel), TableKind::Kernel);
+
+            for (table_kind, virt, other) in [
+                (TableKind::User, user, kernel),
+                (TableKind::Kernel, kernel, user),
+            ] {
+                let table = EmulateArch::<A>::table(table_kind);
+                let mut mapper =
+                    PageMapper::<EmulateArch<A>, _>::new(table_kind, table, &mut allocator);
+                assert_eq!(
+                    mapper.map(other, base, PageFlags::new()).err(),
+                    Some(MapError::InvalidAddress)
+                );
+                mapper
+                    .map(virt, base, PageFlags::new().write(true))
+                    .expect("failed to map page")
+                    .flush();
+                EmulateArch::<A>::write::<u64>(virt, 0xDEAD_BEEF);
+                let (phys, _, _) = mapper.translate(virt).expect("failed to translate page");
+                assert_eq!(
+                    EmulateArch::<A>::read::<u64>(EmulateArch::<A>::phys_to_virt(phys)),
+                    0xDEAD_BEEF
+                );
+                // Walks report the address the page is mapped at, whatever the table it is in
+                assert!(mapper.mappings().any(|mapping| mapping.virt == virt));
+                mapper
+                    .unmap(virt, base, true)
+                    .expect("failed to unmap page")
+                    .flush();
+            }
+        }
+    }
+
+    unsafe fn test_and_clear<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let clean = PageFlags::new().write(true).dirty(false).accessed(false);
+            assert!(!clean.is_accessed());
+            assert!(!clean.is_dirty());
+            assert!(clean.dirty(true).is_dirty());
+            assert!(clean.dirty(true).has_write());
+            if A::ENTRY_FLAG_DIRTY == 0 {
+                assert!(!PageFlags::<A>::new().dirty(true).is_dirty());
+            }
+
+            let virt = VirtualAddress::new(4 * MEGABYTE);
+            let next = virt.add(A::PAGE_SIZE);
+            for page in [virt, next] {
+                mapper
+                    .map(page, PageSize::base(), clean)
+                    .expect("failed to map page")
+                    .flush();
+            }
+            assert!(mapper.test_and_clear_accessed(virt).unwrap().is_none());
+            assert_eq!(
+                mapper
+                    .test_and_clear_dirty(virt.add(3 * A::PAGE_SIZE))
+                    .err(),
+                Some(MapError::NotMapped)
+            );
+
+            // Single pages
+            EmulateArch::<A>::read::<u8>(virt);
+            mapper
+                .test_and_clear_accessed(virt)
+                .unwrap()
+                .expect("page not accessed")
+                .flush();
+            assert!(mapper.test_and_clear_accessed(virt).unwrap().is_none());
+            EmulateArch::<A>::write::<u8>(virt, 1);
+            mapper
+                .test_and_clear_dirty(virt)
+                .unwrap()
+                .expect("page not dirty")
+                .flush();
+            assert!(mapper.test_and_clear_dirty(virt).unwrap().is_none());
+            EmulateArch::<A>::write::<u8>(virt, 2);
+            let (_, flags, _) = mapper.translate(virt).expect("failed to translate page");
+            assert!(flags.is_dirty());
+
+            // Ranges report the pages that had the flag, skipping holes
+            let range = 4 * A::PAGE_SIZE;
+            EmulateArch::<A>::write::<u8>(next, 3);
+            let mut dirty = Vec::new();
+            mapper
+                .test_and_clear_dirty_range(virt, range, |page, size| dirty.push((page, size)))
+                .unwrap_or_else(|partial| panic!("failed to clear range: {}", partial.error))
+                .flush();
+            assert_eq!(dirty, [(virt, PageSize::base()), (next, PageSize::base())]);
+            EmulateArch::<A>::write::<u8>(next, 4);
+            let mut accessed = Vec::new();
+            let mut dirty = Vec::new();
+            mapper
+                .test_and_clear_dirty_range(virt, range, |page, _| dirty.push(page))
+                .unwrap_or_else(|partial| panic!("failed to clear range: {}", partial.error))
+                .flush();
+            mapper
+                .test_and_clear_accessed_range(virt, range, |page, _| accessed.push(page))
+                .unwrap_or_else(|partial| panic!("failed to clear range: {}", partial.error))
+                .flush();
+            assert_eq!(dirty, [next]);
+            assert_eq!(accessed, [virt, next]);
+            assert_eq!(EmulateArch::<A>::read::<u8>(next), 4);
+
+            // Software managed flags fault again once cleared
+            EmulateArch::<A>::set_accessed_dirty(AccessedDirty::Fault);
+            let fault = EmulateArch::<A>::try_write::<u8>(next, 5, Privilege::Kernel).unwrap_err();
+            let expected = if A::ENTRY_FLAG_DIRTY_BIT_MODIFIER != 0 {
+                PageFaultReason::NotWritable
+            } else {
+                PageFaultReason::NotDirty
+            };
+            assert_eq!(fault.reason, expected);
+            let (flags, _, flush) = mapper
+                .remap_with(next, PageSize::base(), |flags| flags.dirty(true))
+                .expect("failed to remap page");
+            flush.flush();
+            assert!(flags.is_accessed());
+            EmulateArch::<A>::write::<u8>(next, 5);
+            mapper
+                .test_and_clear_dirty(next)
+                .unwrap()
+                .expect("page not dirty")
+                .flush();
+            let fault = EmulateArch::<A>::try_write::<u8>(next, 6, Privilege::Kernel).unwrap_err();
+            assert_eq!(fault.reason, expected);
+        }
     }
 }
@@ -1,7 +1,15 @@
-pub use self::{entry::*, flags::*, flush::*, mapper::*, table::*};
+#[cfg(feature = "alloc")]
+pub use self::space::*;
+pub use self::{entry::*, error::*, flags::*, flush::*, mapper::*, size::*, table::*, walk::*};
 
+mod dump;
 mod entry;
+mod error;
 mod flags;
 mod flush;
 mod mapper;
+mod size;
+#[cfg(feature = "alloc")]
+mod space;
 mod table;
+mod walk;
@@ -0,0 +1,100 @@
+use core::{cmp::Ordering, fmt, marker::PhantomData};
+
+use crate::{Arch, FrameCount};
+
+/// Size of a mapping, given by the page table level holding its leaf entry
+#[derive(Clone, Copy)]
+pub struct PageSize<A> {
+    level: usize,
+    arch: PhantomData<A>,
+}
+
+impl<A: Arch> PageSize<A> {
+    /// Smallest page size, mapped by a leaf entry at level 0
+    #[inline(always)]
+    pub fn base() -> Self {
+        Self {
+            level: 0,
+            arch: PhantomData,
+        }
+    }
+
+    /// Page size mapped by a leaf entry at `level`, if the architecture supports leaves there
+    #[inline(always)]
+    pub fn from_level(level: usize) -> Option<Self> {
+        if level <= A::HUGE_PAGE_LEVELS {
+            Some(Self {
+                level,
+                arch: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Page size of exactly `bytes`, if the architecture supports it
+    pub fn from_bytes(bytes: usize) -> Option<Self> {
+        (0..=A::HUGE_PAGE_LEVELS)
+            .filter_map(Self::from_level)
+            .find(|size| size.bytes() == bytes)
+    }
+
+    /// Supported page sizes, from largest to smallest
+    pub fn all() -> impl Iterator<Item = Self> {
+        (0..=A::HUGE_PAGE_LEVELS).rev().filter_map(Self::from_level)
+    }
+
+    #[inline(always)]
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    #[inline(always)]
+    pub fn is_huge(&self) -> bool {
+        self.level > 0
+    }
+
+    #[inline(always)]
+    pub fn bytes(&self) -> usize {
+        1 << (self.level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT)
+    }
+
+    /// Mask of the offset inside a page of this size
+    #[inline(always)]
+    pub fn offset_mask(&self) -> usize {
+        self.bytes() - 1
+    }
+
+    /// Number of base pages in a page of this size
+    #[inline(always)]
+    pub fn frames(&self) -> FrameCount {
+        FrameCount::new(1 << (self.level * A::PAGE_ENTRY_SHIFT))
+    }
+}
+
+impl<A: Arch> fmt::Debug for PageSize<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PageSize({:#x})", self.bytes())
+    }
+}
+
+// Compared by level only, without requiring the architecture to be comparable
+impl<A> PartialEq for PageSize<A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.level == other.level
+    }
+}
+
+impl<A> Eq for PageSize<A> {}
+
+impl<A> PartialOrd for PageSize<A> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<A> Ord for PageSize<A> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.level.cmp(&other.level)
+    }
+}
@@ -0,0 +1,766 @@
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::fmt;
+
+use crate::{
+    Arch, FrameAllocator, FrameCount, MapError, PageFlags, PageFlush, PageMapper, PageSize,
+    PhysicalAddress, RegionError, VirtualAddress,
+};
+
+/// Memory backing the pages of a region
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Backing {
+    /// Zeroed frames from the mapper's allocator, mapped with the region and freed with it
+    Anonymous,
+    /// Like `Anonymous`, with each page only reserved until its first access, see
+    /// [`AddressSpace::resolve_fault`]
+    Demand,
+    /// Contiguous physical memory starting at the address, which is never freed
+    Physical(PhysicalAddress),
+}
+
+/// Range of virtual memory in use, with the flags and backing of its pages
+#[derive(Clone, Copy)]
+pub struct Region<A> {
+    pub start: VirtualAddress,
+    pub size: usize,
+    pub flags: PageFlags<A>,
+    pub backing: Backing,
+}
+
+impl<A: Arch> Region<A> {
+    /// Address right after the region
+    pub fn end(&self) -> VirtualAddress {
+        self.start.add(self.size)
+    }
+
+    pub fn contains(&self, virt: VirtualAddress) -> bool {
+        self.start <= virt && virt < self.end()
+    }
+
+    /// Backing of the part of the region starting `offset` bytes in
+    fn backing_at(&self, offset: usize) -> Backing {
+        match self.backing {
+            Backing::Physical(phys) => Backing::Physical(phys.add(offset)),
+            backing => backing,
+        }
+    }
+
+    /// Shortens the region to end at `at`, returning the part after it
+    fn split_off(&mut self, at: VirtualAddress) -> Self {
+        let offset = at.data() - self.start.data();
+        let tail = Self {
+            start: at,
+            size: self.size - offset,
+            flags: self.flags,
+            backing: self.backing_at(offset),
+        };
+        self.size = offset;
+        tail
+    }
+}
+
+impl<A: Arch> fmt::Debug for Region<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Region")
+            .field("start", &self.start)
+            .field("size", &format_args!("{:#x}", self.size))
+            .field("flags", &self.flags)
+            .field("backing", &self.backing)
+            .finish()
+    }
+}
+
+/// Regions of a range of virtual memory, kept in address order, mapped by a [`PageMapper`] as
+/// they are added, changed and removed. Pages in the range must only be changed through this.
+///
+/// # Safety
+///
+/// As with [`PageMapper`], memory must not be unmapped or remapped while it is still in use.
+pub struct AddressSpace<A, F> {
+    mapper: PageMapper<A, F>,
+    start: VirtualAddress,
+    end: VirtualAddress,
+    regions: BTreeMap<VirtualAddress, Region<A>>,
+}
+
+#[allow(clippy::missing_safety_doc)]
+impl<A: Arch, F: FrameAllocator> AddressSpace<A, F> {
+    /// Manages the pages from `start` up to `end` in the tables of `mapper`, which must not map
+    /// any of them yet. Both must be page aligned.
+    pub fn new(mapper: PageMapper<A, F>, start: VirtualAddress, end: VirtualAddress) -> Self {
+        assert!(
+            start <= end && (start.data() | end.data()) & A::PAGE_OFFSET_MASK == 0,
+            "invalid address space range"
+        );
+        Self {
+            mapper,
+            start,
+            end,
+            regions: BTreeMap::new(),
+        }
+    }
+
+    pub fn mapper(&self) -> &PageMapper<A, F> {
+        &self.mapper
+    }
+
+    /// Stops managing the regions, leaving their pages mapped
+    pub fn into_mapper(self) -> PageMapper<A, F> {
+        self.mapper
+    }
+
+    pub fn start(&self) -> VirtualAddress {
+        self.start
+    }
+
+    pub fn end(&self) -> VirtualAddress {
+        self.end
+    }
+
+    /// Iterates over the regions in address order
+    pub fn regions(&self) -> impl Iterator<Item = &Region<A>> {
+        self.regions.values()
+    }
+
+    /// Region holding `virt`, if any
+    pub fn region(&self, virt: VirtualAddress) -> Option<&Region<A>> {
+        self.regions
+            .range(..=virt)
+            .next_back()
+            .map(|(_, region)| region)
+            .filter(|region| region.contains(virt))
+    }
+
+    /// Lowest address aligned to `align`, or to pages if smaller, starting `size` free bytes, if
+    /// any
+    pub fn find_free(&self, size: usize, align: usize) -> Option<VirtualAddress> {
+        let align = align.max(A::PAGE_SIZE);
+        if !align.is_power_of_two() {
+            return None;
+        }
+        let fits = |gap_start: usize, gap_end: usize| {
+            let base = gap_start.checked_add(align - 1)? & !(align - 1);
+            (base.checked_add(size)? <= gap_end).then(|| VirtualAddress::new(base))
+        };
+        let mut gap_start = self.start.data();
+        for region in self.regions.values() {
+            if let Some(found) = fits(gap_start, region.start.data()) {
+                return Some(found);
+            }
+            gap_start = gap_start.max(region.end().data());
+        }
+        fits(gap_start, self.end.data())
+    }
+
+    /// Adds a region of `size` bytes at the lowest free address aligned to `align`, see
+    /// `find_free`, returning its start
+    pub unsafe fn map(
+        &mut self,
+        size: usize,
+        align: usize,
+        flags: PageFlags<A>,
+        backing: Backing,
+    ) -> Result<(VirtualAddress, PageFlush<A>), RegionError> {
+        unsafe {
+            if !align.max(A::PAGE_SIZE).is_power_of_two() {
+                return Err(RegionError::Misaligned);
+            }
+            self.check_range(self.start, size)?;
+            let virt = self.find_free(size, align).ok_or(RegionError::NoSpace)?;
+            self.map_fixed(virt, size, flags, backing)
+                .map(|flush| (virt, flush))
+        }
+    }
+
+    /// Adds a region of `size` bytes at `virt`, which must be free
+    pub unsafe fn map_fixed(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        flags: PageFlags<A>,
+        backing: Backing,
+    ) -> Result<PageFlush<A>, RegionError> {
+        unsafe {
+            self.check_range(virt, size)?;
+            if self.regions_in(virt, size).next().is_some() {
+                return Err(RegionError::Overlap);
+            }
+            let flush = self.map_backing(virt, size, flags, backing)?;
+            self.regions.insert(
+                virt,
+                Region {
+                    start: virt,
+                    size,
+                    flags,
+                    backing,
+                },
+            );
+            Ok(flush)
+        }
+    }
+
+    /// Removes `size` bytes starting at `virt` from the regions holding them, unmapping their
+    /// pages and splitting regions crossing the ends of the range. Free parts are skipped.
+    pub unsafe fn unmap(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+    ) -> Result<Option<PageFlush<A>>, RegionError> {
+        unsafe {
+            self.check_range(virt, size)?;
+            self.split_at(virt);
+            self.split_at(virt.add(size));
+            let starts: Vec<_> = self.regions_in(virt, size).map(|r| r.start).collect();
+            let mut flush = None;
+            for start in starts {
+                let region = self.regions[&start];
+                let res = match region.backing {
+                    Backing::Anonymous | Backing::Demand => {
+                        self.mapper.unmap_range(region.start, region.size, true)
+                    }
+                    Backing::Physical(_) => {
+                        self.mapper
+                            .unmap_phys_range(region.start, region.size, true)
+                    }
+                };
+                match res {
+                    Ok(done) => flush = Some(combine(flush, done)),
+                    Err(partial) => {
+                        // The part not unmapped yet is kept as a smaller region
+                        let mut region = self.regions.remove(&start).unwrap();
+                        let rest = region.split_off(start.add(partial.done));
+                        self.regions.insert(rest.start, rest);
+                        combine(flush, partial.flush).flush();
+                        return Err(partial.error.into());
+                    }
+                }
+                self.regions.remove(&start);
+            }
+            Ok(flush)
+        }
+    }
+
+    /// Changes the flags of `size` bytes starting at `virt`, splitting regions crossing the ends
+    /// of the range. The whole range must be in regions.
+    pub unsafe fn protect(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, RegionError> {
+        unsafe {
+            self.check_range(virt, size)?;
+            let mut covered = virt;
+            for region in self.regions_in(virt, size) {
+                if region.start > covered {
+                    return Err(RegionError::NotFound);
+                }
+                covered = region.end();
+            }
+            if covered < virt.add(size) {
+                return Err(RegionError::NotFound);
+            }
+            self.split_at(virt);
+            self.split_at(virt.add(size));
+            let starts: Vec<_> = self.regions_in(virt, size).map(|r| r.start).collect();
+            let mut flush = None;
+            for start in starts {
+                let region = self.regions[&start];
+                match self.protect_backing(&region, flags) {
+                    Ok(done) => flush = Some(combine(flush, done)),
+                    Err((partial, err)) => {
+                        if let Some(partial) = partial {
+                            combine(flush, partial).flush();
+                        } else if let Some(flush) = flush {
+                            flush.flush();
+                        }
+                        return Err(err);
+                    }
+                }
+                self.regions.get_mut(&start).unwrap().flags = flags;
+            }
+            // Every start is a region, so some were changed
+            Ok(flush.unwrap())
+        }
+    }
+
+    /// Resizes the `old_size` bytes starting at `virt`, which must be in a single region, to
+    /// `new_size` bytes, returning their new start. Shrinking unmaps the end, and growing maps
+    /// more of the backing after it if the addresses are free, or else moves the pages to a free
+    /// range if `may_move` is set.
+    pub unsafe fn remap(
+        &mut self,
+        virt: VirtualAddress,
+        old_size: usize,
+        new_size: usize,
+        may_move: bool,
+    ) -> Result<(VirtualAddress, Option<PageFlush<A>>), RegionError> {
+        unsafe {
+            self.check_range(virt, old_size)?;
+            if new_size & A::PAGE_OFFSET_MASK != 0 {
+                return Err(RegionError::Misaligned);
+            }
+            if new_size == 0 {
+                return Err(RegionError::InvalidSize);
+            }
+            let region = *self.region(virt).ok_or(RegionError::NotFound)?;
+            if virt.add(old_size) > region.end() {
+                return Err(RegionError::NotFound);
+            }
+            if new_size < old_size {
+                let flush = self.unmap(virt.add(new_size), old_size - new_size)?;
+                return Ok((virt, flush));
+            } else if new_size == old_size {
+                return Ok((virt, None));
+            }
+            let offset = virt.data() - region.start.data();
+            let old = Region {
+                start: virt,
+                size: old_size,
+                backing: region.backing_at(offset),
+                ..region
+            };
+            let extra = region.backing_at(offset + old_size);
+
+            let grows_in_place = old.end() == region.end()
+                && virt
+                    .data()
+                    .checked_add(new_size)
+                    .is_some_and(|end| end <= self.end.data())
+                && self
+                    .regions_in(old.end(), new_size - old_size)
+                    .next()
+                    .is_none();
+            if grows_in_place {
+                let flush =
+                    self.map_backing(old.end(), new_size - old_size, region.flags, extra)?;
+                self.regions.get_mut(&region.start).unwrap().size += new_size - old_size;
+                return Ok((virt, Some(flush)));
+            }
+            if !may_move {
+                return Err(RegionError::NoSpace);
+            }
+
+            let new = self
+                .find_free(new_size, A::PAGE_SIZE)
+                .ok_or(RegionError::NoSpace)?;
+            let moved = self.move_pages(&old, new)?;
+            let grown =
+                match self.map_backing(new.add(old_size), new_size - old_size, old.flags, extra) {
+                    Ok(flush) => flush,
+                    Err(err) => {
+                        let undo = self.mapper.unmap_phys_range(new, old_size, true);
+                        moved.combine(flush_of(undo)).flush();
+                        return Err(err);
+                    }
+                };
+            let unmapped = self.mapper.unmap_phys_range(virt, old_size, true);
+            let flush = moved.combine(grown).combine(flush_of(unmapped));
+            self.split_at(virt);
+            self.split_at(old.end());
+            self.regions.remove(&virt);
+            self.regions.insert(
+                new,
+                Region {
+                    start: new,
+                    size: new_size,
+                    ..old
+                },
+            );
+            Ok((new, Some(flush)))
+        }
+    }
+
+    /// Maps the page holding `virt` on a fault at its first access, if it is in a region with
+    /// `Backing::Demand` and still reserved. Returns `None` if the fault is genuine.
+    pub unsafe fn resolve_fault(
+        &mut self,
+        virt: VirtualAddress,
+    ) -> Result<Option<PageFlush<A>>, RegionError> {
+        unsafe {
+            match self.region(virt) {
+                Some(region) if region.backing == Backing::Demand => {
+                    Ok(self.mapper.resolve_reserved(virt)?)
+                }
+                _ => Ok(None),
+            }
+        }
+    }
+
+    /// Checks `size` bytes starting at `virt` are a nonempty page aligned part of the managed
+    /// range
+    fn check_range(&self, virt: VirtualAddress, size: usize) -> Result<(), RegionError> {
+        if (virt.data() | size) & A::PAGE_OFFSET_MASK != 0 {
+            return Err(RegionError::Misaligned);
+        }
+        if size == 0 {
+            return Err(RegionError::InvalidSize);
+        }
+        match virt.data().checked_add(size) {
+            Some(end) if self.start <= virt && end <= self.end.data() => Ok(()),
+            _ => Err(RegionError::OutOfBounds),
+        }
+    }
+
+    /// Regions overlapping `size` bytes starting at `virt`
+    fn regions_in(&self, virt: VirtualAddress, size: usize) -> impl Iterator<Item = &Region<A>> {
+        let first = self.region(virt).map_or(virt, |region| region.start);
+        self.regions
+            .range(first..virt.add(size))
+            .map(|(_, region)| region)
+    }
+
+    /// Splits the region crossing `at` in two, if any
+    fn split_at(&mut self, at: VirtualAddress) {
+        let Some(start) = self.region(at).map(|region| region.start) else {
+            return;
+        };
+        if start == at {
+            return;
+        }
+        let tail = self.regions.get_mut(&start).unwrap().split_off(at);
+        self.regions.insert(at, tail);
+    }
+
+    /// Maps `size` bytes starting at `virt` to `backing`, leaving nothing mapped on failure
+    unsafe fn map_backing(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        flags: PageFlags<A>,
+        backing: Backing,
+    ) -> Result<PageFlush<A>, RegionError> {
+        unsafe {
+            match backing {
+                Backing::Anonymous => {
+                    let mut flush = None;
+                    let mut offset = 0;
+                    while offset < size {
+                        match self.map_zeroed(virt.add(offset), size - offset, flags) {
+                            Ok((mapped, run)) => {
+                                flush = Some(combine(flush, run));
+                                offset += mapped;
+                            }
+                            Err(err) => {
+                                if let Some(flush) = flush {
+                                    flush.flush();
+                                }
+                                if offset > 0 {
+                                    flush_of(self.mapper.unmap_range(virt, offset, true)).flush();
+                                }
+                                return Err(err);
+                            }
+                        }
+                    }
+                    Ok(flush.unwrap())
+                }
+                Backing::Demand => {
+                    self.mapper.reserve_range(virt, size, flags)?;
+                    Ok(PageFlush::new_range(virt, size, PageSize::base()))
+                }
+                Backing::Physical(phys) => self.map_contiguous(virt, phys, size, flags),
+            }
+        }
+    }
+
+    /// Maps zeroed frames from the mapper's allocator at `virt`, covering as much of `size` bytes
+    /// as the largest contiguous allocation that succeeds, and returns how many bytes it covers
+    unsafe fn map_zeroed(
+        &mut self,
+        virt: VirtualAddress,
+        size: usize,
+        flags: PageFlags<A>,
+    ) -> Result<(usize, PageFlush<A>), RegionError> {
+        unsafe {
+            let mut count = 1 << (size >> A::PAGE_SHIFT).ilog2();
+            let phys = loop {
+                let allocator = self.mapper.allocator_mut();
+                if let Some(phys) = allocator.allocate(FrameCount::new(count)) {
+                    break phys;
+                }
+                if count == 1 {
+                    return Err(MapError::OutOfFrames.into());
+                }
+                count /= 2;
+            };
+            let len = count << A::PAGE_SHIFT;
+            A::write_bytes(A::phys_to_virt(phys), 0, len);
+            self.map_contiguous(virt, phys, len, flags)
+                .map(|flush| (len, flush))
+                .inspect_err(|_| {
+                    self.mapper
+                        .allocator_mut()
+                        .free(phys, FrameCount::new(count));
+                })
+        }
+    }
+
+    /// Maps `size` bytes starting at `virt` to the physical memory at `phys`, leaving nothing
+    /// mapped on failure
+    unsafe fn map_contiguous(
+        &mut self,
+        virt: VirtualAddress,
+        phys: PhysicalAddress,
+        size: usize,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, RegionError> {
+        unsafe {
+            self.mapper
+                .map_range(virt, phys, size, flags)
+                .map_err(|partial| {
+                    partial.flush.flush();
+                    if partial.done > 0 {
+                        flush_of(self.mapper.unmap_phys_range(virt, partial.done, true)).flush();
+                    }
+                    partial.error.into()
+                })
+        }
+    }
+
+    /// Changes the flags of the pages of `region`. On failure, returns the flush of the pages
+    /// changed so far along with the error.
+    unsafe fn protect_backing(
+        &mut self,
+        region: &Region<A>,
+        flags: PageFlags<A>,
+    ) -> Result<PageFlush<A>, (Option<PageFlush<A>>, RegionError)> {
+        unsafe {
+            if region.backing != Backing::Demand {
+                return self
+                    .mapper
+                    .protect_range(region.start, region.size, flags)
+                    .map_err(|partial| (Some(partial.flush), partial.error.into()));
+            }
+            // Pages not accessed yet are reserved again with the new flags
+            let mut flush = None;
+            for offset in (0..region.size).step_by(A::PAGE_SIZE) {
+                let page = region.start.add(offset);
+                let res = match self.mapper.reservation(page) {
+                    Some(_) => self
+                        .mapper
+                        .protect_reserved(page, flags)
+                        .map(|()| PageFlush::new(page)),
+                    None => self
+                        .mapper
+                        .remap_with(page, PageSize::base(), |_| flags)
+                        .map(|(_, _, flush)| flush),
+                };
+                match res {
+                    Ok(page) => flush = Some(combine(flush, page)),
+                    Err(err) => return Err((flush, err.into())),
+                }
+            }
+            Ok(flush.unwrap())
+        }
+    }
+
+    /// Maps the pages of `region` at `new` too, keeping their frames and reservations
+    unsafe fn move_pages(
+        &mut self,
+        region: &Region<A>,
+        new: VirtualAddress,
+    ) -> Result<PageFlush<A>, RegionError> {
+        unsafe {
+            if let Backing::Physical(phys) = region.backing {
+                return self.map_contiguous(new, phys, region.size, region.flags);
+            }
+            let mut flush = None;
+            for offset in (0..region.size).step_by(A::PAGE_SIZE) {
+                let page = region.start.add(offset);
+                let res = match self.mapper.reservation(page) {
+                    Some(flags) => self
+                        .mapper
+                        .reserve_range(new.add(offset), A::PAGE_SIZE, flags)
+                        .map(|()| PageFlush::new(new.add(offset))),
+                    None => match self.mapper.translate(page) {
+                        Some((phys, flags, _)) => {
+                            self.mapper
+                                .map_phys(new.add(offset), phys, PageSize::base(), flags)
+                        }
+                        None => Err(MapError::NotMapped),
+                    },
+                };
+                match res {
+                    Ok(page) => flush = Some(combine(flush, page)),
+                    Err(err) => {
+                        if let Some(flush) = flush {
+                            flush.flush();
+                        }
+                        if offset > 0 {
+                            flush_of(self.mapper.unmap_phys_range(new, offset, true)).flush();
+                        }
+                        return Err(err.into());
+                    }
+                }
+            }
+            Ok(flush.unwrap())
+        }
+    }
+}
+
+fn combine<A: Arch>(flush: Option<PageFlush<A>>, other: PageFlush<A>) -> PageFlush<A> {
+    match flush {
+        Some(flush) => flush.combine(other),
+        None => other,
+    }
+}
+
+/// Flush of the part of an unmapping that was done, which is all that can be undone when
+/// cleaning up after a failure
+fn flush_of<A: Arch, E>(res: Result<PageFlush<A>, crate::PartialRange<A, E>>) -> PageFlush<A> {
+    match res {
+        Ok(flush) => flush,
+        Err(partial) => partial.flush,
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use crate::{
+        arch::all_archs, AddressSpace, Arch, Backing, BuddyAllocator, BumpAllocator, EmulateArch,
+        MemoryArea, PageFaultReason, PageFlags, PageMapper, PhysicalAddress, Privilege,
+        RegionError, TableKind, TlbCheck, VirtualAddress, MEGABYTE,
+    };
+
+    all_archs!(address_space);
+
+    unsafe fn address_space<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            EmulateArch::<A>::set_tlb_check(TlbCheck::Panic);
+            let mut allocator = BuddyAllocator::<EmulateArch<A>>::new(BumpAllocator::new(areas, 0))
+                .expect("failed to create buddy allocator");
+            let mapper = PageMapper::<EmulateArch<A>, _>::current(TableKind::User, &mut allocator);
+            let start = VirtualAddress::new(16 * MEGABYTE);
+            let mut space = AddressSpace::new(mapper, start, VirtualAddress::new(20 * MEGABYTE));
+            let page = A::PAGE_SIZE;
+            let flags = PageFlags::new().write(true);
+            let ranges = |space: &AddressSpace<EmulateArch<A>, _>| {
+                space
+                    .regions()
+                    .map(|region| (region.start, region.size))
+                    .collect::<Vec<_>>()
+            };
+
+            // Regions fill the lowest gap fitting their size and alignment
+            let (anon, flush) = space
+                .map(3 * page, 0, flags, Backing::Anonymous)
+                .expect("failed to map region");
+            flush.flush();
+            assert_eq!(anon, start);
+            assert_eq!(EmulateArch::<A>::read::<u64>(anon.add(page)), 0);
+            EmulateArch::<A>::write::<u64>(anon.add(page), 1);
+            // Anonymous frames are allocated in contiguous runs
+            let phys = |virt| space.mapper().translate(virt).map(|(phys, _, _)| phys);
+            assert_eq!(phys(anon.add(page)), phys(anon).map(|phys| phys.add(page)));
+            assert_eq!(
+                space.map(0, 0, flags, Backing::Anonymous).err(),
+                Some(RegionError::InvalidSize)
+            );
+            let (demand, flush) = space
+                .map(2 * page, MEGABYTE, flags, Backing::Demand)
+                .expect("failed to map region");
+            flush.flush();
+            assert_eq!(demand, start.add(MEGABYTE));
+            assert_eq!(
+                space
+                    .map_fixed(demand.add(page), page, flags, Backing::Anonymous)
+                    .err(),
+                Some(RegionError::Overlap)
+            );
+            assert_eq!(
+                space
+                    .map(page, 0, flags, Backing::Physical(PhysicalAddress::new(0)))
+                    .map(|(virt, flush)| {
+                        flush.flush();
+                        virt
+                    }),
+                Ok(anon.add(3 * page))
+            );
+            let phys = space.region(anon.add(3 * page)).unwrap().backing;
+            assert_eq!(phys, Backing::Physical(PhysicalAddress::new(0)));
+
+            // Demand pages are zeroed on their first access
+            let fault = EmulateArch::<A>::try_read::<u8>(demand, Privilege::Kernel).unwrap_err();
+            assert_eq!(fault.reason, PageFaultReason::NotPresent);
+            space
+                .resolve_fault(fault.address)
+                .unwrap()
+                .expect("fault not resolved")
+                .flush();
+            assert_eq!(EmulateArch::<A>::read::<u64>(demand), 0);
+            EmulateArch::<A>::write::<u64>(demand, 2);
+            assert!(space.resolve_fault(demand).unwrap().is_none());
+            assert!(space.resolve_fault(anon).unwrap().is_none());
+
+            // Protecting and unmapping split regions
+            space
+                .protect(anon.add(page), page, PageFlags::new())
+                .expect("failed to protect range")
+                .flush();
+            assert_eq!(
+                ranges(&space)[..3],
+                [
+                    (anon, page),
+                    (anon.add(page), page),
+                    (anon.add(2 * page), page)
+                ]
+            );
+            let fault = EmulateArch::<A>::try_write::<u8>(anon.add(page), 0, Privilege::Kernel)
+                .unwrap_err();
+            assert_eq!(fault.reason, PageFaultReason::NotWritable);
+            assert_eq!(EmulateArch::<A>::read::<u64>(anon.add(page)), 1);
+            space
+                .protect(demand, 2 * page, PageFlags::new())
+                .expect("failed to protect range")
+                .flush();
+            assert_eq!(
+                space
+                    .mapper()
+                    .reservation(demand.add(page))
+                    .map(|flags| flags.has_write()),
+                Some(false)
+            );
+            assert_eq!(
+                space.protect(demand, 3 * page, flags).err(),
+                Some(RegionError::NotFound)
+            );
+            if let Some(flush) = space.unmap(anon, 2 * page).expect("failed to unmap range") {
+                flush.flush();
+            }
+            assert!(space.region(anon).is_none());
+            assert!(space.mapper().translate(anon.add(page)).is_none());
+            assert_eq!(space.find_free(2 * page, 0), Some(anon));
+
+            // Growing keeps the pages, in place when free or else moved
+            let (grown, flush) = space
+                .remap(demand, 2 * page, 3 * page, false)
+                .expect("failed to grow region");
+            flush.expect("nothing grown").flush();
+            assert_eq!(grown, demand);
+            assert_eq!(space.region(demand).unwrap().size, 3 * page);
+            space
+                .map_fixed(demand.add(3 * page), page, flags, Backing::Anonymous)
+                .expect("failed to map region")
+                .flush();
+            assert_eq!(
+                space.remap(demand, 3 * page, 4 * page, false).err(),
+                Some(RegionError::NoSpace)
+            );
+            let (moved, flush) = space
+                .remap(demand, 3 * page, 4 * page, true)
+                .expect("failed to move region");
+            flush.expect("nothing moved").flush();
+            assert_eq!(moved, anon.add(4 * page));
+            assert_eq!(EmulateArch::<A>::read::<u64>(moved), 2);
+            assert!(space.mapper().reservation(moved.add(page)).is_some());
+            assert!(space.mapper().translate(demand).is_none());
+            assert!(space.mapper().reservation(demand.add(page)).is_none());
+            let (shrunk, flush) = space
+                .remap(moved, 4 * page, page, false)
+                .expect("failed to shrink region");
+            flush.expect("nothing unmapped").flush();
+            assert_eq!(shrunk, moved);
+            assert!(space.mapper().reservation(moved.add(page)).is_none());
+        }
+    }
+}
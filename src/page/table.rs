@@ -1,8 +1,14 @@
-use core::marker::PhantomData;
+use core::{fmt, marker::PhantomData};
 
-use super::PageEntry;
+use super::{dump, walk, MapError, Mapping, Mappings, PageEntry, TableVisitor};
 use crate::{Arch, PhysicalAddress, TableKind, VirtualAddress};
 
+/// Table of page entries at `level`, covering the addresses from `base`
+///
+/// # Safety
+///
+/// The unsafe methods read and write the table through `Arch::phys_to_virt`. It must be a valid
+/// table of the architecture at its level, and changes must be flushed before they are relied on.
 pub struct PageTable<A> {
     base: VirtualAddress,
     phys: PhysicalAddress,
@@ -10,7 +16,11 @@ pub struct PageTable<A> {
     phantom: PhantomData<A>,
 }
 
+#[allow(clippy::missing_safety_doc)]
 impl<A: Arch> PageTable<A> {
+    // Entries holding a bit of the count each, enough to count every entry
+    const COUNTER_ENTRIES: usize = (usize::BITS - A::PAGE_ENTRIES.leading_zeros()) as usize;
+
     pub unsafe fn new(base: VirtualAddress, phys: PhysicalAddress, level: usize) -> Self {
         Self {
             base,
@@ -23,13 +33,23 @@ impl<A: Arch> PageTable<A> {
     pub unsafe fn top(table_kind: TableKind) -> Self {
         unsafe {
             Self::new(
-                VirtualAddress::new(0),
+                Self::root_base(table_kind),
                 A::table(table_kind),
                 A::PAGE_LEVELS - 1,
             )
         }
     }
 
+    /// Base of root tables of `table_kind`. With `SPLIT_TABLES`, the kernel root table maps the
+    /// addresses above `PAGE_NEGATIVE_MASK` from its first entry, others map from 0.
+    pub(crate) fn root_base(table_kind: TableKind) -> VirtualAddress {
+        if A::SPLIT_TABLES && table_kind == TableKind::Kernel {
+            VirtualAddress::new(A::PAGE_NEGATIVE_MASK)
+        } else {
+            VirtualAddress::new(0)
+        }
+    }
+
     pub fn base(&self) -> VirtualAddress {
         self.base
     }
@@ -78,20 +98,107 @@ impl<A: Arch> PageTable<A> {
 
     pub unsafe fn entry(&self, i: usize) -> Option<PageEntry<A>> {
         unsafe {
-            let addr = self.entry_virt(i)?;
-            Some(PageEntry::from_data(A::read::<usize>(addr)))
+            let data = self.read_entry(i)?;
+            Some(PageEntry::from_data(data & !A::ENTRY_FLAG_COUNTER))
         }
     }
 
+    /// Sets entry `i`, updating the number of entries in use
     pub unsafe fn set_entry(&mut self, i: usize, entry: PageEntry<A>) -> Option<()> {
+        unsafe {
+            let old = self.read_entry(i)?;
+            let counter = old & A::ENTRY_FLAG_COUNTER;
+            let data = entry.data() & !A::ENTRY_FLAG_COUNTER;
+            self.write_entry(i, data | counter)?;
+            let was_used = old & !A::ENTRY_FLAG_COUNTER != 0;
+            if A::ENTRY_FLAG_COUNTER != 0 && was_used != (data != 0) {
+                let count = self.entry_count();
+                self.set_entry_count(if data != 0 { count + 1 } else { count - 1 });
+            }
+            Some(())
+        }
+    }
+
+    /// Number of entries in use, which are present or hold software state like reservations.
+    /// With `ENTRY_FLAG_COUNTER`, this is kept by `set_entry`, so the table must start zeroed and
+    /// only change through it.
+    pub unsafe fn entry_count(&self) -> usize {
+        unsafe {
+            if A::ENTRY_FLAG_COUNTER == 0 {
+                return (0..A::PAGE_ENTRIES)
+                    .filter_map(|i| self.entry(i))
+                    .filter(|entry| entry.data() != 0)
+                    .count();
+            }
+            let mut count = 0;
+            for i in 0..Self::COUNTER_ENTRIES {
+                if self.read_entry(i).unwrap_or(0) & A::ENTRY_FLAG_COUNTER != 0 {
+                    count |= 1 << i;
+                }
+            }
+            count
+        }
+    }
+
+    /// Whether no entry is in use, see `entry_count`
+    pub unsafe fn is_empty(&self) -> bool {
+        unsafe {
+            if A::ENTRY_FLAG_COUNTER == 0 {
+                (0..A::PAGE_ENTRIES)
+                    .filter_map(|i| self.entry(i))
+                    .all(|entry| entry.data() == 0)
+            } else {
+                self.entry_count() == 0
+            }
+        }
+    }
+
+    unsafe fn set_entry_count(&mut self, count: usize) {
+        unsafe {
+            for i in 0..Self::COUNTER_ENTRIES {
+                let Some(data) = self.read_entry(i) else {
+                    return;
+                };
+                let bit = if count & 1 << i != 0 {
+                    A::ENTRY_FLAG_COUNTER
+                } else {
+                    0
+                };
+                if data & A::ENTRY_FLAG_COUNTER != bit {
+                    self.write_entry(i, data & !A::ENTRY_FLAG_COUNTER | bit);
+                }
+            }
+        }
+    }
+
+    unsafe fn read_entry(&self, i: usize) -> Option<usize> {
+        unsafe {
+            let addr = self.entry_virt(i)?;
+            // Entries may be narrower than usize, as with 32-bit x86 tables on a 64-bit host
+            Some(if A::PAGE_ENTRY_SIZE == 4 {
+                A::read::<u32>(addr) as usize
+            } else {
+                A::read::<usize>(addr)
+            })
+        }
+    }
+
+    unsafe fn write_entry(&mut self, i: usize, data: usize) -> Option<()> {
         unsafe {
             let addr = self.entry_virt(i)?;
-            A::write::<usize>(addr, entry.data());
+            if A::PAGE_ENTRY_SIZE == 4 {
+                A::write::<u32>(addr, data as u32);
+            } else {
+                A::write::<usize>(addr, data);
+            }
             Some(())
         }
     }
 
-    pub unsafe fn index_of(&self, address: VirtualAddress) -> Option<usize> {
+    pub unsafe fn index_of(&self, address: VirtualAddress) -> Result<usize, MapError> {
+        if !A::virt_is_valid(address) {
+            return Err(MapError::InvalidAddress);
+        }
         // Canonicalize address first
         let address = VirtualAddress::new(address.data() & A::PAGE_ADDRESS_MASK);
         let level_shift = self.level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT;
@@ -100,22 +207,58 @@ impl<A: Arch> PageTable<A> {
         let level_mask = A::PAGE_ENTRIES
             .wrapping_shl(level_shift as u32)
             .wrapping_sub(1);
-        if address >= self.base && address <= self.base.add(level_mask) {
-            Some((address.data() >> level_shift) & A::PAGE_ENTRY_MASK)
+        let base = VirtualAddress::new(self.base.data() & A::PAGE_ADDRESS_MASK);
+        if address >= base && address <= base.add(level_mask) {
+            Ok((address.data() >> level_shift) & A::PAGE_ENTRY_MASK)
         } else {
-            None
+            Err(MapError::InvalidAddress)
         }
     }
 
+    /// Calls `visitor` with the present entries of this table and the tables below it, in address
+    /// order
+    pub unsafe fn walk(&self, visitor: &mut impl TableVisitor<A>) {
+        unsafe { walk::walk(self, visitor) }
+    }
+
+    /// Iterates over the mappings of this table and the tables below it, in address order
+    pub unsafe fn mappings(self) -> Mappings<A> {
+        unsafe { Mappings::new(self) }
+    }
+
+    /// Iterates over the mappings of this table and the tables below it that are both writable
+    /// and executable
+    pub unsafe fn write_execute_mappings(self) -> impl Iterator<Item = Mapping<A>> {
+        unsafe {
+            self.mappings()
+                .filter(|mapping| mapping.flags.is_write_execute())
+        }
+    }
+
+    /// Writes this table and the tables below it to `out` as indented text
+    pub unsafe fn dump_text(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        unsafe { dump::dump_text(self, out) }
+    }
+
+    /// Writes this table and the tables below it to `out` as a Graphviz DOT digraph
+    pub unsafe fn dump_dot(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        unsafe { dump::dump_dot(self, out) }
+    }
+
     pub unsafe fn next(&self, i: usize) -> Option<Self> {
         unsafe {
             if self.level == 0 {
                 return None;
             }
 
+            let entry = self.entry(i)?;
+            if A::entry_is_leaf(entry.data(), self.level) {
+                return None;
+            }
+
             Some(PageTable::new(
                 self.entry_base(i)?,
-                self.entry(i)?.address().ok()?,
+                entry.address().ok()?,
                 self.level - 1,
             ))
         }
@@ -0,0 +1,318 @@
+use core::fmt;
+
+use crate::{Arch, PageEntry, PageFlags, PageSize, PageTable, PhysicalAddress, VirtualAddress};
+
+/// Run of pages of the same size and flags, mapping `len` bytes of contiguous virtual memory
+/// starting at `virt` to contiguous physical memory starting at `phys`
+#[derive(Clone, Copy)]
+pub struct Mapping<A> {
+    pub virt: VirtualAddress,
+    pub phys: PhysicalAddress,
+    pub len: usize,
+    pub flags: PageFlags<A>,
+    pub page_size: PageSize<A>,
+}
+
+impl<A: Arch> Mapping<A> {
+    /// Whether `other` continues this run
+    fn continued_by(&self, other: &Self) -> bool {
+        self.virt.data().checked_add(self.len) == Some(other.virt.data())
+            && self.phys.add(self.len) == other.phys
+            && self.flags.data() == other.flags.data()
+            && self.page_size == other.page_size
+    }
+}
+
+impl<A: Arch> fmt::Debug for Mapping<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Mapping")
+            .field("virt", &self.virt)
+            .field("phys", &self.phys)
+            .field("len", &format_args!("{:#x}", self.len))
+            .field("flags", &self.flags)
+            .field("page_size", &self.page_size)
+            .finish()
+    }
+}
+
+/// Receives the present entries of a page table walk, see [`PageTable::walk`]
+pub trait TableVisitor<A: Arch> {
+    /// Called with each entry at `level` pointing to a table, which maps from `virt`. The table is
+    /// walked only if this returns true.
+    fn table(&mut self, virt: VirtualAddress, entry: PageEntry<A>, level: usize) -> bool {
+        let _ = (virt, entry, level);
+        true
+    }
+
+    /// Called with each leaf entry at `level`, which maps from `virt`
+    fn leaf(&mut self, virt: VirtualAddress, entry: PageEntry<A>, level: usize);
+}
+
+/// Iterator over the mappings of a page table in address order, coalescing adjacent pages into
+/// runs, see [`Mapping`]
+pub struct Mappings<A> {
+    root: PageTable<A>,
+    // Next address to look up, relative to the tables rather than canonical
+    cursor: Option<VirtualAddress>,
+    pending: Option<Mapping<A>>,
+}
+
+impl<A: Arch> Mappings<A> {
+    /// Iterates over the mappings below `root`
+    ///
+    /// # Safety
+    ///
+    /// `root` must be a valid table, which must not change while iterating.
+    pub unsafe fn new(root: PageTable<A>) -> Self {
+        Self {
+            cursor: Some(root.base()),
+            root,
+            pending: None,
+        }
+    }
+
+    /// Finds the next present leaf from the cursor, walking down from the root each time
+    unsafe fn next_leaf(&mut self) -> Option<(VirtualAddress, PageEntry<A>, usize)> {
+        unsafe {
+            'leaves: while let Some(cursor) = self.cursor {
+                let mut table =
+                    PageTable::new(self.root.base(), self.root.phys(), self.root.level());
+                loop {
+                    let level = table.level();
+                    let i = (cursor.data() >> level_shift::<A>(level)) & A::PAGE_ENTRY_MASK;
+                    let entry = table.entry(i)?;
+                    let base = table.entry_base(i)?;
+                    if entry.present() && !A::entry_is_leaf(entry.data(), level) {
+                        table = table.next(i)?;
+                        continue;
+                    }
+                    self.advance(base, level);
+                    if entry.present() {
+                        return Some((canonical::<A>(base), entry, level));
+                    }
+                    continue 'leaves;
+                }
+            }
+            None
+        }
+    }
+
+    /// Moves the cursor past the entry at `level` mapping from `base`
+    fn advance(&mut self, base: VirtualAddress, level: usize) {
+        let last = self
+            .root
+            .base()
+            .data()
+            .wrapping_add(table_span_mask::<A>(self.root.level()));
+        self.cursor = base
+            .data()
+            .checked_add(1 << level_shift::<A>(level))
+            .filter(|&next| next.wrapping_sub(1) < last)
+            .map(VirtualAddress::new);
+    }
+
+    unsafe fn next_page(&mut self) -> Option<Mapping<A>> {
+        unsafe {
+            loop {
+                let (virt, entry, level) = self.next_leaf()?;
+                // Leaves at levels the architecture cannot map are malformed, and skipped
+                let Some(page_size) = PageSize::from_level(level) else {
+                    continue;
+                };
+                let Ok(phys) = entry.address() else {
+                    continue;
+                };
+                let flags = PageFlags::from_data(A::leaf_page_flags(entry.flags().data(), level));
+                return Some(Mapping {
+                    virt,
+                    phys,
+                    len: page_size.bytes(),
+                    flags,
+                    page_size,
+                });
+            }
+        }
+    }
+}
+
+impl<A: Arch> Iterator for Mappings<A> {
+    type Item = Mapping<A>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        unsafe {
+            let mut mapping = match self.pending.take() {
+                Some(some) => some,
+                None => self.next_page()?,
+            };
+            while let Some(next) = self.next_page() {
+                if mapping.continued_by(&next) {
+                    mapping.len += next.len;
+                } else {
+                    self.pending = Some(next);
+                    break;
+                }
+            }
+            Some(mapping)
+        }
+    }
+}
+
+/// Calls `visitor` with the present entries of `table` and the tables below it, in address order
+pub(crate) unsafe fn walk<A: Arch>(table: &PageTable<A>, visitor: &mut impl TableVisitor<A>) {
+    unsafe {
+        let level = table.level();
+        for i in 0..A::PAGE_ENTRIES {
+            let Some(entry) = table.entry(i).filter(|entry| entry.present()) else {
+                continue;
+            };
+            let Some(base) = table.entry_base(i) else {
+                continue;
+            };
+            let virt = canonical::<A>(base);
+            if A::entry_is_leaf(entry.data(), level) {
+                visitor.leaf(virt, entry, level);
+            } else if visitor.table(virt, entry, level)
+                && let Some(next) = table.next(i)
+            {
+                walk(&next, visitor);
+            }
+        }
+    }
+}
+
+#[inline(always)]
+fn level_shift<A: Arch>(level: usize) -> usize {
+    level * A::PAGE_ENTRY_SHIFT + A::PAGE_SHIFT
+}
+
+/// Mask of the addresses covered by a table at `level`, wrapping like `PageTable::index_of`
+#[inline(always)]
+fn table_span_mask<A: Arch>(level: usize) -> usize {
+    A::PAGE_ENTRIES
+        .wrapping_shl(level_shift::<A>(level) as u32)
+        .wrapping_sub(1)
+}
+
+/// Sign extends an address relative to the tables when the architecture requires it
+pub(crate) fn canonical<A: Arch>(virt: VirtualAddress) -> VirtualAddress {
+    if A::virt_is_valid(virt) {
+        virt
+    } else {
+        VirtualAddress::new(virt.data() | A::PAGE_NEGATIVE_MASK)
+    }
+}
+
+#[cfg(all(test, feature = "std", target_pointer_width = "64"))]
+mod tests {
+    use crate::{
+        arch::all_archs, Arch, BumpAllocator, EmulateArch, MemoryArea, PageEntry, PageFlags,
+        PageMapper, PageSize, PhysicalAddress, TableKind, TableVisitor, VirtualAddress,
+    };
+
+    all_archs!(walk);
+
+    unsafe fn walk<A: Arch + 'static>(areas: &'static [MemoryArea]) {
+        unsafe {
+            let mut allocator = BumpAllocator::<EmulateArch<A>>::new(areas, 0);
+            let mut mapper =
+                PageMapper::<EmulateArch<A>, _>::create(TableKind::User, None, &mut allocator)
+                    .expect("failed to create mapper");
+            let base = PageSize::base();
+            let huge = PageSize::<EmulateArch<A>>::from_level(1).expect("no huge pages");
+            let virt = VirtualAddress::new(4 * huge.bytes());
+            let phys = PhysicalAddress::new(0);
+            let flags = PageFlags::new().write(true);
+
+            // A huge page followed by contiguous base pages, which are not coalesced with it
+            mapper
+                .map_range(virt, phys, huge.bytes() + 3 * A::PAGE_SIZE, flags)
+                .unwrap_or_else(|partial| panic!("failed to map range: {}", partial.error))
+                .ignore();
+            // A page with other flags right after them
+            let other = virt.add(huge.bytes() + 3 * A::PAGE_SIZE);
+            mapper
+                .map_phys(
+                    other,
+                    phys.add(huge.bytes() + 3 * A::PAGE_SIZE),
+                    base,
+                    PageFlags::new(),
+                )
+                .expect("failed to map page")
+                .ignore();
+            // A page at the offset mapping, which is canonical in the kernel half and mapped by a
+            // kernel mapper of the same table, unless the kernel half has its own root table
+            let kernel = EmulateArch::<A>::phys_to_virt(phys);
+            if !A::SPLIT_TABLES {
+                let root = mapper.table().phys();
+                PageMapper::<EmulateArch<A>, _>::new(
+                    TableKind::Kernel,
+                    root,
+                    mapper.allocator_mut(),
+                )
+                .map_phys(kernel, phys, base, flags)
+                .expect("failed to map kernel page")
+                .ignore();
+            }
+
+            let mut mappings = mapper.mappings();
+            let mut expect = |virt: VirtualAddress, phys, len, size, write| {
+                let mapping = mappings.next().expect("missing mapping");
+                assert_eq!(mapping.virt, virt);
+                assert_eq!(mapping.phys, phys);
+                assert_eq!(mapping.len, len);
+                assert_eq!(mapping.page_size, size);
+                assert_eq!(mapping.flags.has_write(), write);
+            };
+            expect(virt, phys, huge.bytes(), huge, true);
+            expect(
+                virt.add(huge.bytes()),
+                phys.add(huge.bytes()),
+                3 * A::PAGE_SIZE,
+                base,
+                true,
+            );
+            expect(
+                other,
+                phys.add(huge.bytes() + 3 * A::PAGE_SIZE),
+                A::PAGE_SIZE,
+                base,
+                false,
+            );
+            if !A::SPLIT_TABLES {
+                expect(kernel, phys, A::PAGE_SIZE, base, true);
+            }
+            assert!(mappings.next().is_none());
+
+            // The visitor sees every table on the way to the leaves
+            struct Counter {
+                tables: usize,
+                leaves: Vec<(VirtualAddress, usize)>,
+            }
+            impl<A: Arch> TableVisitor<A> for Counter {
+                fn table(&mut self, _: VirtualAddress, _: PageEntry<A>, _: usize) -> bool {
+                    self.tables += 1;
+                    true
+                }
+                fn leaf(&mut self, virt: VirtualAddress, _: PageEntry<A>, level: usize) {
+                    self.leaves.push((virt, level));
+                }
+            }
+            let mut counter = Counter {
+                tables: 0,
+                leaves: Vec::new(),
+            };
+            mapper.walk(&mut counter);
+            assert_eq!(counter.leaves[0], (virt, 1));
+            // The kernel page needs a table per level below the root, the others share them down to
+            // level 1 and level 0
+            if A::SPLIT_TABLES {
+                assert_eq!(counter.leaves.len(), 5);
+                assert_eq!(counter.tables, A::PAGE_LEVELS - 1);
+            } else {
+                assert_eq!(counter.leaves.len(), 6);
+                assert_eq!(counter.leaves[5], (kernel, 0));
+                assert_eq!(counter.tables, 2 * A::PAGE_LEVELS - 2);
+            }
+        }
+    }
+}